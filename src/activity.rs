@@ -0,0 +1,297 @@
+use crate::consts::ACTIVITY;
+use crate::get_config;
+use crate::provenance::{ProvenanceEntry, ProvenanceKind};
+use crate::store::JsonStore;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+const LOG: &str = "activity";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    MintReceived,
+    Assembled,
+    Disassembled,
+    AskPlaced,
+    AskRemoved,
+    BidPlaced,
+    BidRemoved,
+    Purchase,
+    Sale,
+    TransferSent,
+    TransferReceived,
+    Burned,
+}
+
+impl ActivityKind {
+    const ALL: [ActivityKind; 12] = [
+        Self::MintReceived,
+        Self::Assembled,
+        Self::Disassembled,
+        Self::AskPlaced,
+        Self::AskRemoved,
+        Self::BidPlaced,
+        Self::BidRemoved,
+        Self::Purchase,
+        Self::Sale,
+        Self::TransferSent,
+        Self::TransferReceived,
+        Self::Burned,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MintReceived => "mint_received",
+            Self::Assembled => "assembled",
+            Self::Disassembled => "disassembled",
+            Self::AskPlaced => "ask_placed",
+            Self::AskRemoved => "ask_removed",
+            Self::BidPlaced => "bid_placed",
+            Self::BidRemoved => "bid_removed",
+            Self::Purchase => "purchase",
+            Self::Sale => "sale",
+            Self::TransferSent => "transfer_sent",
+            Self::TransferReceived => "transfer_received",
+            Self::Burned => "burned",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+}
+
+/// Something an account did, or that happened to it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Activity {
+    /// Increases with every activity recorded, across all accounts.
+    pub cursor: u64,
+    pub account_id: String,
+    pub kind: ActivityKind,
+    pub token_id: String,
+    pub contract_id: String,
+    pub counterparty_id: Option<String>,
+    pub price: Option<Decimal>,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActivityPage {
+    pub account_id: String,
+    pub activities: Vec<Activity>,
+    /// Pass as `before` to get the next, older page.
+    pub next_cursor: Option<u64>,
+}
+
+/// Accounts a provenance entry is activity of: the account in it and, for sales and
+/// transfers, its counterparty.
+fn activities_of(entry: &ProvenanceEntry) -> Vec<(String, ActivityKind, Option<String>)> {
+    let (account_id, counterparty_id) = match &entry.account_id {
+        Some(account_id) => (account_id.clone(), entry.counterparty_id.clone()),
+        None => return Vec::new(),
+    };
+    let single = |kind| vec![(account_id.clone(), kind, None)];
+
+    match entry.kind {
+        ProvenanceKind::Mint => single(ActivityKind::MintReceived),
+        ProvenanceKind::Assemble => single(ActivityKind::Assembled),
+        ProvenanceKind::Disassemble => single(ActivityKind::Disassembled),
+        ProvenanceKind::AskAdded => single(ActivityKind::AskPlaced),
+        ProvenanceKind::AskRemoved => single(ActivityKind::AskRemoved),
+        ProvenanceKind::BidAdded => single(ActivityKind::BidPlaced),
+        ProvenanceKind::BidRemoved => single(ActivityKind::BidRemoved),
+        ProvenanceKind::Burn => single(ActivityKind::Burned),
+        ProvenanceKind::Bootstrap | ProvenanceKind::Reconcile => Vec::new(),
+        ProvenanceKind::Sale | ProvenanceKind::Transfer => {
+            let (sent, received) = match entry.kind {
+                ProvenanceKind::Sale => (ActivityKind::Sale, ActivityKind::Purchase),
+                _ => (ActivityKind::TransferSent, ActivityKind::TransferReceived),
+            };
+            let mut activities = vec![(account_id.clone(), sent, counterparty_id.clone())];
+            if let Some(counterparty_id) = counterparty_id {
+                activities.push((counterparty_id, received, Some(account_id)));
+            }
+            activities
+        }
+    }
+}
+
+/// Per-account activity, mirrored from `activity.jsonl` in the state dir.
+pub struct ActivityFeed {
+    store: JsonStore,
+    accounts: HashMap<String, Vec<Activity>>,
+    /// Token ids with the receipt ids and transaction hashes of their sales. The transfer
+    /// completing a sale is shown as the sale only, whichever of them came first.
+    sales: HashSet<(String, String)>,
+    next_cursor: u64,
+}
+
+impl ActivityFeed {
+    pub fn load(store: JsonStore) -> anyhow::Result<Self> {
+        let mut feed = Self {
+            store,
+            accounts: HashMap::new(),
+            sales: HashSet::new(),
+            next_cursor: 0,
+        };
+        for activity in feed.store.load_lines::<Activity>(LOG)? {
+            feed.insert(activity);
+        }
+
+        Ok(feed)
+    }
+
+    fn insert(&mut self, activity: Activity) {
+        self.next_cursor = self.next_cursor.max(activity.cursor + 1);
+        if matches!(activity.kind, ActivityKind::Sale | ActivityKind::Purchase) {
+            for id in sale_ids(&activity) {
+                self.sales
+                    .insert((activity.token_id.clone(), id.to_owned()));
+            }
+        }
+        self.accounts
+            .entry(activity.account_id.clone())
+            .or_default()
+            .push(activity);
+    }
+
+    pub fn record(&mut self, entry: &ProvenanceEntry) -> anyhow::Result<()> {
+        for (account_id, kind, counterparty_id) in activities_of(entry) {
+            let activity = Activity {
+                cursor: self.next_cursor,
+                account_id,
+                kind,
+                token_id: entry.token_id.clone(),
+                contract_id: entry.contract_id.clone(),
+                counterparty_id,
+                price: entry.price,
+                receipt_id: entry.receipt_id.clone(),
+                transaction_hash: entry.transaction_hash.clone(),
+                block_height: entry.block_height,
+                timestamp_ms: entry.timestamp_ms,
+            };
+            self.store.append(LOG, &activity)?;
+            self.insert(activity);
+        }
+
+        Ok(())
+    }
+
+    fn completes_sale(&self, activity: &Activity) -> bool {
+        matches!(
+            activity.kind,
+            ActivityKind::TransferSent | ActivityKind::TransferReceived
+        ) && sale_ids(activity).any(|id| {
+            self.sales
+                .contains(&(activity.token_id.clone(), id.to_owned()))
+        })
+    }
+
+    /// Newest first, starting right before `before` when it's given.
+    pub fn activities(
+        &self,
+        account_id: &str,
+        kinds: &[ActivityKind],
+        before: Option<u64>,
+        limit: usize,
+    ) -> ActivityPage {
+        let mut matching = self
+            .accounts
+            .get(account_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|a| before.map_or(true, |before| a.cursor < before))
+            .filter(|a| kinds.is_empty() || kinds.contains(&a.kind))
+            .filter(|a| !self.completes_sale(a));
+        let activities = matching.by_ref().take(limit).cloned().collect::<Vec<_>>();
+        let next_cursor = match matching.next() {
+            Some(_) => activities.last().map(|a| a.cursor),
+            None => None,
+        };
+
+        ActivityPage {
+            account_id: account_id.to_owned(),
+            activities,
+            next_cursor,
+        }
+    }
+}
+
+/// Ids a sale and the transfer completing it share: the receipt or the transaction.
+fn sale_ids(activity: &Activity) -> impl Iterator<Item = &str> {
+    std::iter::once(activity.receipt_id.as_str()).chain(activity.transaction_hash.as_deref())
+}
+
+#[tracing::instrument(name = "Getting activity feed")]
+pub async fn get_activity() -> &'static Mutex<ActivityFeed> {
+    ACTIVITY
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let feed = ActivityFeed::load(store).expect("Couldn't load activity feed");
+            Mutex::new(feed)
+        })
+        .await
+}
+
+/// Adds the activity following from newly logged provenance entries.
+#[tracing::instrument(name = "Recording account activity", skip(entries))]
+pub async fn record(entries: &[ProvenanceEntry]) -> anyhow::Result<()> {
+    let mut feed = get_activity().await.lock().expect("Activity lock poisoned");
+    for entry in entries {
+        feed.record(entry)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn activity(cursor: u64, kind: ActivityKind, receipt_id: &str) -> Activity {
+        Activity {
+            cursor,
+            account_id: "alice.near".to_owned(),
+            kind,
+            token_id: "token-1".to_owned(),
+            contract_id: "nft.near".to_owned(),
+            counterparty_id: Some("bob.near".to_owned()),
+            price: None,
+            receipt_id: receipt_id.to_owned(),
+            transaction_hash: Some("tx-1".to_owned()),
+            block_height: 1,
+            timestamp_ms: 1,
+        }
+    }
+
+    #[test]
+    fn shows_transfer_completing_a_sale_as_the_sale() {
+        let dir = testing::state_dir("activity");
+        let mut feed = ActivityFeed::load(JsonStore::new(dir)).unwrap();
+        feed.insert(activity(0, ActivityKind::TransferSent, "receipt-1"));
+        feed.insert(activity(1, ActivityKind::Sale, "receipt-2"));
+        feed.insert(activity(2, ActivityKind::TransferSent, "receipt-3"));
+        let mut unrelated = activity(3, ActivityKind::TransferSent, "receipt-4");
+        unrelated.transaction_hash = Some("tx-2".to_owned());
+        feed.insert(unrelated);
+
+        let kinds = feed
+            .activities("alice.near", &[], None, 10)
+            .activities
+            .into_iter()
+            .map(|a| (a.cursor, a.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [(3, ActivityKind::TransferSent), (1, ActivityKind::Sale)]
+        );
+    }
+}
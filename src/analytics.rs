@@ -0,0 +1,731 @@
+use crate::config::RestConfig;
+use crate::consts::ANALYTICS;
+use crate::events::{self, nft::NftChange};
+use crate::get_config;
+use crate::listings::{ListingKey, ListingKind};
+use crate::models::BlockContext;
+use crate::pricing;
+use crate::routing::get_router;
+use crate::store::JsonStore;
+use actix_web::web;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+const STATE: &str = "analytics";
+const LOG: &str = "analytics_updates";
+
+/// Bucket aggregating every token type of a collection.
+pub const ALL_TYPES: &str = "*";
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+const WEEK_MS: u64 = 7 * DAY_MS;
+
+#[derive(Deserialize, Clone)]
+pub struct AnalyticsConfig {
+    #[serde(default = "default_candle_intervals_secs")]
+    pub candle_intervals_secs: Vec<u64>,
+    /// Candles kept per interval, older ones are dropped.
+    #[serde(default = "default_max_candles")]
+    pub max_candles: usize,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            candle_intervals_secs: default_candle_intervals_secs(),
+            max_candles: default_max_candles(),
+        }
+    }
+}
+
+fn default_candle_intervals_secs() -> Vec<u64> {
+    vec![60 * 60, 24 * 60 * 60]
+}
+
+fn default_max_candles() -> usize {
+    1000
+}
+
+/// Collection and type a token belongs to, learned when it's minted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenClass {
+    pub collection: String,
+    pub token_type: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct SalePoint {
+    timestamp_ms: u64,
+    price: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Candle {
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub count: u64,
+}
+
+impl Candle {
+    fn new(price: Decimal) -> Self {
+        Self {
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: price,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, price: Decimal) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += price;
+        self.count += 1;
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CandleForRest {
+    pub start_ms: u64,
+    pub interval_secs: u64,
+    #[serde(flatten)]
+    pub candle: Candle,
+}
+
+/// Trading state of one token type of a collection, prices in NEAR.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct CollectionState {
+    /// Sales of the last week, oldest first.
+    sales: VecDeque<SalePoint>,
+    total_volume: Decimal,
+    total_sales: u64,
+    last_sale_price: Option<Decimal>,
+    /// Open listing prices keyed by `token_id/account_id`.
+    asks: HashMap<String, Decimal>,
+    bids: HashMap<String, Decimal>,
+    /// Interval in seconds to candles keyed by their start in milliseconds.
+    candles: BTreeMap<u64, BTreeMap<u64, Candle>>,
+}
+
+impl CollectionState {
+    fn add_sale(&mut self, timestamp_ms: u64, price: Decimal, config: &AnalyticsConfig) {
+        self.sales.push_back(SalePoint {
+            timestamp_ms,
+            price,
+        });
+        self.total_volume += price;
+        self.total_sales += 1;
+        self.last_sale_price = Some(price);
+
+        for &interval_secs in &config.candle_intervals_secs {
+            let interval_ms = interval_secs * 1000;
+            if interval_ms == 0 {
+                continue;
+            }
+            let start_ms = timestamp_ms - timestamp_ms % interval_ms;
+            let candles = self.candles.entry(interval_secs).or_default();
+            candles
+                .entry(start_ms)
+                .and_modify(|candle| candle.add(price))
+                .or_insert_with(|| Candle::new(price));
+            while candles.len() > config.max_candles {
+                let oldest = *candles.keys().next().expect("Candles are not empty");
+                candles.remove(&oldest);
+            }
+        }
+    }
+
+    /// Whether a sale left the 24 hours or 7 days window between the two timestamps.
+    fn windows_moved(&self, from_ms: u64, to_ms: u64) -> bool {
+        self.sales.iter().any(|sale| {
+            [DAY_MS, WEEK_MS]
+                .iter()
+                .any(|window| (from_ms..to_ms).contains(&(sale.timestamp_ms + window)))
+        })
+    }
+
+    fn prune(&mut self, now_ms: u64) {
+        while let Some(sale) = self.sales.front() {
+            if sale.timestamp_ms + WEEK_MS >= now_ms {
+                break;
+            }
+            self.sales.pop_front();
+        }
+    }
+
+    fn stats(&self, collection: &str, token_type: &str, now_ms: u64) -> CollectionStats {
+        let mut stats = CollectionStats {
+            collection: collection.to_owned(),
+            token_type: token_type.to_owned(),
+            volume_24h: Decimal::ZERO,
+            volume_7d: Decimal::ZERO,
+            sales_24h: 0,
+            sales_7d: 0,
+            total_volume: self.total_volume,
+            total_sales: self.total_sales,
+            last_sale_price: self.last_sale_price,
+            floor_price: self.asks.values().min().copied(),
+            top_bid: self.bids.values().max().copied(),
+            listed_count: self.asks.len(),
+            timestamp_ms: now_ms,
+        };
+        for sale in &self.sales {
+            if sale.timestamp_ms + WEEK_MS >= now_ms {
+                stats.volume_7d += sale.price;
+                stats.sales_7d += 1;
+            }
+            if sale.timestamp_ms + DAY_MS >= now_ms {
+                stats.volume_24h += sale.price;
+                stats.sales_24h += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+/// Trading figures of a collection, or of one token type when `token_type` isn't `*`.
+#[derive(Serialize, Debug, Clone)]
+pub struct CollectionStats {
+    pub collection: String,
+    pub token_type: String,
+    pub volume_24h: Decimal,
+    pub volume_7d: Decimal,
+    pub sales_24h: u64,
+    pub sales_7d: u64,
+    pub total_volume: Decimal,
+    pub total_sales: u64,
+    pub last_sale_price: Option<Decimal>,
+    pub floor_price: Option<Decimal>,
+    pub top_bid: Option<Decimal>,
+    pub listed_count: usize,
+    pub timestamp_ms: u64,
+}
+
+/// Change to the analytics, logged to `analytics_updates.jsonl` in the state dir until the
+/// next snapshot. Token and listing updates set a value, so replaying them changes nothing,
+/// while sales are only counted for blocks the analytics don't include yet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Update {
+    Token {
+        token_id: String,
+        class: TokenClass,
+    },
+    Listing {
+        class: TokenClass,
+        key: ListingKey,
+        price: Option<Decimal>,
+    },
+    Sale {
+        class: TokenClass,
+        timestamp_ms: u64,
+        price: Decimal,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct LoggedUpdate {
+    block_height: u64,
+    update: Update,
+}
+
+/// Per-collection trading analytics, fed by mints, listings and sales.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Analytics {
+    tokens: HashMap<String, TokenClass>,
+    /// Collection to token type to its state, `*` holding all types.
+    collections: HashMap<String, HashMap<String, CollectionState>>,
+    /// Timestamp of the last handled block, "now" for rolling windows.
+    last_timestamp_ms: u64,
+    /// Last block included in the snapshot, later ones are replayed from the log.
+    #[serde(default)]
+    snapshot_height: Option<u64>,
+    /// Last block whose updates are included, in the snapshot or the log.
+    #[serde(default)]
+    block_height: Option<u64>,
+    #[serde(skip)]
+    changed: HashSet<TokenClass>,
+    /// Updates made during the block, to be logged.
+    #[serde(skip)]
+    pending: Vec<Update>,
+    /// Whether there were updates since the last snapshot.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Analytics {
+    /// Loads the last snapshot and replays the updates logged after it.
+    fn load(store: &JsonStore, config: &AnalyticsConfig) -> anyhow::Result<Self> {
+        let mut analytics: Self = store.load(STATE)?;
+        for logged in store.load_lines::<LoggedUpdate>(LOG)? {
+            let snapshotted = analytics
+                .snapshot_height
+                .map_or(false, |height| logged.block_height <= height);
+            if !snapshotted {
+                analytics.apply(logged.update, config);
+                analytics.dirty = true;
+            }
+            analytics.block_height = analytics.block_height.max(Some(logged.block_height));
+        }
+        analytics.block_height = analytics.block_height.max(analytics.snapshot_height);
+        analytics.changed.clear();
+
+        Ok(analytics)
+    }
+
+    /// Returns `false` for blocks already included in the analytics.
+    pub fn accepts(&self, block_height: u64) -> bool {
+        self.block_height
+            .map_or(true, |height| block_height > height)
+    }
+
+    fn apply(&mut self, update: Update, config: &AnalyticsConfig) {
+        match update {
+            Update::Token { token_id, class } => {
+                self.tokens.insert(token_id, class);
+            }
+            Update::Listing { class, key, price } => {
+                let listing_id = format!("{}/{}", key.token_id, key.account_id);
+                self.update(&class, |state| {
+                    let listings = match key.kind {
+                        ListingKind::Ask => &mut state.asks,
+                        ListingKind::Bid => &mut state.bids,
+                    };
+                    match price {
+                        Some(price) => listings.insert(listing_id.clone(), price),
+                        None => listings.remove(&listing_id),
+                    };
+                });
+            }
+            Update::Sale {
+                class,
+                timestamp_ms,
+                price,
+            } => self.update(&class, |state| state.add_sale(timestamp_ms, price, config)),
+        }
+    }
+
+    fn record(&mut self, update: Update, config: &AnalyticsConfig) {
+        self.dirty = true;
+        self.pending.push(update.clone());
+        self.apply(update, config);
+    }
+
+    pub fn register_token(
+        &mut self,
+        token_id: String,
+        class: TokenClass,
+        config: &AnalyticsConfig,
+    ) {
+        self.record(Update::Token { token_id, class }, config);
+    }
+
+    /// Unknown tokens are attributed to the configured NFT contract.
+    pub fn token_class(&self, token_id: &str, default_collection: &str) -> TokenClass {
+        self.tokens
+            .get(token_id)
+            .cloned()
+            .unwrap_or_else(|| TokenClass {
+                collection: default_collection.to_owned(),
+                token_type: "unknown".to_owned(),
+            })
+    }
+
+    /// Runs `f` on the token type's state and on the collection-wide one.
+    fn update(&mut self, class: &TokenClass, mut f: impl FnMut(&mut CollectionState)) {
+        let types = self
+            .collections
+            .entry(class.collection.clone())
+            .or_default();
+        f(types.entry(class.token_type.clone()).or_default());
+        f(types.entry(ALL_TYPES.to_owned()).or_default());
+        self.changed.insert(class.clone());
+        self.changed.insert(TokenClass {
+            collection: class.collection.clone(),
+            token_type: ALL_TYPES.to_owned(),
+        });
+    }
+
+    pub fn set_listing(
+        &mut self,
+        class: &TokenClass,
+        key: &ListingKey,
+        price: Option<Decimal>,
+        config: &AnalyticsConfig,
+    ) {
+        let update = Update::Listing {
+            class: class.clone(),
+            key: key.clone(),
+            price,
+        };
+        self.record(update, config);
+    }
+
+    pub fn add_sale(
+        &mut self,
+        class: &TokenClass,
+        timestamp_ms: u64,
+        price: Decimal,
+        config: &AnalyticsConfig,
+    ) {
+        let update = Update::Sale {
+            class: class.clone(),
+            timestamp_ms,
+            price,
+        };
+        self.record(update, config);
+    }
+
+    pub fn stats(&self, collection: &str, token_type: &str) -> Option<CollectionStats> {
+        self.collections
+            .get(collection)?
+            .get(token_type)
+            .map(|state| state.stats(collection, token_type, self.last_timestamp_ms))
+    }
+
+    /// Most recent candles last, at most `limit` of them.
+    pub fn candles(
+        &self,
+        collection: &str,
+        token_type: &str,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Option<Vec<CandleForRest>> {
+        let candles = self
+            .collections
+            .get(collection)?
+            .get(token_type)?
+            .candles
+            .get(&interval_secs);
+        let mut ret = candles
+            .into_iter()
+            .flatten()
+            .rev()
+            .take(limit)
+            .map(|(&start_ms, &candle)| CandleForRest {
+                start_ms,
+                interval_secs,
+                candle,
+            })
+            .collect::<Vec<_>>();
+        ret.reverse();
+
+        Some(ret)
+    }
+
+    /// Moves "now" to the block's timestamp and returns the stats that changed since the last
+    /// call, including the ones whose rolling windows a sale left.
+    fn advance(&mut self, timestamp_ms: u64) -> Vec<CollectionStats> {
+        let from_ms = self.last_timestamp_ms;
+        self.last_timestamp_ms = timestamp_ms;
+        for (collection, types) in &mut self.collections {
+            for (token_type, state) in types {
+                if state.windows_moved(from_ms, timestamp_ms) {
+                    self.changed.insert(TokenClass {
+                        collection: collection.clone(),
+                        token_type: token_type.clone(),
+                    });
+                }
+                state.prune(timestamp_ms);
+            }
+        }
+
+        let changed = std::mem::take(&mut self.changed);
+        changed
+            .iter()
+            .filter_map(|class| self.stats(&class.collection, &class.token_type))
+            .collect()
+    }
+}
+
+/// Type of a token according to its `model`: the `kind` or `type` field, or the name of the
+/// variant it's tagged with.
+pub fn token_type(model: &Value) -> String {
+    let type_name = match model {
+        Value::String(name) => Some(name.as_str()),
+        Value::Object(fields) => fields
+            .get("kind")
+            .or_else(|| fields.get("type"))
+            .and_then(Value::as_str)
+            .or_else(|| match fields.len() {
+                1 => fields.keys().next().map(String::as_str),
+                _ => None,
+            }),
+        _ => None,
+    };
+
+    type_name.unwrap_or("unknown").to_lowercase()
+}
+
+#[tracing::instrument(name = "Getting analytics")]
+pub async fn get_analytics() -> &'static Mutex<Analytics> {
+    ANALYTICS
+        .get_or_init(|| async {
+            let config = get_config().await;
+            let analytics = Analytics::load(&config.store(), &config.analytics)
+                .expect("Couldn't load analytics");
+            Mutex::new(analytics)
+        })
+        .await
+}
+
+/// Remembers the collection and type of minted tokens.
+pub async fn observe_nft_change(nft_contract_id: &str, change: &NftChange) {
+    let tokens = match change {
+        NftChange::Minted(tokens) => tokens,
+        _ => return,
+    };
+
+    let config = get_config().await;
+    let mut analytics = get_analytics()
+        .await
+        .lock()
+        .expect("Analytics lock poisoned");
+    for token in tokens {
+        let model = serde_json::to_value(&token.model).unwrap_or_default();
+        let class = TokenClass {
+            collection: nft_contract_id.to_owned(),
+            token_type: token_type(&model),
+        };
+        analytics.register_token(token.token_id.clone(), class, &config.analytics);
+    }
+}
+
+/// Updates floor price and top bid, `price_yocto` being `None` once the listing is closed.
+pub async fn observe_listing(key: &ListingKey, price_yocto: Option<Decimal>) {
+    let price = match price_yocto.map(pricing::yocto_to_near).transpose() {
+        Ok(price) => price,
+        Err(e) => {
+            tracing::error!("Skipping listing in analytics: {:?}", e);
+            return;
+        }
+    };
+
+    let config = get_config().await;
+    let default_collection = default_collection().await;
+    let mut analytics = get_analytics()
+        .await
+        .lock()
+        .expect("Analytics lock poisoned");
+    let class = analytics.token_class(&key.token_id, &default_collection);
+    analytics.set_listing(&class, key, price, &config.analytics);
+}
+
+/// Counts the sale towards volume and candles and closes the listing it filled.
+pub async fn observe_sale(
+    token_id: &str,
+    price_yocto: Decimal,
+    filled: Option<&ListingKey>,
+    block: BlockContext,
+) {
+    let price = match pricing::yocto_to_near(price_yocto) {
+        Ok(price) => price,
+        Err(e) => {
+            tracing::error!("Skipping sale in analytics: {:?}", e);
+            return;
+        }
+    };
+
+    let config = get_config().await;
+    let default_collection = default_collection().await;
+    let mut analytics = get_analytics()
+        .await
+        .lock()
+        .expect("Analytics lock poisoned");
+    if !analytics.accepts(block.height) {
+        tracing::debug!("Sale of block {} is already counted", block.height);
+        return;
+    }
+    let class = analytics.token_class(token_id, &default_collection);
+    analytics.add_sale(&class, block.timestamp_ms(), price, &config.analytics);
+    if let Some(key) = filled {
+        analytics.set_listing(&class, key, None, &config.analytics);
+    }
+}
+
+async fn default_collection() -> String {
+    let (_, nft, _) = get_config().await.contracts.ids();
+    let nft: &str = nft.as_ref();
+    nft.to_owned()
+}
+
+/// Logs the updates made during the block, saves a snapshot every `snapshot_interval_blocks`
+/// and sends the stats that changed to the rest service of their collection.
+#[tracing::instrument(name = "Publishing collection analytics", skip(client))]
+pub async fn publish(
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let (stats, updates, snapshot) = {
+        let mut analytics = get_analytics()
+            .await
+            .lock()
+            .expect("Analytics lock poisoned");
+        let stats = analytics.advance(block.timestamp_ms());
+        let updates = std::mem::take(&mut analytics.pending);
+        analytics.block_height = analytics.block_height.max(Some(block.height));
+        let due = analytics.snapshot_height.map_or(true, |height| {
+            block.height >= height + config.snapshot_interval_blocks
+        });
+        let snapshot = if due && analytics.dirty {
+            analytics.snapshot_height = Some(block.height);
+            analytics.dirty = false;
+            Some(analytics.clone())
+        } else {
+            None
+        };
+        (stats, updates, snapshot)
+    };
+
+    let store = config.store();
+    for update in updates {
+        let logged = LoggedUpdate {
+            block_height: block.height,
+            update,
+        };
+        store.append(LOG, &logged)?;
+    }
+    if let Some(snapshot) = snapshot {
+        store.save(STATE, &snapshot)?;
+        store.remove_lines(LOG)?;
+    }
+
+    let mut by_collection = HashMap::<String, Vec<CollectionStats>>::new();
+    for stats in stats {
+        by_collection
+            .entry(stats.collection.clone())
+            .or_default()
+            .push(stats);
+    }
+    let router = get_router().await.read().await;
+    for (collection, stats) in by_collection {
+        let sink = router
+            .route(&collection)
+            .map_or(&config.rest, |route| &route.sink);
+        store_stats(&stats, client.clone(), sink).await?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store collection stats",
+    skip(stats, client, sink)
+)]
+async fn store_stats(
+    stats: &[CollectionStats],
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/collection_stats", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(stats)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn class() -> TokenClass {
+        TokenClass {
+            collection: "nft.near".to_owned(),
+            token_type: "lemon".to_owned(),
+        }
+    }
+
+    fn store(name: &str) -> JsonStore {
+        JsonStore::new(testing::state_dir(&format!("analytics_{name}")))
+    }
+
+    fn sale(block_height: u64, price: Decimal) -> LoggedUpdate {
+        LoggedUpdate {
+            block_height,
+            update: Update::Sale {
+                class: class(),
+                timestamp_ms: block_height * 1000,
+                price,
+            },
+        }
+    }
+
+    #[test]
+    fn aggregates_sales_per_type_and_collection() {
+        let config = AnalyticsConfig::default();
+        let mut analytics = Analytics::default();
+        analytics.add_sale(&class(), 0, Decimal::from(2), &config);
+        analytics.add_sale(&class(), DAY_MS, Decimal::from(5), &config);
+        let key = ListingKey {
+            kind: ListingKind::Ask,
+            market_contract_id: "market.near".to_owned(),
+            token_id: "1".to_owned(),
+            account_id: "alice.near".to_owned(),
+        };
+        analytics.set_listing(&class(), &key, Some(Decimal::from(3)), &config);
+
+        let changed = analytics.advance(DAY_MS + 1);
+        assert_eq!(changed.len(), 2);
+        let stats = analytics.stats("nft.near", ALL_TYPES).unwrap();
+        assert_eq!(stats.total_sales, 2);
+        assert_eq!(stats.sales_24h, 1);
+        assert_eq!(stats.volume_7d, Decimal::from(7));
+        assert_eq!(stats.floor_price, Some(Decimal::from(3)));
+        let candles = analytics
+            .candles("nft.near", "lemon", 24 * 60 * 60, 10)
+            .unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[1].start_ms, DAY_MS);
+        assert_eq!(candles[1].candle.close, Decimal::from(5));
+    }
+
+    #[test]
+    fn replays_only_updates_after_the_snapshot() {
+        let config = AnalyticsConfig::default();
+        let store = store("replay");
+        let mut snapshot = Analytics::default();
+        snapshot.add_sale(&class(), 5000, Decimal::from(1), &config);
+        snapshot.snapshot_height = Some(5);
+        store.save(STATE, &snapshot).unwrap();
+        store.append(LOG, &sale(5, Decimal::from(1))).unwrap();
+        store.append(LOG, &sale(6, Decimal::from(4))).unwrap();
+
+        let analytics = Analytics::load(&store, &config).unwrap();
+
+        let stats = analytics.stats("nft.near", "lemon").unwrap();
+        assert_eq!(stats.total_sales, 2);
+        assert_eq!(stats.total_volume, Decimal::from(5));
+        assert!(!analytics.accepts(6));
+        assert!(analytics.accepts(7));
+    }
+
+    #[test]
+    fn accepts_every_block_before_the_first_one() {
+        assert!(Analytics::default().accepts(0));
+    }
+
+    #[test]
+    fn reads_token_type_from_the_model() {
+        assert_eq!(token_type(&json!({ "kind": "Lemon" })), "lemon");
+        assert_eq!(token_type(&json!({ "Weapon": { "level": 1 } })), "weapon");
+        assert_eq!(token_type(&json!("Outfit")), "outfit");
+        assert_eq!(token_type(&json!({ "a": 1, "b": 2 })), "unknown");
+    }
+}
@@ -0,0 +1,422 @@
+use crate::activity::{self, ActivityKind};
+use crate::analytics::{self, ALL_TYPES};
+use crate::composition::{self, PartParent, TokenParts};
+use crate::contract_metadata;
+use crate::fungible;
+use crate::get_config;
+use crate::models::U128;
+use crate::provenance;
+use crate::search::{self, SearchFilters};
+use crate::traits;
+use crate::upgrades;
+use actix_web::dev::Server;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone)]
+pub struct ApiConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_owned(),
+            port: 8080,
+        }
+    }
+}
+
+/// Starts the HTTP server exposing the indexer's own state.
+#[tracing::instrument(name = "Starting up indexer API", skip(config))]
+pub fn run_api(config: &ApiConfig) -> anyhow::Result<Server> {
+    let server = HttpServer::new(|| {
+        App::new()
+            .route("/metrics", web::get().to(metrics))
+            .route(
+                "/collections/{collection}/stats",
+                web::get().to(collection_stats),
+            )
+            .route(
+                "/collections/{collection}/candles",
+                web::get().to(collection_candles),
+            )
+            .route(
+                "/collections/{collection}/traits",
+                web::get().to(collection_traits),
+            )
+            .route(
+                "/collections/{collection}/rarity",
+                web::get().to(collection_rarity),
+            )
+            .route(
+                "/collections/{collection}/tokens/{token_id}/rarity",
+                web::get().to(token_rarity),
+            )
+            .route(
+                "/contracts/{contract_id}/metadata",
+                web::get().to(contract_metadata),
+            )
+            .route(
+                "/contracts/{contract_id}/deployments",
+                web::get().to(contract_deployments),
+            )
+            .route("/ft/{contract_id}", web::get().to(ft_supply))
+            .route(
+                "/ft/{contract_id}/balances/{account_id}",
+                web::get().to(ft_balance),
+            )
+            .route(
+                "/accounts/{account_id}/ft_balances",
+                web::get().to(account_ft_balances),
+            )
+            .route("/search", web::get().to(search_tokens))
+            .route(
+                "/accounts/{account_id}/activity",
+                web::get().to(account_activity),
+            )
+            .route(
+                "/contracts/{contract_id}/tokens/{token_id}/history",
+                web::get().to(token_history),
+            )
+            .route("/tokens/{token_id}/parts", web::get().to(token_parts))
+            .route("/tokens/{token_id}/parent", web::get().to(token_parent))
+    })
+    .bind((config.host.as_str(), config.port))?
+    .run();
+
+    Ok(server)
+}
+
+async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    token_type: Option<String>,
+}
+
+async fn collection_stats(
+    collection: web::Path<String>,
+    query: web::Query<StatsQuery>,
+) -> HttpResponse {
+    let token_type = query.token_type.as_deref().unwrap_or(ALL_TYPES);
+    let stats = analytics::get_analytics()
+        .await
+        .lock()
+        .expect("Analytics lock poisoned")
+        .stats(&collection, token_type);
+    match stats {
+        Some(stats) => HttpResponse::Ok().json(stats),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CandlesQuery {
+    token_type: Option<String>,
+    interval: Option<u64>,
+    limit: Option<usize>,
+}
+
+async fn collection_candles(
+    collection: web::Path<String>,
+    query: web::Query<CandlesQuery>,
+) -> HttpResponse {
+    let intervals = &get_config().await.analytics.candle_intervals_secs;
+    let interval = match query.interval.or_else(|| intervals.first().copied()) {
+        Some(interval) if intervals.contains(&interval) => interval,
+        _ => {
+            return HttpResponse::BadRequest()
+                .body(format!("Interval must be one of {intervals:?} seconds"))
+        }
+    };
+    let token_type = query.token_type.as_deref().unwrap_or(ALL_TYPES);
+    let candles = analytics::get_analytics()
+        .await
+        .lock()
+        .expect("Analytics lock poisoned")
+        .candles(
+            &collection,
+            token_type,
+            interval,
+            query.limit.unwrap_or(100),
+        );
+    match candles {
+        Some(candles) => HttpResponse::Ok().json(candles),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(50).min(MAX_PAGE_SIZE)
+    }
+}
+
+async fn token_history(
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    let (contract_id, token_id) = path.into_inner();
+    let page = provenance::get_provenance()
+        .await
+        .lock()
+        .expect("Provenance lock poisoned")
+        .history(&contract_id, &token_id, query.offset, query.limit());
+
+    HttpResponse::Ok().json(page)
+}
+
+#[derive(Deserialize)]
+struct HeightQuery {
+    block_height: Option<u64>,
+}
+
+async fn token_parts(token_id: web::Path<String>, query: web::Query<HeightQuery>) -> HttpResponse {
+    let parts = composition::get_composition()
+        .await
+        .lock()
+        .expect("Composition lock poisoned")
+        .parts(&token_id, query.block_height);
+
+    HttpResponse::Ok().json(TokenParts {
+        token_id: token_id.into_inner(),
+        block_height: query.block_height,
+        parts: parts.into_iter().collect(),
+    })
+}
+
+async fn token_parent(token_id: web::Path<String>, query: web::Query<HeightQuery>) -> HttpResponse {
+    let parent_id = composition::get_composition()
+        .await
+        .lock()
+        .expect("Composition lock poisoned")
+        .parent(&token_id, query.block_height)
+        .map(str::to_owned);
+
+    HttpResponse::Ok().json(PartParent {
+        token_id: token_id.into_inner(),
+        block_height: query.block_height,
+        parent_id,
+    })
+}
+
+async fn collection_traits(collection: web::Path<String>) -> HttpResponse {
+    let index = traits::get_traits()
+        .await
+        .lock()
+        .expect("Traits lock poisoned");
+    match index.counts(&collection) {
+        Some((supply, counts)) => HttpResponse::Ok().json(serde_json::json!({
+            "collection": collection.as_str(),
+            "supply": supply,
+            "traits": counts,
+        })),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct RarityQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    /// Only tokens having this trait, as `type:value`.
+    #[serde(rename = "trait")]
+    trait_filter: Option<String>,
+}
+
+async fn collection_rarity(
+    collection: web::Path<String>,
+    query: web::Query<RarityQuery>,
+) -> HttpResponse {
+    let trait_filter = match query.trait_filter.as_deref().map(|t| t.split_once(':')) {
+        Some(None) => return HttpResponse::BadRequest().body("Trait must be `type:value`"),
+        Some(Some(filter)) => Some(filter),
+        None => None,
+    };
+    let ranking = traits::get_traits()
+        .await
+        .lock()
+        .expect("Traits lock poisoned")
+        .ranking(&collection);
+    let ranking = match ranking {
+        Some(ranking) => ranking,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let limit = query.limit.unwrap_or(50).min(MAX_PAGE_SIZE);
+    let page = ranking
+        .into_iter()
+        .filter(|rarity| {
+            trait_filter.map_or(true, |(trait_type, value)| {
+                rarity.traits.get(trait_type).map(String::as_str) == Some(value)
+            })
+        })
+        .skip(query.offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(page)
+}
+
+async fn token_rarity(path: web::Path<(String, String)>) -> HttpResponse {
+    let (collection, token_id) = path.into_inner();
+    let rarity = traits::get_traits()
+        .await
+        .lock()
+        .expect("Traits lock poisoned")
+        .rarity(&collection, &token_id);
+    match rarity {
+        Some(rarity) => HttpResponse::Ok().json(rarity),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn contract_metadata(contract_id: web::Path<String>) -> HttpResponse {
+    let metadata = contract_metadata::get_contract_metadata()
+        .await
+        .lock()
+        .expect("Contract metadata lock poisoned")
+        .get(contract_id.as_str())
+        .cloned();
+    match metadata {
+        Some(metadata) => HttpResponse::Ok().json(metadata),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn contract_deployments(contract_id: web::Path<String>) -> HttpResponse {
+    let deployments = upgrades::get_deployments()
+        .await
+        .lock()
+        .expect("Deployments lock poisoned")
+        .deployments(&contract_id)
+        .to_vec();
+
+    HttpResponse::Ok().json(deployments)
+}
+
+async fn ft_supply(contract_id: web::Path<String>) -> HttpResponse {
+    let ledger = fungible::get_ft_ledger()
+        .await
+        .lock()
+        .expect("Fungible token ledger lock poisoned");
+    match ledger.contract(&contract_id) {
+        Some(contract) => HttpResponse::Ok().json(serde_json::json!({
+            "contract_id": contract_id.as_str(),
+            "total_supply": contract.total_supply,
+            "holders": contract.balances.len(),
+        })),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn ft_balance(path: web::Path<(String, String)>) -> HttpResponse {
+    let (contract_id, account_id) = path.into_inner();
+    let ledger = fungible::get_ft_ledger()
+        .await
+        .lock()
+        .expect("Fungible token ledger lock poisoned");
+    match ledger.contract(&contract_id) {
+        Some(contract) => HttpResponse::Ok().json(serde_json::json!({
+            "contract_id": contract_id,
+            "account_id": account_id,
+            "balance": U128(contract.balance(&account_id)),
+        })),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn account_ft_balances(account_id: web::Path<String>) -> HttpResponse {
+    let ledger = fungible::get_ft_ledger()
+        .await
+        .lock()
+        .expect("Fungible token ledger lock poisoned");
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "account_id": account_id.as_str(),
+        "balances": ledger.account_balances(&account_id),
+    }))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    owner: Option<String>,
+    collection: Option<String>,
+    #[serde(rename = "trait")]
+    trait_filter: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn search_tokens(query: web::Query<SearchQuery>) -> HttpResponse {
+    let query = query.into_inner();
+    let filters = SearchFilters {
+        owner: query.owner,
+        collection: query.collection,
+        trait_filter: query.trait_filter,
+    };
+    let limit = query.limit.unwrap_or(50).min(MAX_PAGE_SIZE);
+    let results = search::get_search()
+        .await
+        .lock()
+        .expect("Search lock poisoned")
+        .search(&query.q, &filters, query.offset, limit);
+
+    HttpResponse::Ok().json(results)
+}
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    /// Comma separated activity kinds, all of them when absent.
+    kind: Option<String>,
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+async fn account_activity(
+    account_id: web::Path<String>,
+    query: web::Query<ActivityQuery>,
+) -> HttpResponse {
+    let mut kinds = Vec::new();
+    for kind in query.kind.iter().flat_map(|k| k.split(',')) {
+        match ActivityKind::parse(kind.trim()) {
+            Some(kind) => kinds.push(kind),
+            None => {
+                return HttpResponse::BadRequest().body(format!("Unknown activity kind `{kind}`"))
+            }
+        }
+    }
+    let limit = query.limit.unwrap_or(50).min(MAX_PAGE_SIZE);
+    let page = activity::get_activity()
+        .await
+        .lock()
+        .expect("Activity lock poisoned")
+        .activities(&account_id, &kinds, query.before, limit);
+
+    HttpResponse::Ok().json(page)
+}
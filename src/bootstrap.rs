@@ -0,0 +1,247 @@
+use crate::analytics;
+use crate::checkpoint;
+use crate::composition;
+use crate::config::{get_config, RestConfig};
+use crate::events;
+use crate::events::nft::NftChange;
+use crate::listings::{self, ListingKind};
+use crate::models::BlockContext;
+use crate::provenance::{self, ProvenanceEntry};
+use crate::reconcile;
+use crate::routing;
+use crate::rpc::{BlockRef, RpcClient, Views};
+use crate::search;
+use crate::snapshot::RPC_PAGE_SIZE;
+use crate::traits;
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use battlemon_models::nft::{NftTokenForRest, TokenExt};
+use serde::Serialize;
+use serde_json::json;
+
+/// State bootstrapping doesn't replace, since view calls don't give it back: fetched IPFS
+/// metadata, the deploy history and metadata of the contracts, contracts discovered under
+/// factories, fungible token balances, and the provenance, activity and part histories.
+const KEPT_STATE: [&str; 8] = [
+    "ipfs_cache",
+    "deployments",
+    "contract_metadata",
+    "discovered_contracts",
+    "ft_ledger",
+    "provenance",
+    "activity",
+    "composition",
+];
+
+const USAGE: &str = "Usage: battlemon_indexer bootstrap [--height <block height>] [--force]";
+
+#[derive(Debug)]
+pub struct BootstrapArgs {
+    /// The final block when absent.
+    pub block_height: Option<u64>,
+    /// Bootstraps even though the indexer already has a checkpoint.
+    pub force: bool,
+}
+
+impl BootstrapArgs {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut block_height = None;
+        let mut force = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--height" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("`--height` needs a value"))?;
+                    block_height = Some(value.parse().context("Invalid block height")?);
+                }
+                "--force" => force = true,
+                other => bail!("Unknown argument `{other}`\n{USAGE}"),
+            }
+        }
+
+        Ok(Self {
+            block_height,
+            force,
+        })
+    }
+}
+
+/// Every token of the NFT contract at `block_height`, read page by page with `nft_tokens`.
+#[tracing::instrument(name = "Fetching tokens over RPC", skip(rpc_client))]
+async fn rpc_tokens(
+    rpc_client: &RpcClient,
+    contract_id: &str,
+    block_height: u64,
+) -> anyhow::Result<Vec<NftTokenForRest>> {
+    let mut tokens = Vec::new();
+    let mut from_index = 0;
+    loop {
+        let page: Vec<TokenExt> = rpc_client
+            .view_function(
+                contract_id,
+                "nft_tokens",
+                &json!({ "from_index": from_index.to_string(), "limit": RPC_PAGE_SIZE }),
+                BlockRef::Height(block_height),
+            )
+            .await?;
+        let count = page.len() as u64;
+        for token in page {
+            let token: NftTokenForRest = token
+                .try_into()
+                .map_err(|_| anyhow!("Failed to convert TokenExt to NftTokenForRest"))?;
+            tokens.push(token);
+        }
+        if count < RPC_PAGE_SIZE {
+            return Ok(tokens);
+        }
+        from_index += count;
+    }
+}
+
+async fn post<T: Serialize>(
+    path: &str,
+    body: &T,
+    client: &reqwest::Client,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/{path}", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(body)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+/// Seeds the rest service and local state with the tokens of the NFT contract.
+#[tracing::instrument(name = "Bootstrapping tokens", skip(rpc_client, client, sink))]
+async fn bootstrap_tokens(
+    rpc_client: &RpcClient,
+    nft_contract_id: &str,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<usize> {
+    let tokens = rpc_tokens(rpc_client, nft_contract_id, block.height).await?;
+    for batch in tokens.chunks(RPC_PAGE_SIZE as usize) {
+        post("nft_tokens", batch, &client, sink).await?;
+    }
+
+    let entries = tokens
+        .iter()
+        .map(|token| {
+            ProvenanceEntry::bootstrap(
+                nft_contract_id,
+                token.token_id.clone(),
+                token.owner_id.to_string(),
+                block,
+            )
+        })
+        .collect::<Vec<_>>();
+    provenance::record(entries).await?;
+
+    let count = tokens.len();
+    let change = NftChange::Minted(tokens);
+    let receipt_id = format!("bootstrap:{}", block.height);
+    analytics::observe_nft_change(nft_contract_id, &change).await;
+    traits::observe_nft_change(nft_contract_id, &change).await?;
+    search::observe_nft_change(nft_contract_id, &change).await?;
+    composition::observe_nft_change(&change, &receipt_id, block, client, sink).await?;
+
+    Ok(count)
+}
+
+/// Seeds the rest service and the listing book with the open asks and bids of the market.
+#[tracing::instrument(name = "Bootstrapping listings", skip(rpc_client, client, sink))]
+async fn bootstrap_listings(
+    rpc_client: &RpcClient,
+    market_contract_id: &str,
+    block_height: u64,
+    client: &reqwest::Client,
+    sink: &RestConfig,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for kind in [ListingKind::Ask, ListingKind::Bid] {
+        let on_chain = reconcile::market_listings(
+            Views::AtBlock(rpc_client, block_height),
+            market_contract_id,
+            kind,
+        )
+        .await?;
+        for listing in on_chain {
+            let stored = reconcile::store_listing(
+                kind,
+                market_contract_id,
+                listing,
+                block_height,
+                client,
+                sink,
+            )
+            .await?;
+            if stored {
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Runs the `bootstrap` command: reads the state of the contracts at a block with view calls,
+/// sends it to the rest service and sets the checkpoint to that block, so the indexer starts
+/// right after it. Local state of an earlier run is reset first, except for what view calls
+/// don't give back.
+#[tracing::instrument(name = "Bootstrapping indexer state", skip(args))]
+pub async fn run(args: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
+    let args = BootstrapArgs::parse(args)?;
+    let config = get_config().await;
+    let store = config.store();
+    if let Some(block_height) = checkpoint::load(&store)? {
+        if !args.force {
+            bail!(
+                "The indexer already has a checkpoint at block {block_height}, \
+                pass `--force` to bootstrap anyway"
+            );
+        }
+    }
+    store.clear(&KEPT_STATE)?;
+
+    let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+    let block = match args.block_height {
+        Some(height) => rpc_client
+            .block(BlockRef::Height(height))
+            .await?
+            .with_context(|| format!("There is no block at height {height}"))?,
+        None => rpc_client.final_block().await?,
+    };
+    let block = BlockContext {
+        height: block.header.height,
+        timestamp_nanosec: block.header.timestamp_nanosec,
+    };
+
+    let (_, nft, market) = config.contracts.ids();
+    let (nft, market): (&str, &str) = (nft.as_ref(), market.as_ref());
+    let client = web::Data::new(reqwest::Client::new());
+    let nft_sink = routing::route_sink(nft).await;
+    let token_count = bootstrap_tokens(&rpc_client, nft, block, client.clone(), &nft_sink).await?;
+    let market_sink = routing::route_sink(market).await;
+    let listing_count =
+        bootstrap_listings(&rpc_client, market, block.height, &client, &market_sink).await?;
+
+    listings::persist(block.height).await?;
+    analytics::publish(block, client.clone()).await?;
+    traits::publish(block.height, client).await?;
+    search::persist(block.height).await?;
+    checkpoint::save(&store, block.height)?;
+    tracing::info!(
+        "Bootstrapped {token_count} tokens and {listing_count} listings at block {}",
+        block.height
+    );
+
+    Ok(())
+}
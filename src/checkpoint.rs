@@ -0,0 +1,23 @@
+use crate::store::JsonStore;
+use serde::{Deserialize, Serialize};
+
+const STATE: &str = "checkpoint";
+
+/// Last block whose events were fully handled.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+pub struct Checkpoint {
+    pub block_height: Option<u64>,
+}
+
+pub fn load(store: &JsonStore) -> anyhow::Result<Option<u64>> {
+    Ok(store.load::<Checkpoint>(STATE)?.block_height)
+}
+
+pub fn save(store: &JsonStore, block_height: u64) -> anyhow::Result<()> {
+    store.save(
+        STATE,
+        &Checkpoint {
+            block_height: Some(block_height),
+        },
+    )
+}
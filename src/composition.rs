@@ -0,0 +1,275 @@
+use crate::config::RestConfig;
+use crate::consts::COMPOSITION;
+use crate::events::{self, nft::NftChange};
+use crate::get_config;
+use crate::models::BlockContext;
+use crate::store::JsonStore;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+const LOG: &str = "composition";
+
+/// A part being attached to or detached from a parent token.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompositionEdge {
+    pub parent_id: String,
+    pub part_id: String,
+    pub attached: bool,
+    pub receipt_id: String,
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TokenParts {
+    pub token_id: String,
+    pub block_height: Option<u64>,
+    pub parts: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PartParent {
+    pub token_id: String,
+    pub block_height: Option<u64>,
+    pub parent_id: Option<String>,
+}
+
+/// Parts equipped on each token over time, replayed from `composition.jsonl` in the state dir.
+pub struct CompositionGraph {
+    store: JsonStore,
+    by_parent: HashMap<String, Vec<CompositionEdge>>,
+    by_part: HashMap<String, Vec<CompositionEdge>>,
+}
+
+impl CompositionGraph {
+    pub fn load(store: JsonStore) -> anyhow::Result<Self> {
+        let mut graph = Self {
+            store,
+            by_parent: HashMap::new(),
+            by_part: HashMap::new(),
+        };
+        for edge in graph.store.load_lines::<CompositionEdge>(LOG)? {
+            graph.insert(edge);
+        }
+
+        Ok(graph)
+    }
+
+    fn insert(&mut self, edge: CompositionEdge) {
+        self.by_part
+            .entry(edge.part_id.clone())
+            .or_default()
+            .push(edge.clone());
+        self.by_parent
+            .entry(edge.parent_id.clone())
+            .or_default()
+            .push(edge);
+    }
+
+    fn push(&mut self, edge: CompositionEdge) -> anyhow::Result<()> {
+        self.store.append(LOG, &edge)?;
+        self.insert(edge);
+        Ok(())
+    }
+
+    /// Parts on `parent_id` after block `block_height`, or now when it's `None`.
+    pub fn parts(&self, parent_id: &str, block_height: Option<u64>) -> BTreeSet<String> {
+        let mut parts = BTreeSet::new();
+        let edges = self.by_parent.get(parent_id).into_iter().flatten();
+        for edge in edges.take_while(|e| block_height.map_or(true, |h| e.block_height <= h)) {
+            if edge.attached {
+                parts.insert(edge.part_id.clone());
+            } else {
+                parts.remove(&edge.part_id);
+            }
+        }
+
+        parts
+    }
+
+    /// Token `part_id` is equipped on after block `block_height`, or now when it's `None`.
+    pub fn parent(&self, part_id: &str, block_height: Option<u64>) -> Option<&str> {
+        self.by_part
+            .get(part_id)?
+            .iter()
+            .take_while(|e| block_height.map_or(true, |h| e.block_height <= h))
+            .last()
+            .filter(|edge| edge.attached)
+            .map(|edge| edge.parent_id.as_str())
+    }
+
+    /// Records the edges that turn the current parts of `parent_id` into `parts`. A part
+    /// attached to another parent is detached from it first. Updates older than the last one
+    /// recorded for `parent_id`, e.g. from a replayed block, are ignored.
+    pub fn set_parts(
+        &mut self,
+        parent_id: &str,
+        parts: BTreeSet<String>,
+        receipt_id: &str,
+        block_height: u64,
+    ) -> anyhow::Result<Vec<CompositionEdge>> {
+        let last_height = self
+            .by_parent
+            .get(parent_id)
+            .and_then(|edges| edges.last())
+            .map(|edge| edge.block_height);
+        if let Some(last_height) = last_height.filter(|&h| h > block_height) {
+            tracing::warn!(
+                "Ignoring parts of `{parent_id}` at block {block_height}, already recorded at \
+                block {last_height}"
+            );
+            return Ok(Vec::new());
+        }
+
+        let current = self.parts(parent_id, None);
+        let edge = |parent_id: &str, part_id: &str, attached| CompositionEdge {
+            parent_id: parent_id.to_owned(),
+            part_id: part_id.to_owned(),
+            attached,
+            receipt_id: receipt_id.to_owned(),
+            block_height,
+        };
+
+        let mut edges = Vec::new();
+        for part_id in current.difference(&parts) {
+            edges.push(edge(parent_id, part_id, false));
+        }
+        for part_id in parts.difference(&current) {
+            if let Some(previous) = self.parent(part_id, None) {
+                edges.push(edge(previous, part_id, false));
+            }
+            edges.push(edge(parent_id, part_id, true));
+        }
+
+        for edge in &edges {
+            self.push(edge.clone())?;
+        }
+
+        Ok(edges)
+    }
+}
+
+/// Parts equipped on a token are the objects nested in its `model` that carry a `token_id`.
+pub fn equipped_parts(model: &Value) -> BTreeSet<String> {
+    let mut parts = BTreeSet::new();
+    let mut pending = match model {
+        Value::Object(fields) => fields.values().collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Object(fields) => match fields.get("token_id").and_then(Value::as_str) {
+                Some(token_id) => {
+                    parts.insert(token_id.to_owned());
+                }
+                None => pending.extend(fields.values()),
+            },
+            Value::Array(values) => pending.extend(values),
+            _ => {}
+        }
+    }
+
+    parts
+}
+
+#[tracing::instrument(name = "Getting composition graph")]
+pub async fn get_composition() -> &'static Mutex<CompositionGraph> {
+    COMPOSITION
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let graph = CompositionGraph::load(store).expect("Couldn't load composition graph");
+            Mutex::new(graph)
+        })
+        .await
+}
+
+/// Derives what got attached and detached from the tokens an event produced and sends the
+/// edges to the rest service.
+#[tracing::instrument(name = "Updating composition graph", skip(change, client, sink))]
+pub async fn observe_nft_change(
+    change: &NftChange,
+    receipt_id: &str,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let edges = {
+        let mut graph = get_composition()
+            .await
+            .lock()
+            .expect("Composition lock poisoned");
+        let mut edges = Vec::new();
+        for token in change.tokens() {
+            let model = serde_json::to_value(&token.model)?;
+            let parts = equipped_parts(&model);
+            edges.extend(graph.set_parts(&token.token_id, parts, receipt_id, block.height)?);
+        }
+        edges
+    };
+
+    if edges.is_empty() {
+        return Ok(());
+    }
+
+    let response = client
+        .post(format!("{}/nft_compositions", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&edges)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn graph(name: &str) -> CompositionGraph {
+        let dir = testing::state_dir(&format!("composition_{name}"));
+        CompositionGraph::load(JsonStore::new(dir)).unwrap()
+    }
+
+    fn parts(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn moves_part_between_parents() {
+        let mut graph = graph("move");
+        graph
+            .set_parts("token-1", parts(&["part-1"]), "r1", 10)
+            .unwrap();
+        graph
+            .set_parts("token-2", parts(&["part-1"]), "r2", 11)
+            .unwrap();
+
+        assert!(graph.parts("token-1", None).is_empty());
+        assert_eq!(graph.parts("token-1", Some(10)), parts(&["part-1"]));
+        assert_eq!(graph.parent("part-1", None), Some("token-2"));
+    }
+
+    #[test]
+    fn ignores_updates_older_than_the_last_one() {
+        let mut graph = graph("stale");
+        graph
+            .set_parts("token-1", parts(&["part-1"]), "r1", 20)
+            .unwrap();
+
+        let edges = graph.set_parts("token-1", parts(&[]), "r0", 19).unwrap();
+
+        assert!(edges.is_empty());
+        assert_eq!(graph.parts("token-1", None), parts(&["part-1"]));
+        assert_eq!(
+            graph
+                .set_parts("token-1", parts(&[]), "r2", 20)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -1,12 +1,27 @@
+use crate::analytics::AnalyticsConfig;
+use crate::api::ApiConfig;
+use crate::checkpoint;
 use crate::consts::CONFIG;
-use anyhow::Context;
+use crate::contract_metadata::ContractMetadataConfig;
+use crate::discovery::FactoryConfig;
+use crate::events::rules::{self, EventRules};
+use crate::ipfs::IpfsConfig;
+use crate::reconcile::ReconciliationConfig;
+use crate::routing::RouteConfig;
+use crate::rpc::RpcClient;
+use crate::store::JsonStore;
+use crate::upgrades::ContractVersionConfig;
+use anyhow::{anyhow, Context};
 use aws_sdk_s3::Region;
 use battlemon_near_json_rpc_client_wrapper::AccountId;
 use battlemon_near_json_rpc_client_wrapper::JsonRpcWrapper;
+use chrono::{DateTime, NaiveDate, Utc};
 use near_crypto::{InMemorySigner, SecretKey};
 use near_lake_framework::{LakeConfig, LakeConfigBuilder};
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(serde::Deserialize)]
@@ -14,6 +29,124 @@ pub struct AppConfig {
     pub contracts: battlemon_models::config::ContractConfig,
     pub rest: RestConfig,
     pub near_lake: NearLakeConfig,
+    #[serde(default)]
+    pub block_source: BlockSourceConfig,
+    /// Accepted event standards and versions, keyed by contract account id.
+    #[serde(default)]
+    pub event_rules: HashMap<String, EventRules>,
+    /// Contracts to index besides the built-in NFT and market ones, or overrides for them.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Handlers and event rules per deployed version of a contract.
+    #[serde(default)]
+    pub contract_versions: Vec<ContractVersionConfig>,
+    /// Parent accounts whose newly deployed sub-account contracts are indexed automatically.
+    #[serde(default)]
+    pub factories: Vec<FactoryConfig>,
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Blocks between snapshots of the trait, search, transaction and analytics state, updates
+    /// in between are logged.
+    #[serde(default = "default_snapshot_interval_blocks")]
+    pub snapshot_interval_blocks: u64,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub analytics: AnalyticsConfig,
+    #[serde(default)]
+    pub ipfs: IpfsConfig,
+    #[serde(default)]
+    pub reconciliation: ReconciliationConfig,
+    #[serde(default)]
+    pub contract_metadata: ContractMetadataConfig,
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("state")
+}
+
+fn default_snapshot_interval_blocks() -> u64 {
+    1000
+}
+
+impl AppConfig {
+    pub fn store(&self) -> JsonStore {
+        JsonStore::new(&self.state_dir)
+    }
+
+    /// Starts from the block configured in `near_lake`, which overrides the checkpoint so the
+    /// indexer can be pointed back to reindex. Without one it resumes after the checkpoint.
+    #[tracing::instrument(name = "Resolving indexer start block height", skip(self))]
+    pub async fn start_block_height(&self) -> anyhow::Result<u64> {
+        let checkpoint = checkpoint::load(&self.store())?;
+        match self.near_lake.start_block_height().await? {
+            Some(block_height) => {
+                if let Some(checkpoint) = checkpoint {
+                    tracing::warn!(
+                        "Starting from configured block height {block_height}, \
+                        ignoring the checkpoint at block height {checkpoint}"
+                    );
+                }
+                Ok(block_height)
+            }
+            None => {
+                let block_height = checkpoint.ok_or_else(|| {
+                    anyhow!(
+                        "There is no checkpoint to resume from, set `start_block_height`, \
+                        `start_block_timestamp` or `start_from_last_block`"
+                    )
+                })?;
+                tracing::info!("Resuming after checkpoint at block height {block_height}");
+                Ok(block_height + 1)
+            }
+        }
+    }
+
+    pub fn event_rules(&self, account_id: &str) -> &EventRules {
+        self.event_rules
+            .get(account_id)
+            .unwrap_or(&rules::ACCEPT_ALL)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BlockSourceConfig {
+    #[default]
+    Lake,
+    /// Polls blocks from `near_lake.rpc_url`, for networks without a lake bucket.
+    Rpc(RpcSourceConfig),
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RpcSourceConfig {
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// How many blocks to stay behind the final block, so that receipts spawned by a
+    /// block's transactions have been executed before the block is assembled.
+    #[serde(default = "default_finality_lag")]
+    pub finality_lag: u64,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    /// Transaction statuses of a chunk fetched at the same time.
+    #[serde(default = "default_tx_status_concurrency")]
+    pub tx_status_concurrency: usize,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_finality_lag() -> u64 {
+    5
+}
+
+fn default_buffer_size() -> usize {
+    100
+}
+
+fn default_tx_status_concurrency() -> usize {
+    8
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -41,15 +174,23 @@ impl NearNetworkKind {
 #[derive(serde::Deserialize, Clone)]
 pub struct NearLakeConfig {
     pub network: NearNetworkKind,
-    pub start_block_height: u64,
+    /// Leave unset to resume after the checkpoint.
+    #[serde(default)]
+    pub start_block_height: Option<u64>,
+    /// RFC 3339 timestamp or `YYYY-MM-DD` date, resolved to the first block at or after it.
+    #[serde(default)]
+    pub start_block_timestamp: Option<String>,
+    #[serde(default)]
     pub start_from_last_block: bool,
+    #[serde(default)]
+    rpc_url: Option<String>,
     aws_access_key_id: Secret<String>,
     aws_secret_access_key: Secret<String>,
     near_credentials: NearCredentialsConfig,
 }
 
 impl NearLakeConfig {
-    pub async fn near_lake_config(&self) -> anyhow::Result<LakeConfig> {
+    pub fn near_lake_config(&self, start_block_height: u64) -> anyhow::Result<LakeConfig> {
         let aws_creds = near_lake_framework::Credentials::new(
             self.aws_access_key_id.expose_secret(),
             self.aws_secret_access_key.expose_secret(),
@@ -62,28 +203,69 @@ impl NearLakeConfig {
             .region(Region::new("eu-central-1"))
             .build();
         let ret = LakeConfigBuilder::default().s3_config(s3_config);
-        let block_height = if self.start_from_last_block {
-            let secret_key =
-                SecretKey::from_str(self.near_credentials.private_key.expose_secret()).unwrap();
-            let signer = InMemorySigner::from_secret_key(
-                self.near_credentials.account_id.clone(),
-                secret_key,
-            );
-            let rpc_client = JsonRpcWrapper::connect(self.network.rpc_url(), signer);
-            rpc_client.final_block_height().await?
-        } else {
-            self.start_block_height
-        };
 
         let ret = match self.network {
             NearNetworkKind::Mainnet => ret.mainnet(),
             NearNetworkKind::Testnet => ret.testnet(),
         }
-        .start_block_height(block_height)
+        .start_block_height(start_block_height)
         .build()?;
 
         Ok(ret)
     }
+
+    pub fn rpc_url(&self) -> &str {
+        self.rpc_url
+            .as_deref()
+            .unwrap_or_else(|| self.network.rpc_url())
+    }
+
+    /// Client signing with `near_credentials`, reading the final state of the chain.
+    pub fn json_rpc_client(&self) -> JsonRpcWrapper {
+        let secret_key =
+            SecretKey::from_str(self.near_credentials.private_key.expose_secret()).unwrap();
+        let signer =
+            InMemorySigner::from_secret_key(self.near_credentials.account_id.clone(), secret_key);
+
+        JsonRpcWrapper::connect(self.rpc_url(), signer)
+    }
+
+    /// Block configured to start from: the final one with `start_from_last_block`, then the one
+    /// at `start_block_timestamp`, then `start_block_height`.
+    #[tracing::instrument(name = "Resolving start block height", skip(self))]
+    pub async fn start_block_height(&self) -> anyhow::Result<Option<u64>> {
+        if self.start_from_last_block {
+            let rpc_client = self.json_rpc_client();
+            return Ok(Some(rpc_client.final_block_height().await?));
+        }
+
+        if let Some(timestamp) = &self.start_block_timestamp {
+            let timestamp = parse_start_timestamp(timestamp)?;
+            let timestamp_nanosec = u64::try_from(timestamp.timestamp_nanos())
+                .context("Start timestamp is before the Unix epoch")?;
+            let block_height = RpcClient::new(self.rpc_url())
+                .first_block_at_or_after(timestamp_nanosec)
+                .await
+                .with_context(|| format!("Failed to resolve block height for {timestamp}"))?;
+            tracing::info!("Resolved start timestamp {timestamp} to block height {block_height}");
+
+            return Ok(Some(block_height));
+        }
+
+        Ok(self.start_block_height)
+    }
+}
+
+fn parse_start_timestamp(value: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        anyhow!("Start timestamp `{value}` is neither an RFC 3339 timestamp nor a YYYY-MM-DD date")
+    })?;
+
+    Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
 }
 
 #[derive(Deserialize, Clone)]
@@ -131,3 +313,35 @@ pub async fn get_config() -> &'static AppConfig {
         .get_or_init(|| async { load_config().expect("Couldn't load config for indexer") })
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn config(name: &str, start_block_height: Option<u64>) -> AppConfig {
+        testing::app_config(
+            &format!("config_{name}"),
+            json!({ "near_lake": { "start_block_height": start_block_height } }),
+        )
+    }
+
+    #[tokio::test]
+    async fn configured_start_overrides_the_checkpoint() {
+        let config = config("configured", Some(100));
+        checkpoint::save(&config.store(), 500).unwrap();
+
+        assert_eq!(config.start_block_height().await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_checkpoint_without_a_configured_start() {
+        let config = config("resumed", None);
+        assert!(config.start_block_height().await.is_err());
+
+        checkpoint::save(&config.store(), 500).unwrap();
+
+        assert_eq!(config.start_block_height().await.unwrap(), 501);
+    }
+}
@@ -1,6 +1,63 @@
+use crate::activity::ActivityFeed;
+use crate::analytics::Analytics;
+use crate::composition::CompositionGraph;
 use crate::config::AppConfig;
-use tokio::sync::OnceCell;
+use crate::contract_metadata::ContractMetadataForRest;
+use crate::deposits::DepositTracker;
+use crate::events::UnknownEventCounts;
+use crate::fungible::FtLedger;
+use crate::ipfs::MetadataJob;
+use crate::listings::ListingBook;
+use crate::provenance::ProvenanceLog;
+use crate::routing::Router;
+use crate::search::SearchIndex;
+use crate::trades::TradeCorrelator;
+use crate::traits::TraitIndex;
+use crate::transactions::TransactionIndex;
+use crate::upgrades::DeploymentHistory;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use tokio::sync::{mpsc, OnceCell, RwLock};
 
 pub const EVENT_PREFIX: &str = "EVENT_JSON:";
 
 pub static CONFIG: OnceCell<AppConfig> = OnceCell::const_new();
+
+pub static ROUTER: OnceCell<RwLock<Router>> = OnceCell::const_new();
+
+pub static TRADES: Lazy<Mutex<TradeCorrelator>> = Lazy::new(Default::default);
+
+pub static TRANSACTIONS: Lazy<Mutex<TransactionIndex>> = Lazy::new(Default::default);
+
+pub static DEPOSITS: Lazy<Mutex<DepositTracker>> = Lazy::new(Default::default);
+
+pub static UNKNOWN_EVENT_COUNTS: Lazy<Mutex<UnknownEventCounts>> = Lazy::new(Default::default);
+
+pub static LISTINGS: OnceCell<Mutex<ListingBook>> = OnceCell::const_new();
+
+pub static ANALYTICS: OnceCell<Mutex<Analytics>> = OnceCell::const_new();
+
+pub static PROVENANCE: OnceCell<Mutex<ProvenanceLog>> = OnceCell::const_new();
+
+pub static COMPOSITION: OnceCell<Mutex<CompositionGraph>> = OnceCell::const_new();
+
+pub static IPFS: OnceCell<mpsc::Sender<MetadataJob>> = OnceCell::const_new();
+
+pub static TRAITS: OnceCell<Mutex<TraitIndex>> = OnceCell::const_new();
+
+pub static SEARCH: OnceCell<Mutex<SearchIndex>> = OnceCell::const_new();
+
+pub static ACTIVITY: OnceCell<Mutex<ActivityFeed>> = OnceCell::const_new();
+
+pub static CONTRACT_METADATA: OnceCell<Mutex<HashMap<String, ContractMetadataForRest>>> =
+    OnceCell::const_new();
+
+pub static DEPLOYMENTS: OnceCell<Mutex<DeploymentHistory>> = OnceCell::const_new();
+
+pub static FT_LEDGER: OnceCell<Mutex<FtLedger>> = OnceCell::const_new();
+
+pub static RECONCILING: AtomicBool = AtomicBool::new(false);
+
+pub static LAST_RECONCILED: OnceCell<Mutex<Option<u64>>> = OnceCell::const_new();
@@ -0,0 +1,386 @@
+use crate::config::{get_config, AppConfig, RestConfig};
+use crate::consts::CONTRACT_METADATA;
+use crate::events;
+use crate::routing;
+use crate::rpc::{BlockRef, RpcClient};
+use crate::{ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+use anyhow::Context;
+use near_lake_framework::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const STATE: &str = "contract_metadata";
+
+#[derive(Deserialize, Clone)]
+pub struct ContractMetadataConfig {
+    /// Market view method returning its configuration, e.g. fees and accepted tokens. The
+    /// market's metadata isn't read when it's not set.
+    #[serde(default)]
+    pub market_config_method: Option<String>,
+    /// Calls to the NFT or market contract after which their metadata is fetched again.
+    #[serde(default = "default_refresh_methods")]
+    pub refresh_methods: Vec<String>,
+}
+
+impl Default for ContractMetadataConfig {
+    fn default() -> Self {
+        Self {
+            market_config_method: None,
+            refresh_methods: default_refresh_methods(),
+        }
+    }
+}
+
+fn default_refresh_methods() -> Vec<String> {
+    ["new", "migrate", "set_metadata", "update_config"]
+        .iter()
+        .map(|method| method.to_string())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractKind {
+    Nft,
+    Market,
+}
+
+/// NEP-177 contract metadata, as `nft_metadata` returns it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NftContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContractMetadataForRest {
+    pub contract_id: String,
+    pub kind: ContractKind,
+    /// `nft_metadata` of the NFT contract, the configuration view of the market.
+    pub metadata: Value,
+    /// Block the metadata was read at.
+    pub block_height: u64,
+}
+
+#[tracing::instrument(name = "Getting contract metadata")]
+pub async fn get_contract_metadata() -> &'static Mutex<HashMap<String, ContractMetadataForRest>> {
+    CONTRACT_METADATA
+        .get_or_init(|| async {
+            let metadata = get_config()
+                .await
+                .store()
+                .load(STATE)
+                .expect("Couldn't load contract metadata");
+            Mutex::new(metadata)
+        })
+        .await
+}
+
+/// NFT contract, and the market when there's a view to read its configuration with.
+fn watched_contracts(config: &AppConfig) -> Vec<(&str, ContractKind)> {
+    let (_, nft, market) = config.contracts.ids();
+    let mut contracts = vec![(nft.as_ref(), ContractKind::Nft)];
+    if config.contract_metadata.market_config_method.is_some() {
+        contracts.push((market.as_ref(), ContractKind::Market));
+    }
+
+    contracts
+}
+
+fn watched_contract(config: &AppConfig, account_id: &str) -> Option<ContractKind> {
+    watched_contracts(config)
+        .into_iter()
+        .find(|(contract_id, _)| *contract_id == account_id)
+        .map(|(_, kind)| kind)
+}
+
+#[tracing::instrument(name = "Fetching contract metadata", skip(rpc_client))]
+async fn fetch(
+    rpc_client: &RpcClient,
+    contract_id: &str,
+    kind: ContractKind,
+    block: BlockRef,
+) -> anyhow::Result<Value> {
+    match kind {
+        ContractKind::Nft => {
+            let metadata: NftContractMetadata = rpc_client
+                .view_function(contract_id, "nft_metadata", &json!({}), block)
+                .await?;
+            Ok(serde_json::to_value(metadata)?)
+        }
+        ContractKind::Market => {
+            let method = get_config()
+                .await
+                .contract_metadata
+                .market_config_method
+                .as_deref()
+                .context("`market_config_method` isn't set")?;
+            rpc_client
+                .view_function(contract_id, method, &json!({}), block)
+                .await
+        }
+    }
+}
+
+/// Reads the metadata of the contract at `block_height`, keeps it and sends it to the rest
+/// service.
+async fn refresh(
+    contract_id: &str,
+    kind: ContractKind,
+    block_height: u64,
+    client: &reqwest::Client,
+) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+    let block = BlockRef::Height(block_height);
+    let metadata = ContractMetadataForRest {
+        contract_id: contract_id.to_owned(),
+        kind,
+        metadata: fetch(&rpc_client, contract_id, kind, block).await?,
+        block_height,
+    };
+
+    let state = {
+        let mut state = get_contract_metadata()
+            .await
+            .lock()
+            .expect("Contract metadata lock poisoned");
+        state.insert(contract_id.to_owned(), metadata.clone());
+        state.clone()
+    };
+    config.store().save(STATE, &state)?;
+    let sink = routing::route_sink(contract_id).await;
+    store_metadata(&metadata, client, &sink).await
+}
+
+/// Fetches the metadata of the NFT and market contracts at the final block, on startup. A
+/// contract whose metadata can't be read keeps what was read before.
+#[tracing::instrument(name = "Refreshing metadata of contracts", skip(config, client))]
+pub async fn refresh_all(config: &AppConfig, client: &reqwest::Client) {
+    let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+    let block_height = match rpc_client.final_block().await {
+        Ok(block) => block.header.height,
+        Err(e) => {
+            tracing::error!(
+                "Failed to get the final block to read contract metadata at: {:?}",
+                e
+            );
+            return;
+        }
+    };
+
+    for (contract_id, kind) in watched_contracts(config) {
+        if let Err(e) = refresh(contract_id, kind, block_height, client).await {
+            tracing::error!("Failed to refresh metadata of `{contract_id}`: {:?}", e);
+        }
+    }
+}
+
+/// Fetches the metadata of the NFT or market contract again when the receipt deployed new
+/// code to it or called one of `refresh_methods`. Failing to read it is logged, the metadata
+/// read before is kept then.
+#[tracing::instrument(
+    name = "Checking receipt for contract metadata changes",
+    skip(outcome, client),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn observe_receipt(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block_height: u64,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let contract_id = outcome.receipt.receiver_id.as_ref();
+    let kind = match watched_contract(config, contract_id) {
+        Some(kind) => kind,
+        None => return Ok(()),
+    };
+    if !changes_metadata(outcome, &config.contract_metadata.refresh_methods) {
+        return Ok(());
+    }
+    let outdated = is_outdated(
+        &get_contract_metadata()
+            .await
+            .lock()
+            .expect("Contract metadata lock poisoned"),
+        contract_id,
+        block_height,
+    );
+    if outdated {
+        return Ok(());
+    }
+
+    tracing::info!("Metadata of `{contract_id}` may have changed at block {block_height}");
+    if let Err(e) = refresh(contract_id, kind, block_height, &client).await {
+        tracing::error!("Failed to refresh metadata of `{contract_id}`: {:?}", e);
+    }
+
+    Ok(())
+}
+
+/// Whether the receipt succeeded in deploying code or calling one of `refresh_methods`.
+fn changes_metadata(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    refresh_methods: &[String],
+) -> bool {
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return false,
+    };
+    let succeeded = matches!(
+        outcome.execution_outcome.outcome.status,
+        ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+    );
+
+    succeeded
+        && actions.iter().any(|action| match action {
+            ActionView::DeployContract { .. } => true,
+            ActionView::FunctionCall { method_name, .. } => refresh_methods.contains(method_name),
+            _ => false,
+        })
+}
+
+/// Replayed blocks must not replace metadata read at a later block.
+fn is_outdated(
+    state: &HashMap<String, ContractMetadataForRest>,
+    contract_id: &str,
+    block_height: u64,
+) -> bool {
+    state
+        .get(contract_id)
+        .map_or(false, |metadata| metadata.block_height >= block_height)
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store contract metadata",
+    skip(metadata, client, sink)
+)]
+async fn store_metadata(
+    metadata: &ContractMetadataForRest,
+    client: &reqwest::Client,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/contract_metadata", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(metadata)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_lake_framework::near_indexer_primitives::CryptoHash;
+
+    fn outcome(actions: Value, status: Value) -> IndexerExecutionOutcomeWithReceipt {
+        let receipt_id = CryptoHash::hash_bytes(b"receipt");
+        serde_json::from_value(json!({
+            "execution_outcome": {
+                "proof": [],
+                "block_hash": CryptoHash::hash_bytes(b"block"),
+                "id": receipt_id,
+                "outcome": {
+                    "logs": [],
+                    "receipt_ids": [],
+                    "gas_burnt": 0,
+                    "tokens_burnt": "0",
+                    "executor_id": "nft.test",
+                    "status": status,
+                    "metadata": { "version": 1, "gas_profile": null },
+                },
+            },
+            "receipt": {
+                "predecessor_id": "owner.test",
+                "receiver_id": "nft.test",
+                "receipt_id": receipt_id,
+                "receipt": {
+                    "Action": {
+                        "signer_id": "owner.test",
+                        "signer_public_key": "ed25519:11111111111111111111111111111111",
+                        "gas_price": "100000000",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": actions,
+                    },
+                },
+            },
+        }))
+        .expect("Test outcome is invalid")
+    }
+
+    fn function_call(method_name: &str) -> Value {
+        json!({
+            "FunctionCall": {
+                "method_name": method_name,
+                "args": "",
+                "gas": 30_000_000_000_000u64,
+                "deposit": "0",
+            }
+        })
+    }
+
+    fn succeeded() -> Value {
+        json!({ "SuccessValue": "" })
+    }
+
+    #[test]
+    fn refreshes_after_deploys_and_refresh_methods() {
+        let methods = ContractMetadataConfig::default().refresh_methods;
+        let deploy = json!({ "DeployContract": { "code": base64::encode([1u8; 32]) } });
+
+        assert!(changes_metadata(
+            &outcome(json!([deploy]), succeeded()),
+            &methods
+        ));
+        assert!(changes_metadata(
+            &outcome(json!([function_call("set_metadata")]), succeeded()),
+            &methods
+        ));
+        assert!(!changes_metadata(
+            &outcome(json!([function_call("nft_transfer")]), succeeded()),
+            &methods
+        ));
+    }
+
+    #[test]
+    fn failed_receipts_change_nothing() {
+        let methods = ContractMetadataConfig::default().refresh_methods;
+        let failed = json!({ "Failure": { "InvalidTxError": "InvalidSignature" } });
+
+        assert!(!changes_metadata(
+            &outcome(json!([function_call("set_metadata")]), failed),
+            &methods
+        ));
+    }
+
+    #[test]
+    fn skips_blocks_before_the_metadata_was_read() {
+        let mut state = HashMap::new();
+        state.insert(
+            "nft.test".to_owned(),
+            ContractMetadataForRest {
+                contract_id: "nft.test".to_owned(),
+                kind: ContractKind::Nft,
+                metadata: json!({}),
+                block_height: 100,
+            },
+        );
+
+        assert!(is_outdated(&state, "nft.test", 90));
+        assert!(is_outdated(&state, "nft.test", 100));
+        assert!(!is_outdated(&state, "nft.test", 101));
+        assert!(!is_outdated(&state, "market.test", 90));
+    }
+}
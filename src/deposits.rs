@@ -0,0 +1,717 @@
+use crate::config::RestConfig;
+use crate::consts::DEPOSITS;
+use crate::events;
+use crate::listings::{ListingKey, ListingKind};
+use crate::models::{BlockContext, U128};
+use crate::provenance::ProvenanceKind;
+use crate::routing::{self, get_router, HandlerKind};
+use crate::store::JsonStore;
+use crate::{transactions, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+use near_lake_framework::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+const STATE: &str = "deposits";
+
+/// How long a deposit waits for its refunds after the transaction was last seen.
+const PENDING_TTL_BLOCKS: u64 = 200;
+
+/// Predecessor of the receipts returning the deposit of a failed call.
+const SYSTEM_ACCOUNT_ID: &str = "system";
+
+/// Bid, ask or sale the receipt carrying a deposit produced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DepositRecord {
+    pub kind: ProvenanceKind,
+    pub token_id: String,
+    /// Rest service collection the record is stored in: `asks`, `bids` or `sales`.
+    pub path: String,
+    /// The record as it was stored, sent again when its deposit is refunded.
+    pub record: Value,
+}
+
+/// Market record stored together with the deposit of the call that produced it.
+#[derive(Serialize, Debug, Clone)]
+pub struct DepositedForRest<T> {
+    #[serde(flatten)]
+    pub record: T,
+    pub deposit: Option<U128>,
+}
+
+/// Market record whose deposit was refunded, in full or in part.
+#[derive(Serialize, Debug, Clone)]
+pub struct RefundedForRest {
+    #[serde(flatten)]
+    pub record: Value,
+    pub deposit: U128,
+    /// Total refunded so far, including this refund.
+    pub refunded: U128,
+    pub refund_kind: RefundKind,
+    pub refund_receipt_id: String,
+    pub block_height: u64,
+}
+
+/// Deposit attached to a call of the market contract, in yoctoNEAR.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketDepositForRest {
+    pub market_contract_id: String,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub account_id: String,
+    pub method_name: String,
+    pub deposit: U128,
+    /// A failed call gets its deposit back from the system.
+    pub succeeded: bool,
+    pub records: Vec<DepositRecord>,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundKind {
+    /// The call failed and the protocol returned its deposit.
+    FailedCall,
+    /// The market sent funds back to the depositor, e.g. an excess or an escrow.
+    Contract,
+}
+
+/// Transfer back to the account that made a deposit in the same transaction.
+#[derive(Serialize, Debug, Clone)]
+pub struct MarketRefundForRest {
+    pub market_contract_id: String,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    /// Receipt carrying the deposit being refunded.
+    pub deposit_receipt_id: String,
+    pub account_id: String,
+    pub kind: RefundKind,
+    pub amount: U128,
+    pub records: Vec<DepositRecord>,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingDeposit {
+    deposit: MarketDepositForRest,
+    /// Refunded so far, never more than the deposit.
+    refunded: U128,
+    seen_at: u64,
+    /// Ask or bid the deposit is held in escrow for, while it's open.
+    #[serde(default)]
+    escrow: Option<ListingKey>,
+    /// Transaction that closed the listing the deposit was held for, whose refunds return it.
+    #[serde(default)]
+    closed_in: Option<String>,
+}
+
+impl PendingDeposit {
+    fn remaining(&self) -> u128 {
+        self.deposit.deposit.0 - self.refunded.0
+    }
+}
+
+/// Deposits waiting for the refunds they cause: within their own transaction, or for escrowed
+/// deposits, within the transaction closing their listing. Saved to `deposits.json` in the state
+/// dir, so refunds arriving after a restart still find them.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<PendingDeposit>", into = "Vec<PendingDeposit>")]
+pub struct DepositTracker {
+    /// Keyed by the receipt carrying the deposit.
+    deposits: HashMap<String, PendingDeposit>,
+    /// Transaction to the deposits its refunds can return.
+    transactions: HashMap<String, Vec<String>>,
+    /// Open listing to the deposit held for it.
+    escrows: HashMap<ListingKey, String>,
+    dirty: bool,
+}
+
+impl From<Vec<PendingDeposit>> for DepositTracker {
+    fn from(deposits: Vec<PendingDeposit>) -> Self {
+        let mut tracker = Self::default();
+        for pending in deposits {
+            tracker.insert(pending);
+        }
+
+        tracker
+    }
+}
+
+impl From<DepositTracker> for Vec<PendingDeposit> {
+    fn from(tracker: DepositTracker) -> Self {
+        tracker.deposits.into_values().collect()
+    }
+}
+
+impl DepositTracker {
+    fn insert(&mut self, pending: PendingDeposit) {
+        let receipt_id = pending.deposit.receipt_id.clone();
+        let transactions = pending
+            .deposit
+            .transaction_hash
+            .iter()
+            .chain(pending.closed_in.iter());
+        for transaction_hash in transactions {
+            self.transactions
+                .entry(transaction_hash.clone())
+                .or_default()
+                .push(receipt_id.clone());
+        }
+        if let Some(escrow) = &pending.escrow {
+            self.escrows.insert(escrow.clone(), receipt_id.clone());
+        }
+        self.deposits.insert(receipt_id, pending);
+    }
+
+    /// Tracks a deposit until the refunds of its transaction can't arrive anymore, or when it's
+    /// held in `escrow` for a listing, until the listing closes. A listing added again closes
+    /// the previous one.
+    pub fn add(&mut self, deposit: MarketDepositForRest, escrow: Option<ListingKey>) {
+        if deposit.transaction_hash.is_none() && escrow.is_none() {
+            return;
+        }
+        if let Some(key) = &escrow {
+            self.close(
+                key,
+                deposit.transaction_hash.as_deref(),
+                deposit.block_height,
+            );
+        }
+        self.dirty = true;
+        self.insert(PendingDeposit {
+            seen_at: deposit.block_height,
+            deposit,
+            refunded: U128(0),
+            escrow,
+            closed_in: None,
+        });
+    }
+
+    /// Releases the deposit held for the listing, to be matched with the refunds of the
+    /// transaction that closed it.
+    pub fn close(&mut self, key: &ListingKey, transaction_hash: Option<&str>, block_height: u64) {
+        let receipt_id = match self.escrows.remove(key) {
+            Some(receipt_id) => receipt_id,
+            None => return,
+        };
+        let pending = match self.deposits.get_mut(&receipt_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        self.dirty = true;
+        pending.escrow = None;
+        pending.seen_at = block_height;
+        if let Some(transaction_hash) = transaction_hash {
+            pending.closed_in = Some(transaction_hash.to_owned());
+            self.transactions
+                .entry(transaction_hash.to_owned())
+                .or_default()
+                .push(receipt_id);
+        }
+    }
+
+    /// Deposit of `account_id` that a transfer of `amount` to it refunds, with the total
+    /// refunded from it so far. Deposits of the transaction come first. A system transfer only
+    /// refunds the full deposit of a failed call, the other ones being gas refunds. The market
+    /// can't send back more than what's left of a deposit, and returns an escrow still held for
+    /// an open listing, e.g. an outbid one, only in full.
+    fn refunded_deposit(
+        &mut self,
+        transaction_hash: Option<&str>,
+        market_contract_id: &str,
+        account_id: &str,
+        amount: u128,
+        kind: RefundKind,
+        block_height: u64,
+    ) -> Option<(MarketDepositForRest, U128)> {
+        let deposits = &self.deposits;
+        let in_transaction = transaction_hash
+            .and_then(|transaction_hash| self.transactions.get(transaction_hash))
+            .into_iter()
+            .flatten()
+            .find(|receipt_id| {
+                deposits.get(*receipt_id).map_or(false, |p| {
+                    p.deposit.account_id == account_id
+                        && match kind {
+                            RefundKind::FailedCall => {
+                                !p.deposit.succeeded && p.remaining() == amount
+                            }
+                            RefundKind::Contract => {
+                                p.deposit.market_contract_id == market_contract_id
+                                    && amount <= p.remaining()
+                            }
+                        }
+                })
+            });
+        let escrowed = || {
+            self.escrows
+                .values()
+                .filter_map(|receipt_id| deposits.get(receipt_id))
+                .filter(|p| {
+                    p.deposit.market_contract_id == market_contract_id
+                        && p.deposit.account_id == account_id
+                        && p.remaining() == amount
+                })
+                .min_by_key(|p| p.deposit.block_height)
+                .map(|p| &p.deposit.receipt_id)
+        };
+        let receipt_id = match (in_transaction, kind) {
+            (Some(receipt_id), _) => receipt_id.clone(),
+            (None, RefundKind::Contract) => escrowed()?.clone(),
+            (None, RefundKind::FailedCall) => return None,
+        };
+
+        let pending = self.deposits.get_mut(&receipt_id)?;
+        pending.seen_at = block_height;
+        pending.refunded.0 += amount;
+        self.dirty = true;
+
+        Some((pending.deposit.clone(), pending.refunded))
+    }
+
+    /// Drops the deposits not held in escrow whose refunds can't arrive anymore.
+    pub fn prune(&mut self, block_height: u64) {
+        let len = self.deposits.len();
+        self.deposits
+            .retain(|_, p| p.escrow.is_some() || p.seen_at + PENDING_TTL_BLOCKS >= block_height);
+        if len == self.deposits.len() {
+            return;
+        }
+
+        self.dirty = true;
+        let deposits = &self.deposits;
+        self.transactions.retain(|_, receipt_ids| {
+            receipt_ids.retain(|receipt_id| deposits.contains_key(receipt_id));
+            !receipt_ids.is_empty()
+        });
+    }
+}
+
+/// Closes the escrow held for a listing that was removed or filled in the transaction of
+/// `outcome`.
+pub fn close_escrow(
+    key: &ListingKey,
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block_height: u64,
+) {
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    DEPOSITS.lock().expect("Deposits lock poisoned").close(
+        key,
+        transaction_hash.as_deref(),
+        block_height,
+    );
+}
+
+/// Listing an ask or bid added by the deposit's receipt is held for.
+fn escrow_key(deposit: &MarketDepositForRest) -> Option<ListingKey> {
+    if !deposit.succeeded {
+        return None;
+    }
+    deposit.records.iter().find_map(|record| {
+        let kind = match record.kind {
+            ProvenanceKind::AskAdded => ListingKind::Ask,
+            ProvenanceKind::BidAdded => ListingKind::Bid,
+            _ => return None,
+        };
+        Some(ListingKey {
+            kind,
+            market_contract_id: deposit.market_contract_id.clone(),
+            token_id: record.token_id.clone(),
+            account_id: deposit.account_id.clone(),
+        })
+    })
+}
+
+/// Replaces the pending deposits with the ones saved before the last shutdown.
+#[tracing::instrument(name = "Restoring pending deposits", skip(store))]
+pub fn restore(store: &JsonStore) -> anyhow::Result<()> {
+    let tracker: DepositTracker = store.load(STATE)?;
+    *DEPOSITS.lock().expect("Deposits lock poisoned") = tracker;
+
+    Ok(())
+}
+
+/// Drops the deposits no refund can arrive for anymore and saves the rest if they changed.
+#[tracing::instrument(name = "Persisting pending deposits", skip(store))]
+pub fn persist(store: &JsonStore, block_height: u64) -> anyhow::Result<()> {
+    let tracker = {
+        let mut tracker = DEPOSITS.lock().expect("Deposits lock poisoned");
+        tracker.prune(block_height);
+        if !tracker.dirty {
+            return Ok(());
+        }
+        tracker.dirty = false;
+        tracker.clone()
+    };
+
+    store.save(STATE, &tracker)
+}
+
+/// Method called by the receipt and the deposit attached to it in yoctoNEAR, if there is one.
+pub fn attached_deposit(outcome: &IndexerExecutionOutcomeWithReceipt) -> Option<(String, U128)> {
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return None,
+    };
+    let mut method_name = None;
+    let mut deposit = 0u128;
+    for action in actions {
+        if let ActionView::FunctionCall {
+            method_name: name,
+            deposit: amount,
+            ..
+        } = action
+        {
+            method_name.get_or_insert_with(|| name.clone());
+            deposit = deposit.saturating_add(*amount);
+        }
+    }
+
+    match method_name {
+        Some(method_name) if deposit > 0 => Some((method_name, U128(deposit))),
+        _ => None,
+    }
+}
+
+/// Records the deposit attached to a market receipt, with the bids, asks and sales it produced.
+#[tracing::instrument(
+    name = "Recording market deposit",
+    skip(outcome, records, client, sink),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn observe_market_receipt(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    records: Vec<DepositRecord>,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let (method_name, deposit) = match attached_deposit(outcome) {
+        Some(attached) => attached,
+        None => return Ok(()),
+    };
+
+    let deposit = MarketDepositForRest {
+        market_contract_id: outcome.receipt.receiver_id.to_string(),
+        receipt_id: outcome.receipt.receipt_id.to_string(),
+        transaction_hash: transactions::transaction_of(&outcome.receipt.receipt_id)
+            .map(|transaction| transaction.transaction_hash),
+        account_id: outcome.receipt.predecessor_id.to_string(),
+        method_name,
+        deposit,
+        succeeded: matches!(
+            outcome.execution_outcome.outcome.status,
+            ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+        ),
+        records,
+        block_height: block.height,
+        timestamp_ms: block.timestamp_ms(),
+    };
+    let escrow = escrow_key(&deposit);
+    DEPOSITS
+        .lock()
+        .expect("Deposits lock poisoned")
+        .add(deposit.clone(), escrow);
+
+    post("market_deposits", &deposit, client, sink).await
+}
+
+/// Records transfers refunding a market deposit: the system returning the deposit of a failed
+/// call, or the market sending funds back to the depositor. The refunded total is also attached
+/// to each bid, ask and sale the deposit was made for.
+#[tracing::instrument(
+    name = "Checking receipt for market refunds",
+    skip(outcome, client),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn observe_refund(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return Ok(()),
+    };
+    let mut amount = 0u128;
+    for action in actions {
+        match action {
+            ActionView::Transfer { deposit } => amount = amount.saturating_add(*deposit),
+            _ => return Ok(()),
+        }
+    }
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let predecessor_id = outcome.receipt.predecessor_id.as_ref();
+    let kind = if predecessor_id == SYSTEM_ACCOUNT_ID {
+        RefundKind::FailedCall
+    } else {
+        let by_market = get_router()
+            .await
+            .read()
+            .await
+            .route(predecessor_id)
+            .map_or(false, |route| route.handler == HandlerKind::Market);
+        if !by_market {
+            return Ok(());
+        }
+        RefundKind::Contract
+    };
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+
+    let account_id = outcome.receipt.receiver_id.as_ref();
+    let refunded = DEPOSITS
+        .lock()
+        .expect("Deposits lock poisoned")
+        .refunded_deposit(
+            transaction_hash.as_deref(),
+            predecessor_id,
+            account_id,
+            amount,
+            kind,
+            block.height,
+        );
+    let (deposit, refunded) = match refunded {
+        Some(refunded) => refunded,
+        None => return Ok(()),
+    };
+
+    let receipt_id = outcome.receipt.receipt_id.to_string();
+    let sink = routing::route_sink(&deposit.market_contract_id).await;
+    for record in &deposit.records {
+        let json = RefundedForRest {
+            record: record.record.clone(),
+            deposit: deposit.deposit,
+            refunded,
+            refund_kind: kind,
+            refund_receipt_id: receipt_id.clone(),
+            block_height: block.height,
+        };
+        post(
+            &format!("{}/refunded", record.path),
+            &json,
+            client.clone(),
+            &sink,
+        )
+        .await?;
+    }
+
+    let refund = MarketRefundForRest {
+        market_contract_id: deposit.market_contract_id,
+        receipt_id,
+        transaction_hash,
+        deposit_receipt_id: deposit.receipt_id,
+        account_id: account_id.to_owned(),
+        kind,
+        amount: U128(amount),
+        records: deposit.records,
+        block_height: block.height,
+        timestamp_ms: block.timestamp_ms(),
+    };
+    tracing::info!(
+        "Refund of {} yoctoNEAR to `{}` for deposit in receipt {}",
+        amount,
+        refund.account_id,
+        refund.deposit_receipt_id
+    );
+
+    post("market_refunds", &refund, client, &sink).await
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store market funds",
+    skip(body, client, sink)
+)]
+async fn post<T: Serialize>(
+    path: &str,
+    body: &T,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/{path}", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(body)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION: &str = "tx-1";
+
+    fn deposit(amount: u128, succeeded: bool) -> MarketDepositForRest {
+        deposit_in("receipt-1", TRANSACTION, amount, succeeded)
+    }
+
+    fn deposit_in(
+        receipt_id: &str,
+        transaction_hash: &str,
+        amount: u128,
+        succeeded: bool,
+    ) -> MarketDepositForRest {
+        MarketDepositForRest {
+            market_contract_id: "market.near".to_owned(),
+            receipt_id: receipt_id.to_owned(),
+            transaction_hash: Some(transaction_hash.to_owned()),
+            account_id: "alice.near".to_owned(),
+            method_name: "add_bid".to_owned(),
+            deposit: U128(amount),
+            succeeded,
+            records: Vec::new(),
+            block_height: 10,
+            timestamp_ms: 0,
+        }
+    }
+
+    fn refund(tracker: &mut DepositTracker, amount: u128, kind: RefundKind) -> Option<U128> {
+        refund_in(tracker, TRANSACTION, amount, kind)
+    }
+
+    fn refund_in(
+        tracker: &mut DepositTracker,
+        transaction_hash: &str,
+        amount: u128,
+        kind: RefundKind,
+    ) -> Option<U128> {
+        tracker
+            .refunded_deposit(
+                Some(transaction_hash),
+                "market.near",
+                "alice.near",
+                amount,
+                kind,
+                11,
+            )
+            .map(|(_, refunded)| refunded)
+    }
+
+    fn bid_key() -> ListingKey {
+        ListingKey {
+            kind: ListingKind::Bid,
+            market_contract_id: "market.near".to_owned(),
+            token_id: "1".to_owned(),
+            account_id: "alice.near".to_owned(),
+        }
+    }
+
+    #[test]
+    fn tracks_amounts_beyond_decimal_range() {
+        let amount = 100_000 * 10u128.pow(24);
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(amount, false), None);
+
+        assert_eq!(
+            refund(&mut tracker, amount, RefundKind::FailedCall),
+            Some(U128(amount))
+        );
+        assert_eq!(
+            serde_json::to_value(U128(amount)).unwrap(),
+            serde_json::json!(amount.to_string())
+        );
+    }
+
+    #[test]
+    fn contract_refunds_never_exceed_the_deposit() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), None);
+
+        assert_eq!(refund(&mut tracker, 101, RefundKind::Contract), None);
+        assert_eq!(
+            refund(&mut tracker, 60, RefundKind::Contract),
+            Some(U128(60))
+        );
+        assert_eq!(refund(&mut tracker, 60, RefundKind::Contract), None);
+        assert_eq!(
+            refund(&mut tracker, 40, RefundKind::Contract),
+            Some(U128(100))
+        );
+    }
+
+    #[test]
+    fn system_refunds_only_return_failed_calls() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), None);
+
+        assert_eq!(refund(&mut tracker, 100, RefundKind::FailedCall), None);
+    }
+
+    #[test]
+    fn pending_deposits_survive_a_restart() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), None);
+        refund(&mut tracker, 30, RefundKind::Contract);
+
+        let json = serde_json::to_string(&tracker).unwrap();
+        let mut restored: DepositTracker = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            refund(&mut restored, 70, RefundKind::Contract),
+            Some(U128(100))
+        );
+    }
+
+    #[test]
+    fn escrow_is_refunded_in_the_transaction_removing_the_listing() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), Some(bid_key()));
+
+        assert_eq!(
+            refund_in(&mut tracker, "tx-2", 50, RefundKind::Contract),
+            None
+        );
+        tracker.close(&bid_key(), Some("tx-2"), 20);
+        tracker.prune(20);
+
+        assert_eq!(
+            refund_in(&mut tracker, "tx-2", 100, RefundKind::Contract),
+            Some(U128(100))
+        );
+    }
+
+    #[test]
+    fn outbid_escrow_is_refunded_in_full_while_the_listing_is_open() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), Some(bid_key()));
+        tracker.prune(10_000);
+
+        assert_eq!(
+            refund_in(&mut tracker, "tx-3", 60, RefundKind::Contract),
+            None
+        );
+        assert_eq!(
+            refund_in(&mut tracker, "tx-3", 100, RefundKind::Contract),
+            Some(U128(100))
+        );
+    }
+
+    #[test]
+    fn adding_the_listing_again_closes_the_previous_escrow() {
+        let mut tracker = DepositTracker::default();
+        tracker.add(deposit(100, true), Some(bid_key()));
+        tracker.add(deposit_in("receipt-2", "tx-2", 200, true), Some(bid_key()));
+
+        assert_eq!(
+            refund_in(&mut tracker, "tx-2", 100, RefundKind::Contract),
+            Some(U128(100))
+        );
+        let json = serde_json::to_string(&tracker).unwrap();
+        let mut restored: DepositTracker = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            refund_in(&mut restored, "tx-4", 200, RefundKind::Contract),
+            Some(U128(200))
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use crate::config::{AppConfig, RestConfig};
+use crate::routing::{get_router, AccountPattern, HandlerKind, Route, Router};
+use crate::{get_config, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use near_lake_framework::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use serde::{Deserialize, Serialize};
+
+const REGISTRY: &str = "discovered_contracts";
+
+/// Parent account whose sub-accounts get indexed as soon as a contract is deployed to them.
+#[derive(Deserialize, Clone)]
+pub struct FactoryConfig {
+    pub parent: String,
+    pub handler: HandlerKind,
+    /// Rest service receiving the events, `rest` when absent.
+    #[serde(default)]
+    pub sink: Option<RestConfig>,
+}
+
+impl FactoryConfig {
+    pub fn is_parent_of(&self, account_id: &str) -> bool {
+        account_id
+            .strip_suffix(self.parent.as_str())
+            .map_or(false, |prefix| prefix.len() > 1 && prefix.ends_with('.'))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscoveredContract {
+    pub account_id: String,
+    pub parent: String,
+    pub handler: HandlerKind,
+    pub block_height: u64,
+}
+
+impl DiscoveredContract {
+    pub fn route(&self, config: &AppConfig) -> Route {
+        let sink = config
+            .factories
+            .iter()
+            .find(|f| f.parent == self.parent)
+            .and_then(|f| f.sink.clone())
+            .unwrap_or_else(|| config.rest.clone());
+
+        Route {
+            pattern: AccountPattern::exact(self.account_id.clone()),
+            handler: self.handler,
+            sink,
+        }
+    }
+}
+
+pub fn load_registry(config: &AppConfig) -> anyhow::Result<Vec<DiscoveredContract>> {
+    config.store().load(REGISTRY)
+}
+
+/// Registers contracts deployed to sub-accounts of the configured factories.
+#[tracing::instrument(
+    name = "Discovering factory-deployed contracts",
+    skip(outcome),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn discover_contracts(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block_height: u64,
+) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let factory = match deploying_factory(&config.factories, outcome) {
+        Some(factory) => factory,
+        None => return Ok(()),
+    };
+
+    let mut router = get_router().await.write().await;
+    let account_id = outcome.receipt.receiver_id.as_ref();
+    if router.has_exact(account_id) {
+        return Ok(());
+    }
+
+    let contract = DiscoveredContract {
+        account_id: account_id.to_owned(),
+        parent: factory.parent.clone(),
+        handler: factory.handler,
+        block_height,
+    };
+    register(config, &mut router, contract)
+}
+
+/// Factory whose sub-account the receipt successfully deployed a contract to.
+fn deploying_factory<'a>(
+    factories: &'a [FactoryConfig],
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+) -> Option<&'a FactoryConfig> {
+    let account_id = outcome.receipt.receiver_id.as_ref();
+    let factory = factories.iter().find(|f| f.is_parent_of(account_id))?;
+
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return None,
+    };
+    let succeeded = matches!(
+        outcome.execution_outcome.outcome.status,
+        ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+    );
+    if !succeeded {
+        return None;
+    }
+
+    if actions
+        .iter()
+        .any(|a| matches!(a, ActionView::CreateAccount))
+    {
+        tracing::info!("Account `{account_id}` created under `{}`", factory.parent);
+    }
+    actions
+        .iter()
+        .any(|a| matches!(a, ActionView::DeployContract { .. }))
+        .then_some(factory)
+}
+
+/// Adds the contract to the registry and routes its receipts from now on.
+fn register(
+    config: &AppConfig,
+    router: &mut Router,
+    contract: DiscoveredContract,
+) -> anyhow::Result<()> {
+    let mut registry = load_registry(config)?;
+    registry.push(contract.clone());
+    config.store().save(REGISTRY, &registry)?;
+    router.register(contract.route(config));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use near_lake_framework::near_indexer_primitives::CryptoHash;
+    use serde_json::{json, Value};
+
+    fn factories() -> Value {
+        json!([
+            { "parent": "factory.test", "handler": "nft" },
+            {
+                "parent": "markets.test",
+                "handler": "market",
+                "sink": { "host": "http://markets", "port": 4000, "username": "markets", "password": "secret" },
+            },
+        ])
+    }
+
+    fn outcome(
+        receiver_id: &str,
+        actions: Value,
+        status: Value,
+    ) -> IndexerExecutionOutcomeWithReceipt {
+        let receipt_id = CryptoHash::hash_bytes(receiver_id.as_bytes());
+        serde_json::from_value(json!({
+            "execution_outcome": {
+                "proof": [],
+                "block_hash": CryptoHash::hash_bytes(b"block"),
+                "id": receipt_id,
+                "outcome": {
+                    "logs": [],
+                    "receipt_ids": [],
+                    "gas_burnt": 0,
+                    "tokens_burnt": "0",
+                    "executor_id": receiver_id,
+                    "status": status,
+                    "metadata": { "version": 1, "gas_profile": null },
+                },
+            },
+            "receipt": {
+                "predecessor_id": "factory.test",
+                "receiver_id": receiver_id,
+                "receipt_id": receipt_id,
+                "receipt": {
+                    "Action": {
+                        "signer_id": "owner.test",
+                        "signer_public_key": "ed25519:11111111111111111111111111111111",
+                        "gas_price": "100000000",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": actions,
+                    },
+                },
+            },
+        }))
+        .expect("Test outcome is invalid")
+    }
+
+    fn deploy() -> Value {
+        json!([
+            "CreateAccount",
+            { "DeployContract": { "code": base64::encode([1u8; 32]) } },
+        ])
+    }
+
+    fn succeeded() -> Value {
+        json!({ "SuccessValue": "" })
+    }
+
+    #[test]
+    fn factories_are_parents_of_their_sub_accounts_only() {
+        let factories: Vec<FactoryConfig> = serde_json::from_value(factories()).unwrap();
+        let factory = &factories[0];
+
+        assert!(factory.is_parent_of("nft.factory.test"));
+        assert!(factory.is_parent_of("a.b.factory.test"));
+        assert!(!factory.is_parent_of("factory.test"));
+        assert!(!factory.is_parent_of(".factory.test"));
+        assert!(!factory.is_parent_of("myfactory.test"));
+    }
+
+    #[test]
+    fn matches_successful_deploys_to_sub_accounts() {
+        let factories: Vec<FactoryConfig> = serde_json::from_value(factories()).unwrap();
+        let failed = json!({ "Failure": { "InvalidTxError": "InvalidSignature" } });
+        let parent = |outcome: IndexerExecutionOutcomeWithReceipt| {
+            deploying_factory(&factories, &outcome).map(|f| f.parent.as_str())
+        };
+
+        assert_eq!(
+            parent(outcome("nft.factory.test", deploy(), succeeded())),
+            Some("factory.test")
+        );
+        assert_eq!(
+            parent(outcome("a.markets.test", deploy(), succeeded())),
+            Some("markets.test")
+        );
+        assert_eq!(
+            parent(outcome("nft.other.test", deploy(), succeeded())),
+            None
+        );
+        assert_eq!(parent(outcome("nft.factory.test", deploy(), failed)), None);
+        assert_eq!(
+            parent(outcome(
+                "nft.factory.test",
+                json!(["CreateAccount"]),
+                succeeded()
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_registered_contracts_after_a_restart() {
+        let config = testing::app_config("discovery", json!({ "factories": factories() }));
+        let mut router = Router::from_config(&config).unwrap();
+
+        for (account_id, parent, handler) in [
+            ("nft.factory.test", "factory.test", HandlerKind::Nft),
+            ("a.markets.test", "markets.test", HandlerKind::Market),
+        ] {
+            let contract = DiscoveredContract {
+                account_id: account_id.to_owned(),
+                parent: parent.to_owned(),
+                handler,
+                block_height: 10,
+            };
+            register(&config, &mut router, contract).unwrap();
+        }
+        assert!(router.has_exact("nft.factory.test"));
+
+        let registry = load_registry(&config).unwrap();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry[0].account_id, "nft.factory.test");
+        assert_eq!(registry[1].block_height, 10);
+
+        let restarted = Router::from_config(&config).unwrap();
+        let nft = restarted.route("nft.factory.test").unwrap();
+        assert_eq!(nft.handler, HandlerKind::Nft);
+        assert_eq!(nft.sink.base_url(), config.rest.base_url());
+        let market = restarted.route("a.markets.test").unwrap();
+        assert_eq!(market.handler, HandlerKind::Market);
+        assert_eq!(market.sink.base_url(), "http://markets:4000");
+        assert!(restarted.route("other.factory.test").is_none());
+    }
+}
@@ -1,11 +1,142 @@
-use crate::{IndexerExecutionOutcomeWithReceipt, EVENT_PREFIX};
-use anyhow::Context;
+use crate::config::RestConfig;
+use crate::consts::UNKNOWN_EVENT_COUNTS;
+use crate::models::{UnknownEvent, UnknownEventReason};
+use crate::store::JsonStore;
+use crate::{metrics, transactions, IndexerExecutionOutcomeWithReceipt, EVENT_PREFIX};
+use actix_web::web;
+use anyhow::{anyhow, Context};
+use legacy::LegacyFormat;
 use reqwest::Response;
-use serde::{Deserialize, Serialize};
+use rules::{EventRules, RuleCheck, UnknownVersionPolicy};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
+pub mod ft;
+pub mod generic;
+pub mod legacy;
 pub mod market;
 pub mod nft;
+pub mod rules;
+pub mod top;
+
+/// NEP-297 envelope every contract event log is wrapped in.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EventEnvelope {
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+/// Decoded event together with the envelope it came in.
+pub struct ContractEvent<T> {
+    pub envelope: EventEnvelope,
+    pub event: T,
+}
+
+const UNKNOWN_COUNTS: &str = "unknown_events";
+
+#[derive(Serialize, Deserialize)]
+struct UnknownEventCount {
+    contract_id: String,
+    reason: String,
+    count: u64,
+}
+
+/// Unknown events seen per contract and reason since the first run, saved to
+/// `unknown_events.json` in the state dir so the metric doesn't start over on restart.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<UnknownEventCount>", into = "Vec<UnknownEventCount>")]
+pub struct UnknownEventCounts {
+    counts: BTreeMap<(String, String), u64>,
+    dirty: bool,
+}
+
+impl From<Vec<UnknownEventCount>> for UnknownEventCounts {
+    fn from(counts: Vec<UnknownEventCount>) -> Self {
+        Self {
+            counts: counts
+                .into_iter()
+                .map(|c| ((c.contract_id, c.reason), c.count))
+                .collect(),
+            dirty: false,
+        }
+    }
+}
+
+impl From<UnknownEventCounts> for Vec<UnknownEventCount> {
+    fn from(counts: UnknownEventCounts) -> Self {
+        counts
+            .counts
+            .into_iter()
+            .map(|((contract_id, reason), count)| UnknownEventCount {
+                contract_id,
+                reason,
+                count,
+            })
+            .collect()
+    }
+}
+
+impl UnknownEventCounts {
+    /// Counts the event and returns how many of its contract and reason were seen so far.
+    pub fn add(&mut self, event: &UnknownEvent) -> u64 {
+        self.dirty = true;
+        metrics::UNKNOWN_EVENTS
+            .with_label_values(&[event.contract_id.as_str(), event.reason.as_str()])
+            .inc();
+        let count = self
+            .counts
+            .entry((event.contract_id.clone(), event.reason.as_str().to_owned()))
+            .or_default();
+        *count += 1;
+
+        *count
+    }
+}
+
+/// Replaces the unknown event counts with the ones saved before the last shutdown and
+/// carries them over to the metric.
+#[tracing::instrument(name = "Restoring unknown event counts", skip(store))]
+pub fn restore_unknown_counts(store: &JsonStore) -> anyhow::Result<()> {
+    let counts: UnknownEventCounts = store.load(UNKNOWN_COUNTS)?;
+    for ((contract_id, reason), count) in &counts.counts {
+        metrics::UNKNOWN_EVENTS
+            .with_label_values(&[contract_id.as_str(), reason.as_str()])
+            .inc_by(*count);
+    }
+    *UNKNOWN_EVENT_COUNTS
+        .lock()
+        .expect("Unknown event counts lock poisoned") = counts;
+
+    Ok(())
+}
+
+/// Saves the unknown event counts if they changed during the block.
+#[tracing::instrument(name = "Persisting unknown event counts", skip(store))]
+pub fn persist_unknown_counts(store: &JsonStore) -> anyhow::Result<()> {
+    let counts = {
+        let mut counts = UNKNOWN_EVENT_COUNTS
+            .lock()
+            .expect("Unknown event counts lock poisoned");
+        if !counts.dirty {
+            return Ok(());
+        }
+        counts.dirty = false;
+        counts.clone()
+    };
+
+    store.save(UNKNOWN_COUNTS, &counts)
+}
+
+/// Events decoded from a receipt's logs, along with the ones the indexer doesn't understand.
+pub struct CollectedEvents<T> {
+    pub events: Vec<ContractEvent<T>>,
+    pub unknown: Vec<UnknownEvent>,
+}
 
 #[tracing::instrument(name = "Handle request error", skip(response))]
 pub async fn handle_response_for_error(response: Response) -> anyhow::Result<()> {
@@ -28,27 +159,382 @@ pub async fn handle_response_for_error(response: Response) -> anyhow::Result<()>
     Ok(())
 }
 
-#[tracing::instrument(name = "Collection contracts events from logs", skip(outcome))]
-pub fn collect_contract_events<'a, T>(
-    outcome: &'a IndexerExecutionOutcomeWithReceipt,
+#[tracing::instrument(
+    name = "Collection contracts events from logs",
+    skip(outcome, rules),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub fn collect_contract_events<T>(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    rules: &EventRules,
+    legacy: Option<&LegacyFormat>,
+    block_height: u64,
     // _block_timestamp: &u64,
     // _shard_id: &ShardId,
     // _index_in_shard: &mut i32,
-) -> Vec<T>
+) -> anyhow::Result<CollectedEvents<T>>
 where
-    T: Serialize + Deserialize<'a>,
+    T: Serialize + DeserializeOwned,
 {
-    outcome
+    let mut events = Vec::new();
+    let mut unknown = Vec::new();
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    let mut record_unknown = |log: &str, reason, error: Option<String>| {
+        unknown.push(UnknownEvent {
+            contract_id: outcome.receipt.receiver_id.to_string(),
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            transaction_hash: transaction_hash.clone(),
+            block_height,
+            reason,
+            error,
+            log: log.to_owned(),
+        })
+    };
+    let logs = outcome
         .execution_outcome
         .outcome
         .logs
         .iter()
-        .filter_map(|log| log.trim().strip_prefix(EVENT_PREFIX))
-        .filter_map(|v| {
-            serde_json::from_str(v).unwrap_or_else(|e| {
+        .filter_map(|log| log.trim().strip_prefix(EVENT_PREFIX));
+
+    for log in logs {
+        let log = match legacy.map(|legacy| legacy.upgrade_log(log)) {
+            Some(Ok(upgraded)) => Cow::Owned(upgraded),
+            Some(Err(e)) => {
+                tracing::error!("Couldn't rewrite log of a legacy version: {}", e);
+                record_unknown(
+                    log,
+                    UnknownEventReason::InvalidEnvelope,
+                    Some(e.to_string()),
+                );
+                continue;
+            }
+            None => Cow::Borrowed(log),
+        };
+        let log = log.as_ref();
+        let envelope: EventEnvelope = match serde_json::from_str(log) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                tracing::error!("Couldn't parse NEP-297 envelope: {}", e);
+                record_unknown(
+                    log,
+                    UnknownEventReason::InvalidEnvelope,
+                    Some(e.to_string()),
+                );
+                continue;
+            }
+        };
+
+        match rules.check(&envelope.standard, &envelope.version) {
+            RuleCheck::Accepted => {}
+            RuleCheck::UnknownStandard => {
+                tracing::warn!(
+                    "Skipping event `{}` of unexpected standard `{}`",
+                    envelope.event,
+                    envelope.standard
+                );
+                record_unknown(log, UnknownEventReason::UnknownStandard, None);
+                continue;
+            }
+            RuleCheck::UnknownVersion(UnknownVersionPolicy::Skip) => {
+                tracing::warn!(
+                    "Skipping event `{}` of unsupported version {} {}",
+                    envelope.event,
+                    envelope.standard,
+                    envelope.version
+                );
+                record_unknown(log, UnknownEventReason::UnknownVersion, None);
+                continue;
+            }
+            RuleCheck::UnknownVersion(UnknownVersionPolicy::Decode) => {
+                tracing::warn!(
+                    "Decoding event `{}` of unsupported version {} {} with current models",
+                    envelope.event,
+                    envelope.standard,
+                    envelope.version
+                );
+            }
+            RuleCheck::UnknownVersion(UnknownVersionPolicy::Fail) => {
+                return Err(anyhow!(
+                    "Event `{}` has unsupported version {} {}",
+                    envelope.event,
+                    envelope.standard,
+                    envelope.version
+                ));
+            }
+        }
+
+        match serde_json::from_str(log) {
+            Ok(event) => events.push(ContractEvent { envelope, event }),
+            Err(e) => {
                 tracing::error!("Couldn't parse: {}", e);
-                None
-            })
+                record_unknown(log, UnknownEventReason::Undecodable, Some(e.to_string()));
+            }
+        }
+    }
+
+    Ok(CollectedEvents { events, unknown })
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store unknown contract events",
+    skip(unknown, client, sink)
+)]
+pub async fn store_unknown_events(
+    unknown: &[UnknownEvent],
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    for event in unknown {
+        let count = UNKNOWN_EVENT_COUNTS
+            .lock()
+            .expect("Unknown event counts lock poisoned")
+            .add(event);
+        tracing::warn!(
+            "Unknown event from `{}` in receipt {} ({} so far for this contract and reason)",
+            event.contract_id,
+            event.receipt_id,
+            count
+        );
+    }
+
+    let response = client
+        .post(format!("{}/unknown_events", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(unknown)
+        .send()
+        .await
+        .context("Failed to send unknown events to the rest service")?;
+
+    handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use near_lake_framework::near_indexer_primitives::CryptoHash;
+    use rules::StandardRule;
+    use serde_json::json;
+
+    fn outcome(logs: &[Value]) -> IndexerExecutionOutcomeWithReceipt {
+        let receipt_id = CryptoHash::hash_bytes(b"receipt");
+        let logs = logs
+            .iter()
+            .map(|log| format!("{EVENT_PREFIX}{log}"))
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "execution_outcome": {
+                "proof": [],
+                "block_hash": CryptoHash::hash_bytes(b"block"),
+                "id": receipt_id,
+                "outcome": {
+                    "logs": logs,
+                    "receipt_ids": [],
+                    "gas_burnt": 0,
+                    "tokens_burnt": "0",
+                    "executor_id": "nft.test",
+                    "status": { "SuccessValue": "" },
+                    "metadata": { "version": 1, "gas_profile": null },
+                },
+            },
+            "receipt": {
+                "predecessor_id": "alice.test",
+                "receiver_id": "nft.test",
+                "receipt_id": receipt_id,
+                "receipt": {
+                    "Action": {
+                        "signer_id": "alice.test",
+                        "signer_public_key": "ed25519:11111111111111111111111111111111",
+                        "gas_price": "100000000",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": [],
+                    },
+                },
+            },
+        }))
+        .expect("Test outcome is invalid")
+    }
+
+    fn event(version: &str) -> Value {
+        json!({
+            "standard": "nep171",
+            "version": version,
+            "event": "nft_mint",
+            "data": [{ "owner_id": "alice.test", "token_ids": ["1"] }],
         })
-        .collect()
+    }
+
+    fn rules(on_unknown_version: UnknownVersionPolicy) -> EventRules {
+        EventRules {
+            standards: vec![StandardRule {
+                standard: "nep171".to_owned(),
+                versions: "^1.0.0".parse().unwrap(),
+                on_unknown_version,
+            }],
+        }
+    }
+
+    fn collect(
+        outcome: &IndexerExecutionOutcomeWithReceipt,
+        policy: UnknownVersionPolicy,
+    ) -> anyhow::Result<CollectedEvents<Value>> {
+        collect_contract_events(outcome, &rules(policy), None, 7)
+    }
+
+    #[test]
+    fn skips_unknown_versions_as_unknown_events() {
+        let outcome = outcome(&[event("1.0.0"), event("2.0.0")]);
+
+        let collected = collect(&outcome, UnknownVersionPolicy::Skip).unwrap();
+
+        assert_eq!(collected.events.len(), 1);
+        assert_eq!(collected.events[0].envelope.version, "1.0.0");
+        assert_eq!(collected.unknown.len(), 1);
+        assert_eq!(collected.unknown[0].reason.as_str(), "unknown_version");
+        assert_eq!(collected.unknown[0].block_height, 7);
+    }
+
+    #[test]
+    fn decodes_unknown_versions_with_the_current_models() {
+        let outcome = outcome(&[event("2.0.0")]);
+
+        let collected = collect(&outcome, UnknownVersionPolicy::Decode).unwrap();
+
+        assert_eq!(collected.events.len(), 1);
+        assert!(collected.unknown.is_empty());
+    }
+
+    #[test]
+    fn fails_on_unknown_versions() {
+        let outcome = outcome(&[event("1.0.0"), event("2.0.0")]);
+
+        assert!(collect(&outcome, UnknownVersionPolicy::Fail).is_err());
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Mint {
+        data: Vec<MintData>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct MintData {
+        owner_id: String,
+        token_ids: Vec<String>,
+    }
+
+    #[test]
+    fn classifies_unknown_events() {
+        let outcome = outcome(&[
+            json!({ "event": "nft_mint", "data": [] }),
+            json!({ "standard": "nep141", "version": "1.0.0", "event": "ft_mint" }),
+            json!({
+                "standard": "nep171",
+                "version": "1.0.0",
+                "event": "nft_mint",
+                "data": [{ "token_ids": ["1"] }],
+            }),
+            event("1.0.0"),
+        ]);
+
+        let collected: CollectedEvents<Mint> =
+            collect_contract_events(&outcome, &rules(UnknownVersionPolicy::Skip), None, 7).unwrap();
+
+        assert_eq!(collected.events.len(), 1);
+        assert_eq!(collected.events[0].event.data[0].owner_id, "alice.test");
+        let reasons = collected
+            .unknown
+            .iter()
+            .map(|unknown| unknown.reason.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            ["invalid_envelope", "unknown_standard", "undecodable"]
+        );
+        assert!(collected.unknown[0].error.is_some());
+        assert!(collected.unknown[1].error.is_none());
+        assert!(collected
+            .unknown
+            .iter()
+            .all(|unknown| unknown.contract_id == "nft.test"));
+    }
+
+    #[tokio::test]
+    async fn posts_unknown_events_to_the_rest_service() {
+        let (url, requests) = testing::record();
+        let outcome = outcome(&[event("2.0.0")]);
+        let unknown = collect(&outcome, UnknownVersionPolicy::Skip)
+            .unwrap()
+            .unknown;
+
+        store_unknown_events(
+            &unknown,
+            web::Data::new(reqwest::Client::new()),
+            &testing::sink(&url),
+        )
+        .await
+        .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/unknown_events");
+        let body = requests[0].json();
+        assert_eq!(body[0]["contract_id"], "nft.test");
+        assert_eq!(body[0]["reason"], "unknown_version");
+        assert_eq!(body[0]["block_height"], 7);
+    }
+
+    #[tokio::test]
+    async fn skips_posting_without_unknown_events() {
+        let (url, requests) = testing::record();
+
+        store_unknown_events(
+            &[],
+            web::Data::new(reqwest::Client::new()),
+            &testing::sink(&url),
+        )
+        .await
+        .unwrap();
+
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_unknown_event_counts_across_restarts() {
+        let store = JsonStore::new(testing::state_dir("unknown_events"));
+        let key = ("restored.test".to_owned(), "undecodable".to_owned());
+        let mut counts = UnknownEventCounts::default();
+        counts.counts.insert(key.clone(), 3);
+        store.save(UNKNOWN_COUNTS, &counts).unwrap();
+
+        restore_unknown_counts(&store).unwrap();
+        let metric = metrics::UNKNOWN_EVENTS.with_label_values(&["restored.test", "undecodable"]);
+        assert_eq!(metric.get(), 3);
+
+        let event = UnknownEvent {
+            contract_id: key.0.clone(),
+            receipt_id: "receipt".to_owned(),
+            transaction_hash: None,
+            block_height: 8,
+            reason: UnknownEventReason::Undecodable,
+            error: None,
+            log: "{}".to_owned(),
+        };
+        let count = UNKNOWN_EVENT_COUNTS.lock().unwrap().add(&event);
+        assert_eq!(count, 4);
+        assert_eq!(metric.get(), 4);
+        persist_unknown_counts(&store).unwrap();
+
+        let saved: UnknownEventCounts = store.load(UNKNOWN_COUNTS).unwrap();
+        assert_eq!(saved.counts[&key], 4);
+        assert!(!saved.dirty);
+    }
 }
@@ -0,0 +1,297 @@
+use crate::config::RestConfig;
+use crate::events::{self, ContractEvent, EventEnvelope};
+use crate::fungible;
+use crate::models::{BlockContext, U128};
+use crate::{transactions, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const FT_MINT_EVENT: &str = "ft_mint";
+pub const FT_TRANSFER_EVENT: &str = "ft_transfer";
+pub const FT_BURN_EVENT: &str = "ft_burn";
+
+/// `data` entry of a NEP-141 `ft_mint` or `ft_burn` event.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FtMintOrBurnData {
+    pub owner_id: String,
+    pub amount: U128,
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+/// `data` entry of a NEP-141 `ft_transfer` event.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FtTransferData {
+    pub old_owner_id: String,
+    pub new_owner_id: String,
+    pub amount: U128,
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FtEventKind {
+    Mint,
+    Transfer,
+    Burn,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FtEventForRest {
+    pub contract_id: String,
+    pub kind: FtEventKind,
+    /// Sender of a transfer or owner of burned tokens.
+    pub old_owner_id: Option<String>,
+    /// Receiver of a transfer or owner of minted tokens.
+    pub new_owner_id: Option<String>,
+    pub amount: U128,
+    pub memo: Option<String>,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+/// Balance change an NEP-141 event describes.
+#[derive(Debug, Clone)]
+pub enum FtChange {
+    Mint(FtMintOrBurnData),
+    Transfer(FtTransferData),
+    Burn(FtMintOrBurnData),
+}
+
+impl FtChange {
+    fn apply(&self, ledger: &mut fungible::FtLedger, contract_id: &str) {
+        match self {
+            FtChange::Mint(mint) => ledger.mint(contract_id, &mint.owner_id, mint.amount.0),
+            FtChange::Transfer(transfer) => ledger.transfer(
+                contract_id,
+                &transfer.old_owner_id,
+                &transfer.new_owner_id,
+                transfer.amount.0,
+            ),
+            FtChange::Burn(burn) => ledger.burn(contract_id, &burn.owner_id, burn.amount.0),
+        }
+    }
+}
+
+fn parse_data<T: DeserializeOwned>(envelope: &EventEnvelope) -> anyhow::Result<Vec<T>> {
+    let data = envelope
+        .data
+        .clone()
+        .with_context(|| format!("`{}` event has no data", envelope.event))?;
+
+    serde_json::from_value(data)
+        .with_context(|| format!("Failed to deserialize `{}` data", envelope.event))
+}
+
+pub fn parse_ft_changes(envelope: &EventEnvelope) -> anyhow::Result<Vec<FtChange>> {
+    let changes = match envelope.event.as_str() {
+        FT_MINT_EVENT => parse_data::<FtMintOrBurnData>(envelope)?
+            .into_iter()
+            .map(FtChange::Mint)
+            .collect(),
+        FT_TRANSFER_EVENT => parse_data::<FtTransferData>(envelope)?
+            .into_iter()
+            .map(FtChange::Transfer)
+            .collect(),
+        FT_BURN_EVENT => parse_data::<FtMintOrBurnData>(envelope)?
+            .into_iter()
+            .map(FtChange::Burn)
+            .collect(),
+        other => {
+            tracing::warn!("Skipping unknown fungible token event `{other}`");
+            Vec::new()
+        }
+    };
+
+    Ok(changes)
+}
+
+/// Applies NEP-141 events to the balances of their token and sends them to the rest service.
+/// The changed balances are sent once the block is handled.
+#[tracing::instrument(
+    name = "Sending request to the rest service to store new fungible token events",
+    skip(outcome, events, client, sink)
+)]
+pub async fn handle_ft_events(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    events: Vec<ContractEvent<EventEnvelope>>,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let mut changes = Vec::new();
+    for ContractEvent { envelope, .. } in &events {
+        changes.extend(parse_ft_changes(envelope)?);
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let contract_id = outcome.receipt.receiver_id.to_string();
+    {
+        let mut ledger = fungible::get_ft_ledger()
+            .await
+            .lock()
+            .expect("Fungible token ledger lock poisoned");
+        if !ledger.accepts(block.height) {
+            tracing::info!("Block {} is already in the ledger, skipping", block.height);
+            return Ok(());
+        }
+        for change in &changes {
+            change.apply(&mut ledger, &contract_id);
+        }
+    }
+
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    let json = changes
+        .into_iter()
+        .map(|change| {
+            let (kind, old_owner_id, new_owner_id, amount, memo) = match change {
+                FtChange::Mint(mint) => (
+                    FtEventKind::Mint,
+                    None,
+                    Some(mint.owner_id),
+                    mint.amount,
+                    mint.memo,
+                ),
+                FtChange::Transfer(transfer) => (
+                    FtEventKind::Transfer,
+                    Some(transfer.old_owner_id),
+                    Some(transfer.new_owner_id),
+                    transfer.amount,
+                    transfer.memo,
+                ),
+                FtChange::Burn(burn) => (
+                    FtEventKind::Burn,
+                    Some(burn.owner_id),
+                    None,
+                    burn.amount,
+                    burn.memo,
+                ),
+            };
+            FtEventForRest {
+                contract_id: contract_id.clone(),
+                kind,
+                old_owner_id,
+                new_owner_id,
+                amount,
+                memo,
+                receipt_id: outcome.receipt.receipt_id.to_string(),
+                transaction_hash: transaction_hash.clone(),
+                block_height: block.height,
+                timestamp_ms: block.timestamp_ms(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let response = client
+        .post(format!("{}/ft_events", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&json)
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn envelope(event: &str, data: Value) -> EventEnvelope {
+        EventEnvelope {
+            standard: "nep141".to_owned(),
+            version: "1.0.0".to_owned(),
+            event: event.to_owned(),
+            data: Some(data),
+        }
+    }
+
+    #[test]
+    fn parses_every_entry_of_a_transfer() {
+        let envelope = envelope(
+            FT_TRANSFER_EVENT,
+            json!([
+                { "old_owner_id": "alice.near", "new_owner_id": "bob.near", "amount": "10" },
+                { "old_owner_id": "bob.near", "new_owner_id": "carol.near", "amount": "3", "memo": "tip" },
+            ]),
+        );
+
+        let changes = parse_ft_changes(&envelope).unwrap();
+
+        assert_eq!(changes.len(), 2);
+        match &changes[1] {
+            FtChange::Transfer(transfer) => {
+                assert_eq!(transfer.old_owner_id, "bob.near");
+                assert_eq!(transfer.new_owner_id, "carol.near");
+                assert_eq!(transfer.amount.0, 3);
+                assert_eq!(transfer.memo.as_deref(), Some("tip"));
+            }
+            other => panic!("Expected a transfer, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_amounts_above_u64() {
+        let amount = u128::MAX.to_string();
+        let envelope = envelope(
+            FT_MINT_EVENT,
+            json!([{ "owner_id": "alice.near", "amount": amount }]),
+        );
+
+        match &parse_ft_changes(&envelope).unwrap()[..] {
+            [FtChange::Mint(mint)] => assert_eq!(mint.amount.0, u128::MAX),
+            other => panic!("Expected a single mint, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn skips_unknown_events() {
+        let envelope = envelope("ft_freeze", json!([{ "owner_id": "alice.near" }]));
+
+        assert!(parse_ft_changes(&envelope).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_events_without_data() {
+        let mut envelope = envelope(FT_BURN_EVENT, Value::Null);
+        envelope.data = None;
+
+        assert!(parse_ft_changes(&envelope).is_err());
+    }
+
+    #[test]
+    fn applies_changes_to_the_ledger() {
+        let mut ledger = fungible::FtLedger::default();
+        let changes = [
+            envelope(
+                FT_MINT_EVENT,
+                json!([{ "owner_id": "alice.near", "amount": "100" }]),
+            ),
+            envelope(
+                FT_TRANSFER_EVENT,
+                json!([{ "old_owner_id": "alice.near", "new_owner_id": "bob.near", "amount": "30" }]),
+            ),
+            envelope(
+                FT_BURN_EVENT,
+                json!([{ "owner_id": "bob.near", "amount": "10" }]),
+            ),
+        ];
+        for envelope in &changes {
+            for change in parse_ft_changes(envelope).unwrap() {
+                change.apply(&mut ledger, "token.near");
+            }
+        }
+
+        let contract = ledger.contract("token.near").unwrap();
+        assert_eq!(contract.total_supply.0, 90);
+        assert_eq!(contract.balance("alice.near"), 70);
+        assert_eq!(contract.balance("bob.near"), 20);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::config::RestConfig;
+use crate::events::{self, ContractEvent, EventEnvelope};
+use crate::models::{BlockContext, ContractEventForRest};
+use crate::{transactions, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+
+/// Stores NEP-297 events of contracts without dedicated models as they were emitted, so
+/// they can be decoded on the rest side or reprocessed later.
+#[tracing::instrument(
+    name = "Sending request to the rest service to store new contract events to the database",
+    skip(outcome, events, client, sink)
+)]
+pub async fn handle_generic_events(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    events: Vec<ContractEvent<EventEnvelope>>,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    let json = events
+        .into_iter()
+        .map(|ContractEvent { envelope, .. }| ContractEventForRest {
+            contract_id: outcome.receipt.receiver_id.to_string(),
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            transaction_hash: transaction_hash.clone(),
+            block_height: block.height,
+            standard: envelope.standard,
+            version: envelope.version,
+            event: envelope.event,
+            data: envelope.data,
+        })
+        .collect::<Vec<_>>();
+
+    let response = client
+        .post(format!("{}/contract_events", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&json)
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await
+}
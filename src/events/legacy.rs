@@ -0,0 +1,111 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Names an older version of a contract used for its events and tokens, where they differ from
+/// what the current models read. Its logs and return values are rewritten to the current names
+/// before they're decoded, so they map to the same `NftChange`s and `MarketEventKind`s.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LegacyFormat {
+    /// Old event name to the current one.
+    #[serde(default)]
+    pub events: HashMap<String, String>,
+    /// Old field name to the current one, wherever it occurs in event data or return values.
+    #[serde(default)]
+    pub fields: HashMap<String, String>,
+}
+
+impl LegacyFormat {
+    /// Rewrites the JSON of an `EVENT_JSON:` log to the current names.
+    pub fn upgrade_log(&self, log: &str) -> anyhow::Result<String> {
+        let mut log: Value = serde_json::from_str(log)?;
+        if let Some(event) = log.get_mut("event") {
+            if let Some(current) = event.as_str().and_then(|name| self.events.get(name)) {
+                *event = Value::String(current.clone());
+            }
+        }
+        if let Some(data) = log.get_mut("data") {
+            self.rename_fields(data);
+        }
+
+        Ok(log.to_string())
+    }
+
+    /// Renames old fields at any depth of `value`.
+    pub fn rename_fields(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, mut field) in std::mem::take(fields) {
+                    self.rename_fields(&mut field);
+                    let name = self.fields.get(&name).cloned().unwrap_or(name);
+                    fields.insert(name, field);
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.rename_fields(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn format() -> LegacyFormat {
+        LegacyFormat {
+            events: HashMap::from([("ask_added".to_owned(), "add_ask".to_owned())]),
+            fields: HashMap::from([("owner".to_owned(), "account_id".to_owned())]),
+        }
+    }
+
+    #[test]
+    fn upgrades_event_name_and_data_fields() {
+        let log = json!({
+            "standard": "market",
+            "version": "0.1.0",
+            "event": "ask_added",
+            "data": { "token_id": "token-1", "owner": "alice.near", "price": "1" },
+        });
+
+        let upgraded: Value =
+            serde_json::from_str(&format().upgrade_log(&log.to_string()).unwrap()).unwrap();
+
+        assert_eq!(
+            upgraded,
+            json!({
+                "standard": "market",
+                "version": "0.1.0",
+                "event": "add_ask",
+                "data": { "token_id": "token-1", "account_id": "alice.near", "price": "1" },
+            })
+        );
+    }
+
+    #[test]
+    fn renames_nested_fields_of_return_values() {
+        let mut tokens = json!([{ "token_id": "token-1", "model": { "owner": "alice.near" } }]);
+
+        format().rename_fields(&mut tokens);
+
+        assert_eq!(
+            tokens,
+            json!([{ "token_id": "token-1", "model": { "account_id": "alice.near" } }])
+        );
+    }
+
+    #[test]
+    fn keeps_envelope_fields_outside_data() {
+        let log =
+            json!({ "standard": "nep171", "version": "1.0.0", "event": "nft_mint", "owner": "x" });
+
+        let upgraded: Value =
+            serde_json::from_str(&format().upgrade_log(&log.to_string()).unwrap()).unwrap();
+
+        assert_eq!(upgraded, log);
+    }
+}
@@ -1,50 +1,349 @@
-use crate::{events, get_config, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use crate::analytics;
+use crate::config::RestConfig;
+use crate::consts::TRADES;
+use crate::deposits::{self, DepositRecord, DepositedForRest};
+use crate::events::ContractEvent;
+use crate::listings::{self, InvalidTransition, ListingKey, ListingKind, ListingState, Transition};
+use crate::models::{BlockContext, ListingFillForRest, U128};
+use crate::provenance::{self, ProvenanceEntry, ProvenanceKind};
+use crate::trades::{self, PendingSale};
+use crate::transactions;
+use crate::{events, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
 use actix_web::web;
 use battlemon_models::{
     market::ask::AskForRest, market::bid::BidForRest, market::events::MarketEventKind,
     market::sale::SaleForRest,
 };
+use rust_decimal::Decimal;
+use serde_json::Value;
 
 #[tracing::instrument(
     name = "Sending request to the rest service to store new market events to the database",
-    skip(outcome, events, client)
+    skip(outcome, events, client, sink)
 )]
 pub async fn handle_market_events(
     outcome: &IndexerExecutionOutcomeWithReceipt,
-    events: Vec<MarketEventKind>,
+    mut events: Vec<ContractEvent<MarketEventKind>>,
+    block: BlockContext,
     client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
 ) -> anyhow::Result<()> {
-    for event in events {
+    use MarketEventKind::*;
+
+    let market_contract_id = outcome.receipt.receiver_id.to_string();
+    let deposit = deposits::attached_deposit(outcome).map(|(_, deposit)| deposit);
+    move_sales_before_filled_listings(&mut events);
+    let mut records = Vec::new();
+    for ContractEvent { event, .. } in events {
+        let entry = market_provenance(outcome, block, &event);
+        let (path, record) = market_record(&event)?;
+        records.push(DepositRecord {
+            kind: entry.kind,
+            token_id: entry.token_id.clone(),
+            path: path.to_owned(),
+            record: record.clone(),
+        });
+        provenance::record([entry]).await?;
+        let transition = match &event {
+            Sale(_) => None,
+            AddAsk(ask) | RemoveAsk(ask) => {
+                let ask: AskForRest = ask.clone().into();
+                let key = listing_key(
+                    ListingKind::Ask,
+                    &market_contract_id,
+                    ask.token_id,
+                    ask.account_id,
+                );
+                Some(apply_transition(outcome, &event, key, ask.price, record, block.height).await)
+            }
+            AddBid(bid) | RemoveBid(bid) => {
+                let bid: BidForRest = bid.clone().into();
+                let key = listing_key(
+                    ListingKind::Bid,
+                    &market_contract_id,
+                    bid.token_id,
+                    bid.account_id,
+                );
+                Some(apply_transition(outcome, &event, key, bid.price, record, block.height).await)
+            }
+        };
+
+        if transition == Some(Transition::RemovedAfterFill) {
+            tracing::info!("Listing was filled by a sale, skipping its removal");
+            continue;
+        }
+
+        if let Sale(sale) = event {
+            handle_sale(outcome, sale.into(), deposit, block, client.clone(), sink).await?;
+            continue;
+        }
+
         let outcome_result = &outcome.execution_outcome.outcome.status;
-        let request = build_market_request(event, outcome_result, client.clone()).await?;
+        let request =
+            build_market_request(event, deposit, outcome_result, client.clone(), sink).await?;
         let response = request.send().await?;
         events::handle_response_for_error(response).await?;
         tracing::info!("Successfully stored nft event");
     }
+
+    deposits::observe_market_receipt(outcome, records, block, client, sink).await
+}
+
+/// A sale and the removal of the ask or bid it filled can be logged in either order. Moves each
+/// sale right before the removal of the seller's ask or the buyer's bid, keeping the other
+/// events where they were logged.
+fn move_sales_before_filled_listings(events: &mut Vec<ContractEvent<MarketEventKind>>) {
+    use MarketEventKind::*;
+
+    let mut index = 0;
+    while index < events.len() {
+        let sale: SaleForRest = match &events[index].event {
+            Sale(sale) => sale.clone().into(),
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        let removal = events[..index].iter().position(|e| match &e.event {
+            RemoveAsk(ask) => {
+                let ask: AskForRest = ask.clone().into();
+                ask.token_id == sale.token_id && ask.account_id == sale.prev_owner
+            }
+            RemoveBid(bid) => {
+                let bid: BidForRest = bid.clone().into();
+                bid.token_id == sale.token_id && bid.account_id == sale.curr_owner
+            }
+            _ => false,
+        });
+        if let Some(removal) = removal {
+            let sale = events.remove(index);
+            events.insert(removal, sale);
+        }
+        index += 1;
+    }
+}
+
+/// Rest service collection and body of the record a market event stores.
+fn market_record(event: &MarketEventKind) -> anyhow::Result<(&'static str, Value)> {
+    use MarketEventKind::*;
+
+    let record = match event {
+        Sale(sale) => (
+            "sales",
+            serde_json::to_value(SaleForRest::from(sale.clone()))?,
+        ),
+        AddAsk(ask) | RemoveAsk(ask) => {
+            ("asks", serde_json::to_value(AskForRest::from(ask.clone()))?)
+        }
+        AddBid(bid) | RemoveBid(bid) => {
+            ("bids", serde_json::to_value(BidForRest::from(bid.clone()))?)
+        }
+    };
+
+    Ok(record)
+}
+
+fn market_provenance(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block: BlockContext,
+    event: &MarketEventKind,
+) -> ProvenanceEntry {
+    use MarketEventKind::*;
+
+    match event {
+        Sale(sale) => {
+            let sale: SaleForRest = sale.clone().into();
+            ProvenanceEntry::new(outcome, block, ProvenanceKind::Sale, sale.token_id)
+                .account(sale.prev_owner)
+                .counterparty(sale.curr_owner)
+                .price(sale.price)
+        }
+        AddAsk(ask) | RemoveAsk(ask) => {
+            let ask: AskForRest = ask.clone().into();
+            let kind = match event {
+                AddAsk(_) => ProvenanceKind::AskAdded,
+                _ => ProvenanceKind::AskRemoved,
+            };
+            ProvenanceEntry::new(outcome, block, kind, ask.token_id)
+                .account(ask.account_id)
+                .price(ask.price)
+        }
+        AddBid(bid) | RemoveBid(bid) => {
+            let bid: BidForRest = bid.clone().into();
+            let kind = match event {
+                AddBid(_) => ProvenanceKind::BidAdded,
+                _ => ProvenanceKind::BidRemoved,
+            };
+            ProvenanceEntry::new(outcome, block, kind, bid.token_id)
+                .account(bid.account_id)
+                .price(bid.price)
+        }
+    }
+}
+
+fn listing_key(
+    kind: ListingKind,
+    market_contract_id: &str,
+    token_id: String,
+    account_id: String,
+) -> ListingKey {
+    ListingKey {
+        kind,
+        market_contract_id: market_contract_id.to_owned(),
+        token_id,
+        account_id,
+    }
+}
+
+async fn apply_transition(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    event: &MarketEventKind,
+    key: ListingKey,
+    price: Decimal,
+    record: Value,
+    block_height: u64,
+) -> Transition {
+    let transition = {
+        let mut book = listings::get_listings()
+            .await
+            .lock()
+            .expect("Listings lock poisoned");
+        match event {
+            MarketEventKind::AddAsk(_) | MarketEventKind::AddBid(_) => {
+                book.add(key.clone(), record, block_height)
+            }
+            _ => book.remove(key.clone(), block_height),
+        }
+    };
+
+    match transition {
+        Transition::Opened | Transition::Replaced => {
+            analytics::observe_listing(&key, Some(price)).await
+        }
+        Transition::Cancelled => {
+            deposits::close_escrow(&key, outcome, block_height);
+            analytics::observe_listing(&key, None).await
+        }
+        Transition::Invalid(invalid) => listings::report_invalid(&key, invalid),
+        _ => {}
+    }
+
+    transition
+}
+
+/// Closes the listing a sale filled and hands the sale over to `trades`, which stores it
+/// together with the NFT transfer it causes.
+#[tracing::instrument(
+    name = "Handling market sale",
+    skip(outcome, sale, client, sink),
+    fields(token_id = %sale.token_id)
+)]
+async fn handle_sale(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    sale: SaleForRest,
+    deposit: Option<U128>,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let market_contract_id = outcome.receipt.receiver_id.to_string();
+    let ask_key = listing_key(
+        ListingKind::Ask,
+        &market_contract_id,
+        sale.token_id.clone(),
+        sale.prev_owner.clone(),
+    );
+    let bid_key = listing_key(
+        ListingKind::Bid,
+        &market_contract_id,
+        sale.token_id.clone(),
+        sale.curr_owner.clone(),
+    );
+    let filled = {
+        let mut book = listings::get_listings()
+            .await
+            .lock()
+            .expect("Listings lock poisoned");
+        let is_open = |key: &ListingKey| book.get(key).map(|l| l.state) == Some(ListingState::Open);
+        let (ask_open, bid_open) = (is_open(&ask_key), is_open(&bid_key));
+        if ask_open {
+            book.fill(ask_key.clone(), block.height);
+            Some(ask_key)
+        } else if bid_open {
+            book.fill(bid_key.clone(), block.height);
+            Some(bid_key)
+        } else {
+            listings::report_invalid(&ask_key, InvalidTransition::FillMissing);
+            None
+        }
+    };
+
+    if let Some(key) = &filled {
+        deposits::close_escrow(key, outcome, block.height);
+    }
+    analytics::observe_sale(&sale.token_id, sale.price, filled.as_ref(), block).await;
+
+    if let Some(key) = filled {
+        let path = match key.kind {
+            ListingKind::Ask => "asks",
+            ListingKind::Bid => "bids",
+        };
+        let json = ListingFillForRest {
+            market_contract_id: key.market_contract_id,
+            token_id: key.token_id,
+            account_id: key.account_id,
+            block_height: block.height,
+            sale: sale.clone(),
+        };
+        let response = client
+            .post(format!("{}/{path}/filled", sink.base_url()))
+            .header("Content-Type", "application/json")
+            .basic_auth(sink.username(), Some(sink.password()))
+            .json(&json)
+            .send()
+            .await?;
+        events::handle_response_for_error(response).await?;
+    }
+
+    let sale = PendingSale {
+        market_contract_id,
+        sale,
+        receipt_id: outcome.receipt.receipt_id.to_string(),
+        transaction: transactions::transaction_of(&outcome.receipt.receipt_id),
+        block_height: block.height,
+        deposit,
+    };
+    let trade = TRADES.lock().expect("Trades lock poisoned").add_sale(sale);
+    if let Some(trade) = trade {
+        trades::store_trade(&trade, client.clone()).await?;
+    }
+
     Ok(())
 }
 
 #[tracing::instrument(
     name = "Building request for saving market contract's event",
-    skip(_outcome_result, client)
+    skip(_outcome_result, client, sink)
 )]
 pub async fn build_market_request(
     event: MarketEventKind,
+    deposit: Option<U128>,
     _outcome_result: &ExecutionStatusView,
     client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
 ) -> anyhow::Result<reqwest::RequestBuilder> {
     use MarketEventKind::*;
 
-    let config = get_config().await;
-    let base_url = config.rest.base_url();
+    let base_url = sink.base_url();
 
     let request_builder = match event {
-        Sale(sale) => {
-            let json: SaleForRest = sale.into();
-            client.post(format!("{base_url}/sales")).json(&json)
-        }
+        // Sales are stored along with their trade by `handle_sale`.
+        Sale(_) => anyhow::bail!("Sales aren't stored as market requests"),
         AddBid(bid) => {
-            let json: BidForRest = bid.into();
+            let json = DepositedForRest {
+                record: BidForRest::from(bid),
+                deposit,
+            };
             client.post(format!("{base_url}/bids")).json(&json)
         }
         RemoveBid(bid) => {
@@ -52,7 +351,10 @@ pub async fn build_market_request(
             client.delete(format!("{base_url}/bids")).json(&json)
         }
         AddAsk(ask) => {
-            let json: AskForRest = ask.into();
+            let json = DepositedForRest {
+                record: AskForRest::from(ask),
+                deposit,
+            };
             client.post(format!("{base_url}/asks")).json(&json)
         }
         RemoveAsk(ask) => {
@@ -63,7 +365,122 @@ pub async fn build_market_request(
 
     let ret = request_builder
         .header("Content-Type", "application/json")
-        .basic_auth(config.rest.username(), Some(config.rest.password()));
+        .basic_auth(sink.username(), Some(sink.password()));
 
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(name: &str, data: Value) -> ContractEvent<MarketEventKind> {
+        let log = json!({
+            "standard": "market",
+            "version": "1.0.0",
+            "event": name,
+            "data": data,
+        });
+
+        ContractEvent {
+            envelope: serde_json::from_value(log.clone()).unwrap(),
+            event: serde_json::from_value(log).expect("Test event is invalid"),
+        }
+    }
+
+    fn listing(name: &str, token_id: &str, account_id: &str) -> ContractEvent<MarketEventKind> {
+        event(
+            name,
+            json!({ "token_id": token_id, "account_id": account_id, "price": "10" }),
+        )
+    }
+
+    fn sale(token_id: &str) -> ContractEvent<MarketEventKind> {
+        event(
+            "sale",
+            json!({
+                "token_id": token_id,
+                "prev_owner": "alice.near",
+                "curr_owner": "bob.near",
+                "price": "10",
+            }),
+        )
+    }
+
+    fn names(events: &[ContractEvent<MarketEventKind>]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| {
+                format!(
+                    "{} {}",
+                    e.envelope.event,
+                    e.envelope.data.as_ref().unwrap()["token_id"]
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn moves_sale_before_the_removal_of_the_filled_ask() {
+        let mut events = vec![
+            listing("remove_ask", "1", "alice.near"),
+            listing("add_ask", "2", "alice.near"),
+            sale("1"),
+        ];
+
+        move_sales_before_filled_listings(&mut events);
+
+        assert_eq!(
+            names(&events),
+            ["sale \"1\"", "remove_ask \"1\"", "add_ask \"2\""]
+        );
+    }
+
+    #[test]
+    fn moves_sale_before_the_removal_of_the_filled_bid() {
+        let mut events = vec![listing("remove_bid", "1", "bob.near"), sale("1")];
+
+        move_sales_before_filled_listings(&mut events);
+
+        assert_eq!(names(&events), ["sale \"1\"", "remove_bid \"1\""]);
+    }
+
+    #[test]
+    fn keeps_sales_logged_before_the_removal() {
+        let mut events = vec![
+            sale("1"),
+            listing("remove_ask", "1", "alice.near"),
+            sale("2"),
+            listing("remove_bid", "2", "bob.near"),
+        ];
+
+        move_sales_before_filled_listings(&mut events);
+
+        assert_eq!(
+            names(&events),
+            [
+                "sale \"1\"",
+                "remove_ask \"1\"",
+                "sale \"2\"",
+                "remove_bid \"2\""
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_removals_of_other_listings_in_place() {
+        let mut events = vec![
+            listing("remove_ask", "1", "carol.near"),
+            listing("remove_bid", "1", "dave.near"),
+            sale("1"),
+        ];
+
+        move_sales_before_filled_listings(&mut events);
+
+        assert_eq!(
+            names(&events),
+            ["remove_ask \"1\"", "remove_bid \"1\"", "sale \"1\""]
+        );
+    }
+}
@@ -1,8 +1,50 @@
-use crate::{events, get_config, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use crate::analytics;
+use crate::composition;
+use crate::config::RestConfig;
+use crate::consts::TRADES;
+use crate::events::{legacy::LegacyFormat, ContractEvent, EventEnvelope};
+use crate::ipfs;
+use crate::models::BlockContext;
+use crate::pricing::Payout;
+use crate::provenance::{self, ProvenanceEntry, ProvenanceKind};
+use crate::routing::{get_router, HandlerKind};
+use crate::search;
+use crate::trades::{self, TokenTransfer};
+use crate::traits;
+use crate::transactions;
+use crate::{events, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
 use actix_web::web;
 use anyhow::{anyhow, Context};
 use battlemon_models::nft::{NftEvent, NftEventKind, NftTokenForRest, TokenExt};
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+pub const NFT_TRANSFER_EVENT: &str = "nft_transfer";
+pub const NFT_BURN_EVENT: &str = "nft_burn";
+
+/// `data` entry of a NEP-171 `nft_transfer` event.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NftTransferData {
+    pub old_owner_id: String,
+    pub new_owner_id: String,
+    pub token_ids: Vec<String>,
+    #[serde(default)]
+    pub authorized_id: Option<String>,
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+/// `data` entry of a NEP-171 `nft_burn` event, also the body removing the tokens from the rest
+/// service.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NftBurnData {
+    pub owner_id: String,
+    pub token_ids: Vec<String>,
+    #[serde(default)]
+    pub authorized_id: Option<String>,
+    #[serde(default)]
+    pub memo: Option<String>,
+}
 
 #[derive(Deserialize)]
 #[serde(untagged)]
@@ -29,20 +71,52 @@ pub fn deserialize_outcome_result_into<T: DeserializeOwned>(
     Ok(ret)
 }
 
+/// Tokens the receipt returned, under the current names when a legacy version returned them.
+fn deserialize_tokens<T: DeserializeOwned>(
+    outcome_result: &ExecutionStatusView,
+    legacy: Option<&LegacyFormat>,
+) -> anyhow::Result<T> {
+    let legacy = match legacy {
+        Some(legacy) => legacy,
+        None => return deserialize_outcome_result_into(outcome_result),
+    };
+    let mut tokens: Value = deserialize_outcome_result_into(outcome_result)?;
+    legacy.rename_fields(&mut tokens);
+
+    Ok(serde_json::from_value(tokens)?)
+}
+
+/// Tokens an NFT event produced, decoded from the receipt's return value.
+#[derive(Debug)]
+pub enum NftChange {
+    Minted(Vec<NftTokenForRest>),
+    Assembled(NftTokenForRest),
+    Disassembled(NftTokenForRest),
+}
+
+impl NftChange {
+    pub fn tokens(&self) -> &[NftTokenForRest] {
+        match self {
+            NftChange::Minted(tokens) => tokens,
+            NftChange::Assembled(token) | NftChange::Disassembled(token) => {
+                std::slice::from_ref(token)
+            }
+        }
+    }
+}
+
 #[tracing::instrument(
-    name = "Building request for saving nft contract's event",
-    skip(outcome_result, client)
+    name = "Decoding tokens changed by nft contract's event",
+    skip(outcome_result)
 )]
-pub async fn build_nft_request(
-    event: NftEvent,
+pub fn decode_nft_change(
+    event: &NftEvent,
     outcome_result: &ExecutionStatusView,
-    client: web::Data<reqwest::Client>,
-) -> anyhow::Result<reqwest::RequestBuilder> {
-    let config = get_config().await;
-    let base_url = config.rest.base_url();
+    legacy: Option<&LegacyFormat>,
+) -> anyhow::Result<NftChange> {
     match event.event {
         NftEventKind::NftMint => {
-            let tokens: OneOrMany<TokenExt> = deserialize_outcome_result_into(outcome_result)
+            let tokens: OneOrMany<TokenExt> = deserialize_tokens(outcome_result, legacy)
                 .context("Failed to deserialize nft token")?;
 
             let tokens_ext = match tokens {
@@ -55,57 +129,224 @@ pub async fn build_nft_request(
                 .flat_map(|t| NftTokenForRest::try_from(t).ok())
                 .collect::<Vec<_>>();
 
-            let request = client
-                .post(format!("{base_url}/nft_tokens"))
-                .header("Content-Type", "application/json")
-                .basic_auth(config.rest.username(), Some(config.rest.password()))
-                .json(&tokens_for_rest);
-
-            Ok(request)
+            Ok(NftChange::Minted(tokens_for_rest))
         }
         NftEventKind::AssembleNft | NftEventKind::DisassembleNft => {
-            let token: TokenExt = deserialize_outcome_result_into(outcome_result)
+            let token: TokenExt = deserialize_tokens(outcome_result, legacy)
                 .context("Failed to deserialize nft token")?;
 
             let token_for_rest: NftTokenForRest = token
                 .try_into()
                 .map_err(|_| anyhow::anyhow!("Failed to convert TokenExt to NftTokenForRest"))?;
 
-            let request = client
-                .patch(format!("{base_url}/nft_tokens"))
-                .basic_auth(config.rest.username(), Some(config.rest.password()))
-                .header("Content-Type", "application/json")
-                .json(&token_for_rest);
-
-            Ok(request)
+            match event.event {
+                NftEventKind::AssembleNft => Ok(NftChange::Assembled(token_for_rest)),
+                _ => Ok(NftChange::Disassembled(token_for_rest)),
+            }
         }
         _ => Err(anyhow!("The event is not implemented, {:?}", event)),
     }
 }
 
+#[tracing::instrument(
+    name = "Building request for saving nft contract's event",
+    skip(change, client, sink)
+)]
+pub fn build_nft_request(
+    change: &NftChange,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> reqwest::RequestBuilder {
+    let base_url = sink.base_url();
+    let request = match change {
+        NftChange::Minted(tokens_for_rest) => client
+            .post(format!("{base_url}/nft_tokens"))
+            .json(tokens_for_rest),
+        NftChange::Assembled(token_for_rest) | NftChange::Disassembled(token_for_rest) => client
+            .patch(format!("{base_url}/nft_tokens"))
+            .json(token_for_rest),
+    };
+
+    request
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+}
+
 #[tracing::instrument(
     name = "Sending request to the rest service to store new nft events to the database",
-    skip(outcome, client)
+    skip(outcome, events, legacy, client, sink)
 )]
 pub async fn handle_nft_events(
     outcome: &IndexerExecutionOutcomeWithReceipt,
-    events: Vec<NftEvent>,
+    events: Vec<ContractEvent<NftEvent>>,
+    legacy: Option<&LegacyFormat>,
+    block: BlockContext,
     client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
 ) -> anyhow::Result<()> {
-    for event in events {
-        let outcome_result = &outcome.execution_outcome.outcome.status;
-        let request = build_nft_request(event, outcome_result, client.clone()).await;
-
-        if request.is_err() {
-            tracing::error!(
-                "Failed to build request for saving nft event: {:?}",
-                request
-            );
+    for ContractEvent { envelope, event } in events {
+        if envelope.event == NFT_TRANSFER_EVENT {
+            handle_nft_transfer(outcome, &envelope, block, client.clone()).await?;
+            continue;
+        }
+        if envelope.event == NFT_BURN_EVENT {
+            handle_nft_burn(outcome, &envelope, block, client.clone(), sink).await?;
             continue;
         }
 
-        let response = request?.send().await?;
+        let outcome_result = &outcome.execution_outcome.outcome.status;
+        let change = match decode_nft_change(&event, outcome_result, legacy) {
+            Ok(change) => change,
+            Err(e) => {
+                tracing::error!("Failed to build request for saving nft event: {:?}", e);
+                continue;
+            }
+        };
+
+        analytics::observe_nft_change(outcome.receipt.receiver_id.as_ref(), &change).await;
+        traits::observe_nft_change(outcome.receipt.receiver_id.as_ref(), &change).await?;
+        search::observe_nft_change(outcome.receipt.receiver_id.as_ref(), &change).await?;
+        composition::observe_nft_change(
+            &change,
+            &outcome.receipt.receipt_id.to_string(),
+            block,
+            client.clone(),
+            sink,
+        )
+        .await?;
+        let kind = match change {
+            NftChange::Minted(_) => ProvenanceKind::Mint,
+            NftChange::Assembled(_) => ProvenanceKind::Assemble,
+            NftChange::Disassembled(_) => ProvenanceKind::Disassemble,
+        };
+        provenance::record(change.tokens().iter().map(|token| {
+            ProvenanceEntry::new(outcome, block, kind, token.token_id.clone())
+                .account(token.owner_id.clone())
+        }))
+        .await?;
+
+        let response = build_nft_request(&change, client.clone(), sink)
+            .send()
+            .await?;
+        events::handle_response_for_error(response).await?;
+        // Resolved after the token is stored, so the metadata has something to attach to.
+        ipfs::enqueue(outcome.receipt.receiver_id.as_ref(), &change, sink).await;
+    }
+    Ok(())
+}
+
+pub fn parse_nft_transfers(envelope: &EventEnvelope) -> anyhow::Result<Vec<NftTransferData>> {
+    let data = envelope
+        .data
+        .clone()
+        .context("`nft_transfer` event has no data")?;
+
+    serde_json::from_value(data).context("Failed to deserialize `nft_transfer` data")
+}
+
+#[tracing::instrument(name = "Handling nft burn", skip(outcome, envelope, client, sink))]
+async fn handle_nft_burn(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    envelope: &EventEnvelope,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let data = envelope
+        .data
+        .clone()
+        .context("`nft_burn` event has no data")?;
+    let burns: Vec<NftBurnData> =
+        serde_json::from_value(data).context("Failed to deserialize `nft_burn` data")?;
+
+    for burn in burns {
+        traits::observe_burn(outcome.receipt.receiver_id.as_ref(), &burn.token_ids).await;
+        search::observe_burn(outcome.receipt.receiver_id.as_ref(), &burn.token_ids).await;
+        provenance::record(burn.token_ids.iter().map(|token_id| {
+            ProvenanceEntry::new(outcome, block, ProvenanceKind::Burn, token_id.clone())
+                .account(burn.owner_id.clone())
+        }))
+        .await?;
+
+        let response = client
+            .delete(format!("{}/nft_tokens", sink.base_url()))
+            .header("Content-Type", "application/json")
+            .basic_auth(sink.username(), Some(sink.password()))
+            .json(&burn)
+            .send()
+            .await?;
         events::handle_response_for_error(response).await?;
     }
+
+    Ok(())
+}
+
+/// Logs the transfer in the tokens' provenance. Transfers made by a market are the second half
+/// of a sale and also complete a trade.
+#[tracing::instrument(name = "Handling nft transfer", skip(outcome, envelope, client))]
+async fn handle_nft_transfer(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    envelope: &EventEnvelope,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let transfers = parse_nft_transfers(envelope)?;
+    for transfer in &transfers {
+        for token_id in &transfer.token_ids {
+            search::observe_transfer(
+                outcome.receipt.receiver_id.as_ref(),
+                token_id,
+                &transfer.new_owner_id,
+            )
+            .await;
+        }
+    }
+    provenance::record(transfers.iter().flat_map(|transfer| {
+        transfer.token_ids.iter().map(|token_id| {
+            ProvenanceEntry::new(outcome, block, ProvenanceKind::Transfer, token_id.clone())
+                .account(transfer.old_owner_id.clone())
+                .counterparty(transfer.new_owner_id.clone())
+        })
+    }))
+    .await?;
+
+    let predecessor_id = outcome.receipt.predecessor_id.as_ref();
+    let by_market = get_router()
+        .await
+        .read()
+        .await
+        .route(predecessor_id)
+        .map_or(false, |route| route.handler == HandlerKind::Market);
+    if !by_market {
+        tracing::info!("Transfer wasn't made by a market, skipping");
+        return Ok(());
+    }
+
+    let transaction = transactions::transaction_of(&outcome.receipt.receipt_id);
+    let payout: Option<Payout> =
+        deserialize_outcome_result_into(&outcome.execution_outcome.outcome.status).ok();
+    for transfer in transfers {
+        for token_id in transfer.token_ids {
+            let token_transfer = TokenTransfer {
+                nft_contract_id: outcome.receipt.receiver_id.to_string(),
+                market_contract_id: predecessor_id.to_owned(),
+                token_id,
+                old_owner_id: transfer.old_owner_id.clone(),
+                new_owner_id: transfer.new_owner_id.clone(),
+                receipt_id: outcome.receipt.receipt_id.to_string(),
+                transaction: transaction.clone(),
+                block_height: block.height,
+                payout: payout.clone(),
+            };
+            let trade = TRADES
+                .lock()
+                .expect("Trades lock poisoned")
+                .add_transfer(token_transfer);
+            if let Some(trade) = trade {
+                trades::store_trade(&trade, client.clone()).await?;
+            }
+        }
+    }
+
     Ok(())
 }
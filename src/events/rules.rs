@@ -0,0 +1,137 @@
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+pub static ACCEPT_ALL: EventRules = EventRules {
+    standards: Vec::new(),
+};
+
+/// Which NEP-297 standards and versions a contract is expected to emit.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct EventRules {
+    /// Accepted standards. When empty every event is accepted.
+    #[serde(default)]
+    pub standards: Vec<StandardRule>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StandardRule {
+    pub standard: String,
+    /// Semver requirement, e.g. `">=1.0.0, <2.0.0"`.
+    pub versions: VersionReq,
+    #[serde(default)]
+    pub on_unknown_version: UnknownVersionPolicy,
+}
+
+/// What to do with an event of an accepted standard whose version is outside the range, from
+/// the most lenient to the strictest.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownVersionPolicy {
+    /// Drop the event and log a warning.
+    #[default]
+    Skip,
+    /// Try to decode the event with the current models anyway.
+    Decode,
+    /// Stop indexing until the models are updated, so no event is lost.
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuleCheck {
+    Accepted,
+    UnknownStandard,
+    UnknownVersion(UnknownVersionPolicy),
+}
+
+impl EventRules {
+    /// A version is accepted when any rule of its standard matches it. Otherwise the strictest
+    /// policy among those rules applies.
+    pub fn check(&self, standard: &str, version: &str) -> RuleCheck {
+        if self.standards.is_empty() {
+            return RuleCheck::Accepted;
+        }
+
+        let rules = self
+            .standards
+            .iter()
+            .filter(|r| r.standard == standard)
+            .collect::<Vec<_>>();
+        let policy = match rules.iter().map(|r| r.on_unknown_version).max() {
+            Some(policy) => policy,
+            None => return RuleCheck::UnknownStandard,
+        };
+
+        match Version::parse(version) {
+            Ok(version) if rules.iter().any(|r| r.versions.matches(&version)) => {
+                RuleCheck::Accepted
+            }
+            _ => RuleCheck::UnknownVersion(policy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(versions: &str, on_unknown_version: UnknownVersionPolicy) -> StandardRule {
+        StandardRule {
+            standard: "nep171".to_owned(),
+            versions: VersionReq::parse(versions).unwrap(),
+            on_unknown_version,
+        }
+    }
+
+    #[test]
+    fn accepts_versions_matching_any_rule_of_the_standard() {
+        let rules = EventRules {
+            standards: vec![
+                rule("^1.0.0", UnknownVersionPolicy::Skip),
+                rule("^2.0.0", UnknownVersionPolicy::Skip),
+            ],
+        };
+
+        assert_eq!(rules.check("nep171", "1.2.0"), RuleCheck::Accepted);
+        assert_eq!(rules.check("nep171", "2.0.1"), RuleCheck::Accepted);
+        assert_eq!(rules.check("nep141", "1.0.0"), RuleCheck::UnknownStandard);
+        assert_eq!(ACCEPT_ALL.check("anything", "x"), RuleCheck::Accepted);
+    }
+
+    #[test]
+    fn applies_the_policy_to_unknown_versions() {
+        for policy in [
+            UnknownVersionPolicy::Skip,
+            UnknownVersionPolicy::Decode,
+            UnknownVersionPolicy::Fail,
+        ] {
+            let rules = EventRules {
+                standards: vec![rule("^1.0.0", policy)],
+            };
+
+            assert_eq!(
+                rules.check("nep171", "3.0.0"),
+                RuleCheck::UnknownVersion(policy)
+            );
+            assert_eq!(
+                rules.check("nep171", "not a version"),
+                RuleCheck::UnknownVersion(policy)
+            );
+        }
+    }
+
+    #[test]
+    fn applies_the_strictest_policy_of_the_standard() {
+        let rules = EventRules {
+            standards: vec![
+                rule("^1.0.0", UnknownVersionPolicy::Skip),
+                rule("^2.0.0", UnknownVersionPolicy::Fail),
+                rule("^3.0.0", UnknownVersionPolicy::Decode),
+            ],
+        };
+
+        assert_eq!(
+            rules.check("nep171", "4.0.0"),
+            RuleCheck::UnknownVersion(UnknownVersionPolicy::Fail)
+        );
+    }
+}
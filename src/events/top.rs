@@ -0,0 +1,229 @@
+use crate::config::RestConfig;
+use crate::events::{self, ContractEvent, EventEnvelope};
+use crate::models::{BlockContext, ContractEventForRest, U128};
+use crate::{transactions, ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+use near_lake_framework::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Function call made to the top-level contract (`top_contract_id`). `battlemon_models` has no
+/// models for it, so calls are stored with their arguments as sent.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TopCallForRest {
+    pub contract_id: String,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    /// Position of the call among the receipt's actions.
+    pub action_index: usize,
+    pub predecessor_id: String,
+    pub method_name: String,
+    /// `None` when the arguments aren't JSON.
+    pub args: Option<Value>,
+    pub deposit: U128,
+    pub succeeded: bool,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+/// Calls made to the top contract by the receipt.
+pub fn top_calls(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block: BlockContext,
+) -> Vec<TopCallForRest> {
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return Vec::new(),
+    };
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    let succeeded = matches!(
+        outcome.execution_outcome.outcome.status,
+        ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+    );
+
+    actions
+        .iter()
+        .enumerate()
+        .filter_map(|(action_index, action)| match action {
+            ActionView::FunctionCall {
+                method_name,
+                args,
+                deposit,
+                ..
+            } => Some(TopCallForRest {
+                contract_id: outcome.receipt.receiver_id.to_string(),
+                receipt_id: outcome.receipt.receipt_id.to_string(),
+                transaction_hash: transaction_hash.clone(),
+                action_index,
+                predecessor_id: outcome.receipt.predecessor_id.to_string(),
+                method_name: method_name.clone(),
+                args: base64::decode(args)
+                    .ok()
+                    .and_then(|args| serde_json::from_slice(&args).ok()),
+                deposit: U128(*deposit),
+                succeeded,
+                block_height: block.height,
+                timestamp_ms: block.timestamp_ms(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Stores the calls made to the top contract and the NEP-297 events it emitted.
+#[tracing::instrument(
+    name = "Sending request to the rest service to store top contract calls and events",
+    skip(outcome, events, client, sink),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn handle_top_contract(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    events: Vec<ContractEvent<EventEnvelope>>,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let calls = top_calls(outcome, block);
+    if !calls.is_empty() {
+        post("top_calls", &calls, client.clone(), sink).await?;
+    }
+
+    if events.is_empty() {
+        return Ok(());
+    }
+    let transaction_hash = transactions::transaction_of(&outcome.receipt.receipt_id)
+        .map(|transaction| transaction.transaction_hash);
+    let events = events
+        .into_iter()
+        .map(|ContractEvent { envelope, .. }| ContractEventForRest {
+            contract_id: outcome.receipt.receiver_id.to_string(),
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            transaction_hash: transaction_hash.clone(),
+            block_height: block.height,
+            standard: envelope.standard,
+            version: envelope.version,
+            event: envelope.event,
+            data: envelope.data,
+        })
+        .collect::<Vec<_>>();
+
+    post("top_events", &events, client, sink).await
+}
+
+async fn post<T: Serialize>(
+    path: &str,
+    body: &T,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/{path}", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(body)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_lake_framework::near_indexer_primitives::CryptoHash;
+    use serde_json::json;
+
+    fn outcome(actions: Value, status: Value) -> IndexerExecutionOutcomeWithReceipt {
+        let receipt_id = CryptoHash::hash_bytes(b"receipt");
+        serde_json::from_value(json!({
+            "execution_outcome": {
+                "proof": [],
+                "block_hash": CryptoHash::hash_bytes(b"block"),
+                "id": receipt_id,
+                "outcome": {
+                    "logs": [],
+                    "receipt_ids": [],
+                    "gas_burnt": 0,
+                    "tokens_burnt": "0",
+                    "executor_id": "top.test",
+                    "status": status,
+                    "metadata": { "version": 1, "gas_profile": null },
+                },
+            },
+            "receipt": {
+                "predecessor_id": "alice.test",
+                "receiver_id": "top.test",
+                "receipt_id": receipt_id,
+                "receipt": {
+                    "Action": {
+                        "signer_id": "alice.test",
+                        "signer_public_key": "ed25519:11111111111111111111111111111111",
+                        "gas_price": "100000000",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": actions,
+                    },
+                },
+            },
+        }))
+        .expect("Test outcome is invalid")
+    }
+
+    fn function_call(method_name: &str, args: &[u8], deposit: u128) -> Value {
+        json!({
+            "FunctionCall": {
+                "method_name": method_name,
+                "args": base64::encode(args),
+                "gas": 30_000_000_000_000u64,
+                "deposit": deposit.to_string(),
+            }
+        })
+    }
+
+    fn block() -> BlockContext {
+        BlockContext {
+            height: 7,
+            timestamp_nanosec: 7_000_000,
+        }
+    }
+
+    #[test]
+    fn records_every_function_call_with_json_args() {
+        let outcome = outcome(
+            json!([
+                function_call("register", br#"{"account_id":"alice.test"}"#, 0),
+                { "Transfer": { "deposit": "5" } },
+                function_call("buy_lemon", b"not json", 10u128.pow(27)),
+            ]),
+            json!({ "SuccessValue": "" }),
+        );
+
+        let calls = top_calls(&outcome, block());
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method_name, "register");
+        assert_eq!(calls[0].args, Some(json!({ "account_id": "alice.test" })));
+        assert_eq!(calls[0].action_index, 0);
+        assert_eq!(calls[1].method_name, "buy_lemon");
+        assert_eq!(calls[1].args, None);
+        assert_eq!(calls[1].action_index, 2);
+        assert_eq!(calls[1].deposit, U128(10u128.pow(27)));
+        assert!(calls.iter().all(|call| call.succeeded));
+        assert_eq!(calls[0].predecessor_id, "alice.test");
+        assert_eq!(calls[0].timestamp_ms, 7);
+    }
+
+    #[test]
+    fn flags_failed_calls() {
+        let outcome = outcome(
+            json!([function_call("register", b"{}", 0)]),
+            json!({ "Failure": { "InvalidTxError": "InvalidSignature" } }),
+        );
+
+        let calls = top_calls(&outcome, block());
+
+        assert_eq!(calls.len(), 1);
+        assert!(!calls[0].succeeded);
+    }
+}
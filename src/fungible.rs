@@ -0,0 +1,286 @@
+use crate::config::RestConfig;
+use crate::consts::FT_LEDGER;
+use crate::events;
+use crate::get_config;
+use crate::models::U128;
+use crate::routing::get_router;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+const STATE: &str = "ft_ledger";
+
+/// Balances and supply of a NEP-141 token, as far as its events tell.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct FtContract {
+    pub total_supply: U128,
+    pub balances: HashMap<String, U128>,
+}
+
+impl FtContract {
+    pub fn balance(&self, account_id: &str) -> u128 {
+        self.balances.get(account_id).map_or(0, |b| b.0)
+    }
+
+    fn credit(&mut self, account_id: &str, amount: u128) {
+        let balance = self.balance(account_id).saturating_add(amount);
+        self.balances.insert(account_id.to_owned(), U128(balance));
+    }
+
+    /// Balances from before the indexer's start block are unknown, so an overdraft is
+    /// reported and the balance clamped at zero.
+    fn debit(&mut self, contract_id: &str, account_id: &str, amount: u128) {
+        let balance = self.balance(account_id);
+        let remaining = balance.checked_sub(amount).unwrap_or_else(|| {
+            tracing::warn!(
+                "`{account_id}` spent {amount} of `{contract_id}` with an indexed balance of \
+                {balance}, clamping it at zero"
+            );
+            0
+        });
+        if remaining == 0 {
+            self.balances.remove(account_id);
+        } else {
+            self.balances.insert(account_id.to_owned(), U128(remaining));
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FtBalanceForRest {
+    pub contract_id: String,
+    pub account_id: String,
+    pub balance: U128,
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FtSupplyForRest {
+    pub contract_id: String,
+    pub total_supply: U128,
+    pub holders: usize,
+    pub block_height: u64,
+}
+
+/// Balances of every indexed fungible token, saved to `ft_ledger.json` in the state dir.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FtLedger {
+    contracts: HashMap<String, FtContract>,
+    /// Last block whose events are included, so a block replayed after a restart isn't counted
+    /// twice.
+    block_height: Option<u64>,
+    #[serde(skip)]
+    changed: BTreeSet<(String, String)>,
+}
+
+impl FtLedger {
+    /// Returns `false` for blocks already included in the ledger.
+    pub fn accepts(&self, block_height: u64) -> bool {
+        self.block_height
+            .map_or(true, |height| block_height > height)
+    }
+
+    pub fn contract(&self, contract_id: &str) -> Option<&FtContract> {
+        self.contracts.get(contract_id)
+    }
+
+    /// Balances of the account in every token it holds.
+    pub fn account_balances(&self, account_id: &str) -> HashMap<&str, U128> {
+        self.contracts
+            .iter()
+            .filter_map(|(contract_id, contract)| {
+                let balance = *contract.balances.get(account_id)?;
+                Some((contract_id.as_str(), balance))
+            })
+            .collect()
+    }
+
+    fn touch(&mut self, contract_id: &str, account_id: &str) {
+        self.changed
+            .insert((contract_id.to_owned(), account_id.to_owned()));
+    }
+
+    pub fn mint(&mut self, contract_id: &str, owner_id: &str, amount: u128) {
+        let contract = self.contracts.entry(contract_id.to_owned()).or_default();
+        contract.credit(owner_id, amount);
+        contract.total_supply = U128(contract.total_supply.0.saturating_add(amount));
+        self.touch(contract_id, owner_id);
+    }
+
+    pub fn transfer(&mut self, contract_id: &str, from: &str, to: &str, amount: u128) {
+        let contract = self.contracts.entry(contract_id.to_owned()).or_default();
+        contract.debit(contract_id, from, amount);
+        contract.credit(to, amount);
+        self.touch(contract_id, from);
+        self.touch(contract_id, to);
+    }
+
+    pub fn burn(&mut self, contract_id: &str, owner_id: &str, amount: u128) {
+        let contract = self.contracts.entry(contract_id.to_owned()).or_default();
+        contract.debit(contract_id, owner_id, amount);
+        contract.total_supply = U128(contract.total_supply.0.saturating_sub(amount));
+        self.touch(contract_id, owner_id);
+    }
+
+    /// Balances and supplies changed since the last call, as of `block_height`.
+    fn take_changed(&mut self, block_height: u64) -> (Vec<FtBalanceForRest>, Vec<FtSupplyForRest>) {
+        let changed = std::mem::take(&mut self.changed);
+        let contract_ids = changed
+            .iter()
+            .map(|(contract_id, _)| contract_id.clone())
+            .collect::<BTreeSet<_>>();
+        let balances = changed
+            .into_iter()
+            .map(|(contract_id, account_id)| FtBalanceForRest {
+                balance: U128(self.contracts[&contract_id].balance(&account_id)),
+                contract_id,
+                account_id,
+                block_height,
+            })
+            .collect();
+        let supplies = contract_ids
+            .into_iter()
+            .map(|contract_id| {
+                let contract = &self.contracts[&contract_id];
+                FtSupplyForRest {
+                    total_supply: contract.total_supply,
+                    holders: contract.balances.len(),
+                    contract_id,
+                    block_height,
+                }
+            })
+            .collect();
+
+        (balances, supplies)
+    }
+}
+
+#[tracing::instrument(name = "Getting fungible token ledger")]
+pub async fn get_ft_ledger() -> &'static Mutex<FtLedger> {
+    FT_LEDGER
+        .get_or_init(|| async {
+            let ledger = get_config()
+                .await
+                .store()
+                .load(STATE)
+                .expect("Couldn't load fungible token ledger");
+            Mutex::new(ledger)
+        })
+        .await
+}
+
+/// Sends the balances and supplies that changed during the block to the rest service and
+/// saves the ledger.
+#[tracing::instrument(name = "Publishing fungible token balances", skip(client))]
+pub async fn publish(block_height: u64, client: web::Data<reqwest::Client>) -> anyhow::Result<()> {
+    let (balances, supplies, ledger) = {
+        let mut ledger = get_ft_ledger()
+            .await
+            .lock()
+            .expect("Fungible token ledger lock poisoned");
+        if ledger.changed.is_empty() {
+            return Ok(());
+        }
+        let (balances, supplies) = ledger.take_changed(block_height);
+        ledger.block_height = Some(block_height);
+        (balances, supplies, ledger.clone())
+    };
+
+    let config = get_config().await;
+    config.store().save(STATE, &ledger)?;
+    let router = get_router().await.read().await;
+    for supply in supplies {
+        let sink = router
+            .route(&supply.contract_id)
+            .map_or(&config.rest, |route| &route.sink);
+        let balances = balances
+            .iter()
+            .filter(|b| b.contract_id == supply.contract_id)
+            .collect::<Vec<_>>();
+        post("ft_balances", &balances, client.clone(), sink).await?;
+        post("ft_supplies", &[supply], client.clone(), sink).await?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store fungible token state",
+    skip(body, client, sink)
+)]
+async fn post<T: Serialize>(
+    path: &str,
+    body: &[T],
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/{path}", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(body)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_moves_balance_and_keeps_supply() {
+        let mut ledger = FtLedger::default();
+        ledger.mint("token.near", "alice.near", 100);
+        ledger.transfer("token.near", "alice.near", "bob.near", 100);
+
+        let contract = ledger.contract("token.near").unwrap();
+        assert_eq!(contract.total_supply.0, 100);
+        assert_eq!(contract.balance("bob.near"), 100);
+        assert!(!contract.balances.contains_key("alice.near"));
+    }
+
+    #[test]
+    fn overdraft_clamps_balance_at_zero() {
+        let mut ledger = FtLedger::default();
+        ledger.mint("token.near", "alice.near", 5);
+        ledger.transfer("token.near", "alice.near", "bob.near", 8);
+        ledger.burn("token.near", "carol.near", 1);
+
+        let contract = ledger.contract("token.near").unwrap();
+        assert_eq!(contract.balance("alice.near"), 0);
+        assert_eq!(contract.balance("bob.near"), 8);
+        assert_eq!(contract.total_supply.0, 4);
+    }
+
+    #[test]
+    fn takes_each_changed_balance_once() {
+        let mut ledger = FtLedger::default();
+        ledger.mint("token.near", "alice.near", 10);
+        ledger.transfer("token.near", "alice.near", "bob.near", 4);
+
+        let (balances, supplies) = ledger.take_changed(7);
+        let balances = balances
+            .iter()
+            .map(|b| (b.account_id.as_str(), b.balance.0))
+            .collect::<Vec<_>>();
+        assert_eq!(balances, [("alice.near", 6), ("bob.near", 4)]);
+        assert_eq!(supplies.len(), 1);
+        assert_eq!(supplies[0].holders, 2);
+        assert!(ledger.take_changed(8).0.is_empty());
+    }
+
+    #[test]
+    fn accepts_only_blocks_after_the_last_one() {
+        let ledger = FtLedger {
+            block_height: Some(10),
+            ..Default::default()
+        };
+
+        assert!(!ledger.accepts(10));
+        assert!(ledger.accepts(11));
+        assert!(FtLedger::default().accepts(0));
+    }
+}
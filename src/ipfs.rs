@@ -0,0 +1,451 @@
+use crate::config::RestConfig;
+use crate::consts::IPFS;
+use crate::events::{self, nft::NftChange};
+use crate::models::IpfsHash;
+use crate::search;
+use crate::store::JsonStore;
+use crate::{get_config, metrics};
+use anyhow::{anyhow, ensure, Context};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+const CACHE: &str = "ipfs_cache";
+
+#[derive(Deserialize, Clone)]
+pub struct IpfsConfig {
+    #[serde(default = "default_gateway_url")]
+    pub gateway_url: String,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Attempts after the first one failed, waiting twice as long before each.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// Larger responses are dropped, e.g. when a token's `media` points to an image or video.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// References waiting to be resolved, further ones are dropped until there's room.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Default for IpfsConfig {
+    fn default() -> Self {
+        Self {
+            gateway_url: default_gateway_url(),
+            timeout_ms: default_timeout_ms(),
+            max_retries: default_max_retries(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            max_body_bytes: default_max_body_bytes(),
+            concurrency: default_concurrency(),
+            queue_size: default_queue_size(),
+        }
+    }
+}
+
+fn default_gateway_url() -> String {
+    "https://ipfs.io/ipfs".to_owned()
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
+/// Token whose metadata reference is waiting to be resolved.
+pub struct MetadataJob {
+    pub contract_id: String,
+    pub token_id: String,
+    pub reference: IpfsHash,
+    pub sink: RestConfig,
+}
+
+/// Failed fetch, `Permanent` when asking again would give the same answer.
+enum FetchError {
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedMetadata {
+    reference: IpfsHash,
+    metadata: Value,
+}
+
+/// Metadata fetched from IPFS, attached to the stored token.
+#[derive(Serialize, Debug, Clone)]
+pub struct TokenMetadataForRest {
+    pub contract_id: String,
+    pub token_id: String,
+    pub reference: String,
+    pub attributes: Value,
+    pub metadata: Value,
+}
+
+/// Fetches metadata through the gateway. Content behind a CID never changes, so everything
+/// fetched is cached for good in `ipfs_cache.jsonl` in the state dir.
+pub struct IpfsResolver {
+    client: reqwest::Client,
+    config: IpfsConfig,
+    store: JsonStore,
+    cache: Mutex<HashMap<IpfsHash, Value>>,
+}
+
+impl IpfsResolver {
+    pub fn new(config: IpfsConfig, store: JsonStore) -> anyhow::Result<Self> {
+        let cache = store
+            .load_lines::<CachedMetadata>(CACHE)?
+            .into_iter()
+            .map(|cached| (cached.reference, cached.metadata))
+            .collect();
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            config,
+            store,
+            cache: Mutex::new(cache),
+        })
+    }
+
+    #[tracing::instrument(name = "Resolving IPFS metadata", skip(self))]
+    pub async fn resolve(&self, reference: &IpfsHash) -> anyhow::Result<Value> {
+        let cached = self
+            .cache
+            .lock()
+            .expect("IPFS cache lock poisoned")
+            .get(reference)
+            .cloned();
+        if let Some(metadata) = cached {
+            metrics::IPFS_RESOLUTIONS
+                .with_label_values(&["cached"])
+                .inc();
+            return Ok(metadata);
+        }
+
+        let bytes = self.fetch(reference).await?;
+        let metadata: Value =
+            serde_json::from_slice(&bytes).context("Token metadata is not valid JSON")?;
+        ensure!(metadata.is_object(), "Token metadata is not a JSON object");
+
+        self.store.append(
+            CACHE,
+            &CachedMetadata {
+                reference: reference.clone(),
+                metadata: metadata.clone(),
+            },
+        )?;
+        self.cache
+            .lock()
+            .expect("IPFS cache lock poisoned")
+            .insert(reference.clone(), metadata.clone());
+        metrics::IPFS_RESOLUTIONS
+            .with_label_values(&["fetched"])
+            .inc();
+
+        Ok(metadata)
+    }
+
+    async fn fetch(&self, reference: &IpfsHash) -> anyhow::Result<Vec<u8>> {
+        let url = format!(
+            "{}/{reference}",
+            self.config.gateway_url.trim_end_matches('/')
+        );
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            match self.fetch_once(&url).await {
+                Ok(bytes) => return Ok(bytes),
+                Err(FetchError::Transient(e)) if attempt < self.config.max_retries => {
+                    tracing::warn!("Failed to fetch {url}, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(FetchError::Transient(e) | FetchError::Permanent(e)) => {
+                    return Err(e).with_context(|| format!("Failed to fetch {url}"))
+                }
+            }
+        }
+    }
+
+    /// Client errors, e.g. 404 for a CID the gateway can't find, and bodies over
+    /// `max_body_bytes` aren't worth retrying.
+    async fn fetch_once(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        let mut response = self
+            .client
+            .get(url)
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await
+            .map_err(|e| FetchError::Transient(e.into()))?;
+
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(FetchError::Permanent(anyhow!(
+                "Gateway responded with {status}"
+            )));
+        }
+        if !status.is_success() {
+            return Err(FetchError::Transient(anyhow!(
+                "Gateway responded with {status}"
+            )));
+        }
+
+        let max_body_bytes = self.config.max_body_bytes;
+        let too_large =
+            || FetchError::Permanent(anyhow!("Response is larger than {max_body_bytes} bytes"));
+        if response
+            .content_length()
+            .map_or(false, |length| length > max_body_bytes as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| FetchError::Transient(e.into()))?
+        {
+            if body.len() + chunk.len() > max_body_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+
+    #[tracing::instrument(
+        name = "Attaching IPFS metadata to token",
+        skip(self, job),
+        fields(token_id = %job.token_id)
+    )]
+    async fn enrich(&self, job: MetadataJob) -> anyhow::Result<()> {
+        let metadata = self.resolve(&job.reference).await?;
+        search::observe_metadata(&job.contract_id, &job.token_id, &metadata).await;
+        let json = TokenMetadataForRest {
+            contract_id: job.contract_id,
+            token_id: job.token_id,
+            reference: job.reference.to_string(),
+            attributes: metadata.get("attributes").cloned().unwrap_or(Value::Null),
+            metadata,
+        };
+
+        let response = self
+            .client
+            .patch(format!("{}/nft_tokens/metadata", job.sink.base_url()))
+            .header("Content-Type", "application/json")
+            .basic_auth(job.sink.username(), Some(job.sink.password()))
+            .json(&json)
+            .send()
+            .await?;
+
+        events::handle_response_for_error(response).await
+    }
+}
+
+/// IPFS reference in a token's metadata: its `reference`, or its `media` as a fallback.
+pub fn metadata_reference(token: &Value) -> Option<IpfsHash> {
+    let metadata = token.get("metadata").unwrap_or(token);
+    ["reference", "media"]
+        .iter()
+        .filter_map(|field| metadata.get(field)?.as_str())
+        .find_map(IpfsHash::parse)
+}
+
+/// Starts the background resolver on first use and returns its queue.
+#[tracing::instrument(name = "Getting IPFS resolver queue")]
+pub async fn get_queue() -> &'static mpsc::Sender<MetadataJob> {
+    IPFS.get_or_init(|| async {
+        let config = get_config().await;
+        let resolver = IpfsResolver::new(config.ipfs.clone(), config.store())
+            .expect("Couldn't load IPFS cache");
+        let (sender, receiver) = mpsc::channel(config.ipfs.queue_size);
+        tokio::spawn(run(Arc::new(resolver), receiver));
+        sender
+    })
+    .await
+}
+
+async fn run(resolver: Arc<IpfsResolver>, receiver: mpsc::Receiver<MetadataJob>) {
+    ReceiverStream::new(receiver)
+        .for_each_concurrent(resolver.config.concurrency, |job| {
+            let resolver = resolver.clone();
+            async move {
+                if let Err(e) = resolver.enrich(job).await {
+                    metrics::IPFS_RESOLUTIONS
+                        .with_label_values(&["failed"])
+                        .inc();
+                    tracing::error!("Failed to enrich token with IPFS metadata: {:?}", e);
+                }
+            }
+        })
+        .await;
+}
+
+/// Queues the IPFS references of the tokens an event produced without waiting for them to be
+/// resolved.
+pub async fn enqueue(contract_id: &str, change: &NftChange, sink: &RestConfig) {
+    let queue = get_queue().await;
+    for token in change.tokens() {
+        let reference = match serde_json::to_value(token)
+            .ok()
+            .and_then(|token| metadata_reference(&token))
+        {
+            Some(reference) => reference,
+            None => continue,
+        };
+
+        let job = MetadataJob {
+            contract_id: contract_id.to_owned(),
+            token_id: token.token_id.clone(),
+            reference,
+            sink: sink.clone(),
+        };
+        if queue.try_send(job).is_err() {
+            metrics::IPFS_RESOLUTIONS
+                .with_label_values(&["dropped"])
+                .inc();
+            tracing::warn!(
+                "IPFS resolver queue is full, dropping metadata of token {}",
+                token.token_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Reply};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Local stand-in for a gateway. `respond` gives the response to each attempt, counted
+    /// from zero.
+    fn gateway(respond: fn(usize) -> Reply) -> (String, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let url = testing::serve(move |_| respond(counter.fetch_add(1, Ordering::SeqCst)));
+
+        (format!("{url}/ipfs"), attempts)
+    }
+
+    fn metadata(status: u16) -> Reply {
+        Reply::json(status, &json!({ "name": "Token", "attributes": [] }))
+    }
+
+    fn resolver(name: &str, gateway_url: String, max_retries: u32) -> IpfsResolver {
+        let config = IpfsConfig {
+            gateway_url,
+            timeout_ms: 200,
+            max_retries,
+            retry_backoff_ms: 10,
+            max_body_bytes: 1024,
+            ..Default::default()
+        };
+        let dir = testing::state_dir(&format!("ipfs_{name}"));
+        IpfsResolver::new(config, JsonStore::new(dir)).unwrap()
+    }
+
+    fn reference() -> IpfsHash {
+        IpfsHash {
+            hash: "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG".to_owned(),
+            path: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_after_server_errors() {
+        let (url, attempts) = gateway(|attempt| metadata(if attempt < 2 { 502 } else { 200 }));
+        let resolver = resolver("errors", url, 3);
+
+        let metadata = resolver.resolve(&reference()).await.unwrap();
+
+        assert_eq!(metadata["name"], "Token");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_after_timeout() {
+        let (url, attempts) = gateway(|attempt| {
+            let delay_ms = if attempt == 0 { 1000 } else { 0 };
+            metadata(200).delayed(delay_ms)
+        });
+        let resolver = resolver("timeout", url, 1);
+
+        assert!(resolver.resolve(&reference()).await.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let (url, attempts) = gateway(|_| metadata(504));
+        let resolver = resolver("give_up", url, 2);
+
+        assert!(resolver.resolve(&reference()).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (url, attempts) = gateway(|_| metadata(404));
+        let resolver = resolver("not_found", url, 2);
+
+        assert!(resolver.resolve(&reference()).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_responses_over_the_size_limit() {
+        let (url, attempts) = gateway(|_| Reply::bytes(200, vec![b' '; 2048]));
+        let resolver = resolver("too_large", url, 2);
+
+        let error = resolver.resolve(&reference()).await.unwrap_err();
+
+        assert!(format!("{error:#}").contains("larger than 1024 bytes"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn serves_resolved_metadata_from_cache() {
+        let (url, attempts) = gateway(|_| metadata(200));
+        let resolver = resolver("cache", url, 0);
+        resolver.resolve(&reference()).await.unwrap();
+        resolver.resolve(&reference()).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let reloaded = IpfsResolver::new(resolver.config.clone(), resolver.store.clone()).unwrap();
+        reloaded.resolve(&reference()).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,18 +1,47 @@
 use self::config::get_config;
 use actix_web::web;
-use consts::EVENT_PREFIX;
-use events::{market, nft};
+use consts::{EVENT_PREFIX, TRANSACTIONS};
+use events::{ft, generic, market, nft, top};
 use futures::try_join;
+use models::BlockContext;
 use near_lake_framework::near_indexer_primitives::{
     views::ExecutionStatusView, IndexerExecutionOutcomeWithReceipt, IndexerShard, StreamerMessage,
 };
+use routing::{get_router, HandlerKind};
 
+pub mod activity;
+pub mod analytics;
+pub mod api;
+pub mod bootstrap;
+pub mod checkpoint;
+pub mod composition;
 pub mod config;
 pub mod consts;
+pub mod contract_metadata;
+pub mod deposits;
+pub mod discovery;
 pub mod events;
+pub mod fungible;
+pub mod ipfs;
+pub mod listings;
+pub mod metrics;
 pub mod models;
+pub mod pricing;
+pub mod provenance;
+pub mod reconcile;
+pub mod routing;
+pub mod rpc;
+pub mod search;
+pub mod snapshot;
 pub mod startup;
+pub mod store;
 pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod trades;
+pub mod traits;
+pub mod transactions;
+pub mod upgrades;
 
 #[tracing::instrument(
     name = "Handling streamer message",
@@ -23,20 +52,42 @@ async fn handle_message(
     streamer_message: StreamerMessage,
     client: web::Data<reqwest::Client>,
 ) -> anyhow::Result<()> {
+    {
+        let router = get_router().await.read().await;
+        TRANSACTIONS
+            .lock()
+            .expect("Transactions lock poisoned")
+            .index_block(&streamer_message, |account_id| {
+                router.route(account_id).is_some()
+            });
+    }
+
+    let block = BlockContext {
+        height: streamer_message.block.header.height,
+        timestamp_nanosec: streamer_message.block.header.timestamp_nanosec,
+    };
     let events = async {
         for shard in &streamer_message.shards {
-            collect_and_store_contracts_events(
-                shard,
-                // &streamer_message.block.header.height,
-                client.clone(),
-            )
-            .await?;
+            collect_and_store_contracts_events(shard, block, client.clone()).await?;
         }
 
         Ok::<(), anyhow::Error>(())
     };
 
     try_join!(events)?;
+    let store = get_config().await.store();
+    trades::expire_pending_trades(block.height, client.clone()).await?;
+    trades::persist(&store)?;
+    listings::persist(block.height).await?;
+    analytics::publish(block, client.clone()).await?;
+    traits::publish(block.height, client.clone()).await?;
+    search::persist(block.height).await?;
+    fungible::publish(block.height, client.clone()).await?;
+    transactions::persist(&store, block.height).await?;
+    deposits::persist(&store, block.height)?;
+    events::persist_unknown_counts(&store)?;
+    checkpoint::save(&store, block.height)?;
+    reconcile::schedule(block.height, client).await;
 
     Ok(())
 }
@@ -47,24 +98,105 @@ async fn handle_message(
 )]
 async fn collect_and_store_contracts_events(
     shard: &IndexerShard,
-    // _block_height: &u64,
+    block: BlockContext,
     client: web::Data<reqwest::Client>,
 ) -> anyhow::Result<()> {
     // let mut index_in_shard: i32 = 0;
-    let (_, nft, market) = get_config().await.contracts.ids();
-    for outcome in &shard.receipt_execution_outcomes {
-        match outcome.receipt.receiver_id.as_ref() {
-            id if id == nft.as_ref() => {
+    let config = get_config().await;
+    let router = get_router().await;
+    for (position, outcome) in shard.receipt_execution_outcomes.iter().enumerate() {
+        discovery::discover_contracts(outcome, block.height).await?;
+        upgrades::observe_receipt(outcome, block.height, position, client.clone()).await?;
+        contract_metadata::observe_receipt(outcome, block.height, client.clone()).await?;
+        deposits::observe_refund(outcome, block, client.clone()).await?;
+        let receiver_id = outcome.receipt.receiver_id.as_ref();
+        let route = match router.read().await.route(receiver_id) {
+            Some(route) => route.clone(),
+            None => continue,
+        };
+        let version = upgrades::version_at(receiver_id, block.height, position).await;
+        let handler = version.map_or(route.handler, |v| v.handler);
+        let rules = version
+            .and_then(|v| v.event_rules.as_ref())
+            .unwrap_or_else(|| config.event_rules(receiver_id));
+        let legacy = version.and_then(|v| v.legacy.as_ref());
+        match handler {
+            HandlerKind::Generic => {
+                tracing::info!("Handle contract events");
+                let contract_events =
+                    events::collect_contract_events(outcome, rules, legacy, block.height)?;
+                events::store_unknown_events(&contract_events.unknown, client.clone(), &route.sink)
+                    .await?;
+                generic::handle_generic_events(
+                    outcome,
+                    contract_events.events,
+                    block,
+                    client.clone(),
+                    &route.sink,
+                )
+                .await?;
+            }
+            HandlerKind::Nft => {
                 tracing::info!("Handle NFT events");
-                let nft_events = events::collect_contract_events(outcome);
-                nft::handle_nft_events(outcome, nft_events, client.clone()).await?;
+                let nft_events =
+                    events::collect_contract_events(outcome, rules, legacy, block.height)?;
+                events::store_unknown_events(&nft_events.unknown, client.clone(), &route.sink)
+                    .await?;
+                nft::handle_nft_events(
+                    outcome,
+                    nft_events.events,
+                    legacy,
+                    block,
+                    client.clone(),
+                    &route.sink,
+                )
+                .await?;
             }
-            id if id == market.as_ref() => {
+            HandlerKind::Market => {
                 tracing::info!("Handle Market events");
-                let market_events = events::collect_contract_events(outcome);
-                market::handle_market_events(outcome, market_events, client.clone()).await?;
+                let market_events =
+                    events::collect_contract_events(outcome, rules, legacy, block.height)?;
+                events::store_unknown_events(&market_events.unknown, client.clone(), &route.sink)
+                    .await?;
+                market::handle_market_events(
+                    outcome,
+                    market_events.events,
+                    block,
+                    client.clone(),
+                    &route.sink,
+                )
+                .await?;
+            }
+            HandlerKind::Ft => {
+                tracing::info!("Handle FT events");
+                let ft_events =
+                    events::collect_contract_events(outcome, rules, legacy, block.height)?;
+                events::store_unknown_events(&ft_events.unknown, client.clone(), &route.sink)
+                    .await?;
+                ft::handle_ft_events(
+                    outcome,
+                    ft_events.events,
+                    block,
+                    client.clone(),
+                    &route.sink,
+                )
+                .await?;
+            }
+            HandlerKind::Top => {
+                tracing::info!("Handle top contract calls and events");
+                let top_events =
+                    events::collect_contract_events(outcome, rules, legacy, block.height)?;
+                events::store_unknown_events(&top_events.unknown, client.clone(), &route.sink)
+                    .await?;
+                top::handle_top_contract(
+                    outcome,
+                    top_events.events,
+                    block,
+                    client.clone(),
+                    &route.sink,
+                )
+                .await?;
             }
-            _ => continue,
         }
     }
 
@@ -0,0 +1,248 @@
+use crate::consts::LISTINGS;
+use crate::get_config;
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const STATE: &str = "listings";
+
+/// How long filled and cancelled listings are kept to recognise events that follow them.
+const CLOSED_TTL_BLOCKS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingKind {
+    Ask,
+    Bid,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ListingKey {
+    pub kind: ListingKind,
+    pub market_contract_id: String,
+    pub token_id: String,
+    pub account_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingState {
+    Open,
+    Cancelled,
+    Filled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Listing {
+    pub state: ListingState,
+    pub block_height: u64,
+    /// What the listing was stored in the rest service as, to remove it with when
+    /// reconciliation finds it gone from the market.
+    #[serde(default)]
+    pub record: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Opened,
+    /// An open listing was added again, e.g. with a new price.
+    Replaced,
+    Cancelled,
+    Filled,
+    /// The contract removed a listing that was already filled by a sale.
+    RemovedAfterFill,
+    Invalid(InvalidTransition),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidTransition {
+    RemoveMissing,
+    RemoveClosed,
+    FillMissing,
+}
+
+impl InvalidTransition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RemoveMissing => "remove_missing",
+            Self::RemoveClosed => "remove_closed",
+            Self::FillMissing => "fill_missing",
+        }
+    }
+}
+
+/// Lifecycle of every ask and bid the indexer has seen.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(
+    from = "Vec<(ListingKey, Listing)>",
+    into = "Vec<(ListingKey, Listing)>"
+)]
+pub struct ListingBook {
+    listings: HashMap<ListingKey, Listing>,
+    dirty: bool,
+}
+
+impl From<Vec<(ListingKey, Listing)>> for ListingBook {
+    fn from(listings: Vec<(ListingKey, Listing)>) -> Self {
+        Self {
+            listings: listings.into_iter().collect(),
+            dirty: false,
+        }
+    }
+}
+
+impl From<ListingBook> for Vec<(ListingKey, Listing)> {
+    fn from(book: ListingBook) -> Self {
+        book.listings.into_iter().collect()
+    }
+}
+
+impl ListingBook {
+    fn set(&mut self, key: ListingKey, state: ListingState, block_height: u64) -> &mut Listing {
+        self.dirty = true;
+        let listing = self.listings.entry(key).or_insert(Listing {
+            state,
+            block_height,
+            record: None,
+        });
+        listing.state = state;
+        listing.block_height = block_height;
+
+        listing
+    }
+
+    pub fn get(&self, key: &ListingKey) -> Option<&Listing> {
+        self.listings.get(key)
+    }
+
+    pub fn open_listings(&self) -> impl Iterator<Item = &ListingKey> {
+        self.listings
+            .iter()
+            .filter(|(_, listing)| listing.state == ListingState::Open)
+            .map(|(key, _)| key)
+    }
+
+    pub fn add(&mut self, key: ListingKey, record: Value, block_height: u64) -> Transition {
+        let transition = match self.get(&key).map(|l| l.state) {
+            Some(ListingState::Open) => Transition::Replaced,
+            _ => Transition::Opened,
+        };
+        self.set(key, ListingState::Open, block_height).record = Some(record);
+
+        transition
+    }
+
+    pub fn remove(&mut self, key: ListingKey, block_height: u64) -> Transition {
+        match self.get(&key).map(|l| l.state) {
+            Some(ListingState::Open) => {
+                self.set(key, ListingState::Cancelled, block_height);
+                Transition::Cancelled
+            }
+            Some(ListingState::Filled) => Transition::RemovedAfterFill,
+            Some(ListingState::Cancelled) => Transition::Invalid(InvalidTransition::RemoveClosed),
+            None => Transition::Invalid(InvalidTransition::RemoveMissing),
+        }
+    }
+
+    pub fn fill(&mut self, key: ListingKey, block_height: u64) -> Transition {
+        match self.get(&key).map(|l| l.state) {
+            Some(ListingState::Open) => {
+                self.set(key, ListingState::Filled, block_height);
+                Transition::Filled
+            }
+            _ => Transition::Invalid(InvalidTransition::FillMissing),
+        }
+    }
+
+    /// Forgets closed listings once nothing is expected to refer to them anymore.
+    pub fn prune(&mut self, block_height: u64) {
+        let len = self.listings.len();
+        self.listings.retain(|_, listing| {
+            listing.state == ListingState::Open
+                || listing.block_height + CLOSED_TTL_BLOCKS >= block_height
+        });
+        self.dirty |= len != self.listings.len();
+    }
+}
+
+/// Counts an invalid transition so it shows up in the metrics.
+pub fn report_invalid(key: &ListingKey, invalid: InvalidTransition) {
+    let kind = match key.kind {
+        ListingKind::Ask => "ask",
+        ListingKind::Bid => "bid",
+    };
+    metrics::INVALID_LISTING_TRANSITIONS
+        .with_label_values(&[key.market_contract_id.as_str(), kind, invalid.as_str()])
+        .inc();
+    tracing::warn!("Invalid listing transition {:?} for {:?}", invalid, key);
+}
+
+#[tracing::instrument(name = "Getting listings")]
+pub async fn get_listings() -> &'static Mutex<ListingBook> {
+    LISTINGS
+        .get_or_init(|| async {
+            let book = get_config()
+                .await
+                .store()
+                .load(STATE)
+                .expect("Couldn't load listings");
+            Mutex::new(book)
+        })
+        .await
+}
+
+/// Prunes closed listings and saves the book if it changed during the block.
+#[tracing::instrument(name = "Persisting listings")]
+pub async fn persist(block_height: u64) -> anyhow::Result<()> {
+    let book = {
+        let mut book = get_listings().await.lock().expect("Listings lock poisoned");
+        book.prune(block_height);
+        if !book.dirty {
+            return Ok(());
+        }
+        book.dirty = false;
+        book.clone()
+    };
+
+    get_config().await.store().save(STATE, &book)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key() -> ListingKey {
+        ListingKey {
+            kind: ListingKind::Ask,
+            market_contract_id: "market.near".to_owned(),
+            token_id: "token-1".to_owned(),
+            account_id: "alice.near".to_owned(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_record_of_closed_listings() {
+        let mut book = ListingBook::default();
+        let record = json!({ "token_id": "token-1", "price": "1" });
+        assert_eq!(book.add(key(), record.clone(), 1), Transition::Opened);
+        assert_eq!(book.remove(key(), 2), Transition::Cancelled);
+
+        let listing = book.get(&key()).unwrap();
+        assert_eq!(listing.state, ListingState::Cancelled);
+        assert_eq!(listing.block_height, 2);
+        assert_eq!(listing.record, Some(record));
+    }
+
+    #[test]
+    fn flags_removal_of_missing_listing() {
+        let mut book = ListingBook::default();
+        assert_eq!(
+            book.remove(key(), 1),
+            Transition::Invalid(InvalidTransition::RemoveMissing)
+        );
+        assert!(book.get(&key()).is_none());
+    }
+}
@@ -1,26 +1,54 @@
 use anyhow::Context;
-use battlemon_indexer::config::{get_config, AppConfig};
-use battlemon_indexer::{startup, telemetry};
+use battlemon_indexer::config::{get_config, AppConfig, BlockSourceConfig};
+use battlemon_indexer::contract_metadata;
+use battlemon_indexer::rpc::{self, RpcClient};
+use battlemon_indexer::{api, bootstrap, snapshot, startup, telemetry};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let subscriber = telemetry::get_subscriber("battlemon_indexer".into(), "info".into());
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        // Commands write their output to stdout, so logs go to stderr.
+        let subscriber =
+            telemetry::get_subscriber("battlemon_indexer".into(), "info".into(), std::io::stderr);
+        telemetry::init_subscriber(subscriber);
+        return match command.as_str() {
+            "bootstrap" => bootstrap::run(args).await,
+            "snapshot" => snapshot::run(args).await,
+            other => Err(anyhow::anyhow!("Unknown command `{other}`")),
+        };
+    }
+
+    let subscriber =
+        telemetry::get_subscriber("battlemon_indexer".into(), "info".into(), std::io::stdout);
     telemetry::init_subscriber(subscriber);
     let config = get_config().await;
-    tracing::info!("Loading configuration for NEAR Lake Framework");
-    let lake_config = config.near_lake.near_lake_config().await?;
     let client = reqwest::Client::new();
-    tracing::info!("Starting up NEAR Lake Framework");
-    let stream = near_lake_framework::streamer(lake_config).1;
+    let start_block_height = config.start_block_height().await?;
+    let stream = match &config.block_source {
+        BlockSourceConfig::Lake => {
+            tracing::info!("Loading configuration for NEAR Lake Framework");
+            let lake_config = config.near_lake.near_lake_config(start_block_height)?;
+            tracing::info!("Starting up NEAR Lake Framework");
+            near_lake_framework::streamer(lake_config).1
+        }
+        BlockSourceConfig::Rpc(rpc_config) => {
+            let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+            tracing::info!("Starting up JSON-RPC block polling at {}", rpc_client.url());
+            rpc::streamer::streamer(rpc_client, rpc_config.clone(), start_block_height).1
+        }
+    };
     upsert_contract_ids(&config, &client).await?;
-    startup::run_indexer(stream, client)
-        .await
-        .expect("Couldn't run indexer");
+    let api = api::run_api(&config.api)?;
+    tokio::select! {
+        result = startup::run_indexer(stream, client) => result.expect("Couldn't run indexer"),
+        result = api => result.expect("Couldn't run indexer API"),
+    }
     Ok(())
 }
 
 #[tracing::instrument(
-    name = "Update info about Battlemon's contracts ids",
+    name = "Update info about Battlemon's contracts ids and metadata",
     skip(config, http_client)
 )]
 async fn upsert_contract_ids(
@@ -36,6 +64,7 @@ async fn upsert_contract_ids(
         .context(
             "Failed to make request to rest service for updating info about actual contract's id",
         )?;
+    contract_metadata::refresh_all(config, http_client).await;
 
     Ok(())
 }
@@ -0,0 +1,38 @@
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+pub static UNKNOWN_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "battlemon_indexer_unknown_events_total",
+        "Contract logs that couldn't be decoded into a known event",
+        &["contract_id", "reason"]
+    )
+    .expect("Couldn't register `unknown_events` metric")
+});
+
+pub static INVALID_LISTING_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "battlemon_indexer_invalid_listing_transitions_total",
+        "Market events that don't fit the lifecycle of the listing they refer to",
+        &["contract_id", "kind", "transition"]
+    )
+    .expect("Couldn't register `invalid_listing_transitions` metric")
+});
+
+pub static IPFS_RESOLUTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "battlemon_indexer_ipfs_resolutions_total",
+        "Token metadata references resolved through the IPFS gateway, by outcome",
+        &["result"]
+    )
+    .expect("Couldn't register `ipfs_resolutions` metric")
+});
+
+pub static RECONCILIATION_MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "battlemon_indexer_reconciliation_mismatches_total",
+        "Differences between indexed state and contract view calls found by reconciliation",
+        &["kind"]
+    )
+    .expect("Couldn't register `reconciliation_mismatches` metric")
+});
@@ -1,4 +1,137 @@
-#[derive(serde::Deserialize)]
+/// Block the events being handled were emitted in.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockContext {
+    pub height: u64,
+    pub timestamp_nanosec: u64,
+}
+
+impl BlockContext {
+    pub fn timestamp_ms(&self) -> u64 {
+        self.timestamp_nanosec / 1_000_000
+    }
+}
+
+/// Token amount, serialized as a decimal string like NEAR's JSON `U128`, since it doesn't fit
+/// in a JSON number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U128(pub u128);
+
+impl serde::Serialize for U128 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for U128 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let amount = String::deserialize(deserializer)?;
+        amount
+            .parse()
+            .map(Self)
+            .map_err(|_| serde::de::Error::custom(format!("Invalid amount `{amount}`")))
+    }
+}
+
+/// IPFS content id, with the path inside it when the reference points into a directory.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct IpfsHash {
     pub hash: String,
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+impl IpfsHash {
+    /// Accepts `ipfs://<cid>/<path>`, gateway URLs containing `/ipfs/<cid>/<path>` and bare
+    /// CIDs.
+    pub fn parse(reference: &str) -> Option<Self> {
+        let reference = reference.trim();
+        let rest = match reference.strip_prefix("ipfs://") {
+            Some(rest) => rest,
+            None => match reference.find("/ipfs/") {
+                Some(index) => &reference[index + "/ipfs/".len()..],
+                None => reference,
+            },
+        };
+        let (hash, path) = match rest.split_once('/') {
+            Some((hash, path)) if !path.is_empty() => (hash, Some(path.to_owned())),
+            Some((hash, _)) => (hash, None),
+            None => (rest, None),
+        };
+
+        // CIDv0 is base58 `Qm...`, CIDv1 is usually base32 `b...`.
+        let is_cid = (hash.len() == 46 && hash.starts_with("Qm"))
+            || (hash.len() > 46 && hash.starts_with('b'));
+        if !is_cid || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+
+        Some(Self {
+            hash: hash.to_owned(),
+            path,
+        })
+    }
+}
+
+impl std::fmt::Display for IpfsHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}/{}", self.hash, path),
+            None => write!(f, "{}", self.hash),
+        }
+    }
+}
+
+/// NEP-297 event of a contract the indexer has no dedicated models for.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ContractEventForRest {
+    pub contract_id: String,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub block_height: u64,
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    pub data: Option<serde_json::Value>,
+}
+
+/// Ask or bid closed by a sale instead of being removed by its owner.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ListingFillForRest {
+    pub market_contract_id: String,
+    pub token_id: String,
+    pub account_id: String,
+    pub block_height: u64,
+    pub sale: battlemon_models::market::sale::SaleForRest,
+}
+
+/// Raw contract log the indexer couldn't turn into a known event, kept for reprocessing.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct UnknownEvent {
+    pub contract_id: String,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub block_height: u64,
+    pub reason: UnknownEventReason,
+    pub error: Option<String>,
+    pub log: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownEventReason {
+    InvalidEnvelope,
+    UnknownStandard,
+    UnknownVersion,
+    Undecodable,
+}
+
+impl UnknownEventReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidEnvelope => "invalid_envelope",
+            Self::UnknownStandard => "unknown_standard",
+            Self::UnknownVersion => "unknown_version",
+            Self::Undecodable => "undecodable",
+        }
+    }
 }
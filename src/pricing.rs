@@ -0,0 +1,165 @@
+use crate::models::U128;
+use anyhow::{anyhow, Context};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const YOCTO_PER_NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+const NEAR_DECIMALS: u32 = 24;
+
+/// NEP-199 result of `nft_transfer_payout`: yoctoNEAR amounts keyed by receiver.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Payout {
+    pub payout: HashMap<String, String>,
+}
+
+/// Whole yoctoNEAR amount of a price the market models carry as a decimal.
+pub fn decimal_to_yocto(yocto: Decimal) -> anyhow::Result<u128> {
+    if yocto.is_sign_negative() || !yocto.fract().is_zero() {
+        return Err(anyhow!("{yocto} is not a yoctoNEAR amount"));
+    }
+    yocto
+        .to_u128()
+        .ok_or_else(|| anyhow!("{yocto} is not a yoctoNEAR amount"))
+}
+
+/// NEAR amount of `yocto`. A decimal's mantissa has 96 bits, so amounts from ~79k NEAR on lose
+/// their smallest digits instead of overflowing.
+pub fn near(yocto: u128) -> Decimal {
+    let mut mantissa = yocto;
+    let mut scale = NEAR_DECIMALS;
+    while mantissa >= 1 << 96 {
+        mantissa /= 10;
+        scale -= 1;
+    }
+    Decimal::from_i128_with_scale(mantissa as i128, scale)
+}
+
+pub fn yocto_to_near(yocto: Decimal) -> anyhow::Result<Decimal> {
+    decimal_to_yocto(yocto).map(near)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RoyaltySplit {
+    pub account_id: String,
+    pub amount: Decimal,
+}
+
+/// Where the money of a sale went, in NEAR.
+#[derive(Serialize, Debug, Clone)]
+pub struct SalePricing {
+    pub price_yocto: U128,
+    pub price: Decimal,
+    /// What the market kept: the part of the price not paid out, plus its own payout entry.
+    pub marketplace_fee: Option<Decimal>,
+    pub royalties: Vec<RoyaltySplit>,
+    pub seller_proceeds: Option<Decimal>,
+}
+
+impl SalePricing {
+    /// Splits are only known when the NFT contract returned a payout for the transfer.
+    pub fn new(
+        price_yocto: Decimal,
+        seller_id: &str,
+        market_contract_id: &str,
+        payout: Option<&Payout>,
+    ) -> anyhow::Result<Self> {
+        let price_yocto = decimal_to_yocto(price_yocto)?;
+        let price = near(price_yocto);
+        let payout = match payout {
+            Some(payout) => payout,
+            None => {
+                return Ok(Self {
+                    price_yocto: U128(price_yocto),
+                    price,
+                    marketplace_fee: None,
+                    royalties: Vec::new(),
+                    seller_proceeds: None,
+                })
+            }
+        };
+
+        let mut paid_out = 0u128;
+        let mut marketplace_fee = 0u128;
+        let mut seller_proceeds = 0u128;
+        let mut royalties = Vec::new();
+        for (account_id, amount) in &payout.payout {
+            let amount: u128 = amount
+                .parse()
+                .with_context(|| format!("Invalid payout amount `{amount}` for {account_id}"))?;
+            paid_out = paid_out
+                .checked_add(amount)
+                .context("Payout amounts overflow")?;
+            if account_id == seller_id {
+                seller_proceeds += amount;
+            } else if account_id == market_contract_id {
+                marketplace_fee += amount;
+            } else {
+                royalties.push(RoyaltySplit {
+                    account_id: account_id.clone(),
+                    amount: near(amount),
+                });
+            }
+        }
+        royalties.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+        if paid_out > price_yocto {
+            tracing::warn!("Payout of {paid_out} yoctoNEAR exceeds the price of {price_yocto}");
+        }
+
+        Ok(Self {
+            price_yocto: U128(price_yocto),
+            price,
+            marketplace_fee: Some(near(marketplace_fee + price_yocto.saturating_sub(paid_out))),
+            royalties,
+            seller_proceeds: Some(near(seller_proceeds)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payout(entries: &[(&str, &str)]) -> Payout {
+        Payout {
+            payout: entries
+                .iter()
+                .map(|(account_id, amount)| (account_id.to_string(), amount.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn converts_amounts_beyond_a_decimal_mantissa() {
+        assert_eq!(near(YOCTO_PER_NEAR / 2), Decimal::new(5, 1));
+        assert_eq!(near(100_000 * YOCTO_PER_NEAR + 5), Decimal::from(100_000));
+        assert_eq!(
+            near(u128::MAX).trunc(),
+            Decimal::from((u128::MAX / YOCTO_PER_NEAR) as u64)
+        );
+    }
+
+    #[test]
+    fn splits_price_between_seller_royalties_and_market() {
+        let price = Decimal::from_i128_with_scale(10 * YOCTO_PER_NEAR as i128, 0);
+        let payout = payout(&[
+            ("seller.near", "8000000000000000000000000"),
+            ("artist.near", "1000000000000000000000000"),
+        ]);
+
+        let pricing = SalePricing::new(price, "seller.near", "market.near", Some(&payout)).unwrap();
+
+        assert_eq!(pricing.price, Decimal::from(10));
+        assert_eq!(pricing.seller_proceeds, Some(Decimal::from(8)));
+        assert_eq!(pricing.marketplace_fee, Some(Decimal::from(1)));
+        assert_eq!(pricing.royalties[0].account_id, "artist.near");
+        assert_eq!(pricing.royalties[0].amount, Decimal::from(1));
+    }
+
+    #[test]
+    fn rejects_fractional_yocto() {
+        assert!(decimal_to_yocto(Decimal::new(15, 1)).is_err());
+        assert!(decimal_to_yocto(Decimal::from(-1)).is_err());
+    }
+}
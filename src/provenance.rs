@@ -0,0 +1,359 @@
+use crate::activity;
+use crate::consts::PROVENANCE;
+use crate::get_config;
+use crate::models::BlockContext;
+use crate::store::JsonStore;
+use crate::{transactions, IndexerExecutionOutcomeWithReceipt};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+const LOG: &str = "provenance";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvenanceKind {
+    Mint,
+    Assemble,
+    Disassemble,
+    AskAdded,
+    AskRemoved,
+    BidAdded,
+    BidRemoved,
+    Sale,
+    Transfer,
+    Burn,
+    /// Owner at the block state was bootstrapped from, read with view calls.
+    Bootstrap,
+    /// Owner corrected by reconciliation, read with view calls. Without an account the
+    /// contract no longer has the token.
+    Reconcile,
+}
+
+/// Something that happened to a token, as emitted by the contract in `contract_id`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProvenanceEntry {
+    pub token_id: String,
+    pub kind: ProvenanceKind,
+    pub contract_id: String,
+    /// Owner, lister or seller, depending on `kind`.
+    pub account_id: Option<String>,
+    /// New owner of a transfer or buyer of a sale.
+    pub counterparty_id: Option<String>,
+    /// Listing or sale price in yoctoNEAR.
+    pub price: Option<Decimal>,
+    pub receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub block_height: u64,
+    pub timestamp_ms: u64,
+}
+
+impl ProvenanceEntry {
+    pub fn new(
+        outcome: &IndexerExecutionOutcomeWithReceipt,
+        block: BlockContext,
+        kind: ProvenanceKind,
+        token_id: String,
+    ) -> Self {
+        Self {
+            token_id,
+            kind,
+            contract_id: outcome.receipt.receiver_id.to_string(),
+            account_id: None,
+            counterparty_id: None,
+            price: None,
+            receipt_id: outcome.receipt.receipt_id.to_string(),
+            transaction_hash: transactions::transaction_of(&outcome.receipt.receipt_id)
+                .map(|transaction| transaction.transaction_hash),
+            block_height: block.height,
+            timestamp_ms: block.timestamp_ms(),
+        }
+    }
+
+    /// Entry for a token found by the `bootstrap` command, which has no receipt behind it.
+    pub fn bootstrap(
+        contract_id: &str,
+        token_id: String,
+        owner_id: String,
+        block: BlockContext,
+    ) -> Self {
+        Self::viewed(
+            ProvenanceKind::Bootstrap,
+            contract_id,
+            token_id,
+            Some(owner_id),
+            block,
+        )
+    }
+
+    /// Entry for a token whose owner reconciliation corrected, which has no receipt behind it.
+    /// `owner_id` is `None` when the token is gone from the contract.
+    pub fn reconcile(
+        contract_id: &str,
+        token_id: String,
+        owner_id: Option<String>,
+        block: BlockContext,
+    ) -> Self {
+        Self::viewed(
+            ProvenanceKind::Reconcile,
+            contract_id,
+            token_id,
+            owner_id,
+            block,
+        )
+    }
+
+    fn viewed(
+        kind: ProvenanceKind,
+        contract_id: &str,
+        token_id: String,
+        owner_id: Option<String>,
+        block: BlockContext,
+    ) -> Self {
+        let source = match kind {
+            ProvenanceKind::Bootstrap => "bootstrap",
+            _ => "reconcile",
+        };
+        Self {
+            token_id,
+            kind,
+            contract_id: contract_id.to_owned(),
+            account_id: owner_id,
+            counterparty_id: None,
+            price: None,
+            receipt_id: format!("{source}:{}", block.height),
+            transaction_hash: None,
+            block_height: block.height,
+            timestamp_ms: block.timestamp_ms(),
+        }
+    }
+
+    pub fn account(mut self, account_id: impl Into<String>) -> Self {
+        self.account_id = Some(account_id.into());
+        self
+    }
+
+    pub fn counterparty(mut self, counterparty_id: impl Into<String>) -> Self {
+        self.counterparty_id = Some(counterparty_id.into());
+        self
+    }
+
+    pub fn price(mut self, price: Decimal) -> Self {
+        self.price = Some(price);
+        self
+    }
+
+    /// Identifies the entry so replayed blocks don't log it twice.
+    fn key(&self) -> (String, ProvenanceKind, String) {
+        (self.receipt_id.clone(), self.kind, self.token_id.clone())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProvenancePage {
+    pub contract_id: String,
+    pub token_id: String,
+    pub total: usize,
+    pub entries: Vec<ProvenanceEntry>,
+    /// Offset of the next page, if there is one.
+    pub next_offset: Option<usize>,
+}
+
+/// Append-only history of every token, mirrored from `provenance.jsonl` in the state dir.
+/// Tokens are told apart by the contract that logged their entries, so the market's asks, bids
+/// and sales form a history separate from the one of the NFT contract.
+pub struct ProvenanceLog {
+    store: JsonStore,
+    /// Keyed by contract and token id.
+    tokens: HashMap<(String, String), Vec<ProvenanceEntry>>,
+    seen: HashSet<(String, ProvenanceKind, String)>,
+}
+
+impl ProvenanceLog {
+    pub fn load(store: JsonStore) -> anyhow::Result<Self> {
+        let mut log = Self {
+            store,
+            tokens: HashMap::new(),
+            seen: HashSet::new(),
+        };
+        for entry in log.store.load_lines::<ProvenanceEntry>(LOG)? {
+            log.insert(entry);
+        }
+
+        Ok(log)
+    }
+
+    fn insert(&mut self, entry: ProvenanceEntry) -> bool {
+        if !self.seen.insert(entry.key()) {
+            return false;
+        }
+        self.tokens
+            .entry((entry.contract_id.clone(), entry.token_id.clone()))
+            .or_default()
+            .push(entry);
+
+        true
+    }
+
+    /// Entries are written in the order blocks are handled, so each token's history stays in
+    /// block order. Returns whether the entry wasn't logged before.
+    pub fn append(&mut self, entry: ProvenanceEntry) -> anyhow::Result<bool> {
+        if self.seen.contains(&entry.key()) {
+            tracing::debug!("Provenance entry {:?} is already logged", entry.key());
+            return Ok(false);
+        }
+        self.store.append(LOG, &entry)?;
+
+        Ok(self.insert(entry))
+    }
+
+    /// Tokens of the contract with their history.
+    pub fn tokens<'a>(
+        &'a self,
+        contract_id: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a [ProvenanceEntry])> {
+        self.tokens
+            .iter()
+            .filter(move |((contract, _), _)| contract == contract_id)
+            .map(|((_, token_id), entries)| (token_id.as_str(), entries.as_slice()))
+    }
+
+    fn entries(&self, contract_id: &str, token_id: &str) -> &[ProvenanceEntry] {
+        self.tokens
+            .get(&(contract_id.to_owned(), token_id.to_owned()))
+            .map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Block of the last entry the contract logged for the token.
+    pub fn last_block_height(&self, contract_id: &str, token_id: &str) -> Option<u64> {
+        self.entries(contract_id, token_id)
+            .last()
+            .map(|entry| entry.block_height)
+    }
+
+    pub fn history(
+        &self,
+        contract_id: &str,
+        token_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> ProvenancePage {
+        let entries = self.entries(contract_id, token_id);
+        let page = entries
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        let end = offset + page.len();
+
+        ProvenancePage {
+            contract_id: contract_id.to_owned(),
+            token_id: token_id.to_owned(),
+            total: entries.len(),
+            entries: page,
+            next_offset: (end < entries.len()).then_some(end),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Getting provenance log")]
+pub async fn get_provenance() -> &'static Mutex<ProvenanceLog> {
+    PROVENANCE
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let log = ProvenanceLog::load(store).expect("Couldn't load provenance log");
+            Mutex::new(log)
+        })
+        .await
+}
+
+/// Logs the entries and the account activity they represent.
+#[tracing::instrument(name = "Recording token provenance", skip(entries))]
+pub async fn record(entries: impl IntoIterator<Item = ProvenanceEntry>) -> anyhow::Result<()> {
+    let logged = {
+        let mut log = get_provenance()
+            .await
+            .lock()
+            .expect("Provenance lock poisoned");
+        let mut logged = Vec::new();
+        for entry in entries {
+            if log.append(entry.clone())? {
+                logged.push(entry);
+            }
+        }
+        logged
+    };
+
+    activity::record(&logged).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn entry(
+        contract_id: &str,
+        token_id: &str,
+        kind: ProvenanceKind,
+        height: u64,
+    ) -> ProvenanceEntry {
+        ProvenanceEntry {
+            token_id: token_id.to_owned(),
+            kind,
+            contract_id: contract_id.to_owned(),
+            account_id: Some("alice.near".to_owned()),
+            counterparty_id: None,
+            price: None,
+            receipt_id: format!("receipt-{height}"),
+            transaction_hash: None,
+            block_height: height,
+            timestamp_ms: height,
+        }
+    }
+
+    fn log(name: &str) -> ProvenanceLog {
+        let dir = testing::state_dir(&format!("provenance_{name}"));
+        ProvenanceLog::load(JsonStore::new(dir)).unwrap()
+    }
+
+    #[test]
+    fn keeps_same_named_tokens_of_other_contracts_apart() {
+        let mut log = log("contracts");
+        log.append(entry("nft.near", "1", ProvenanceKind::Mint, 1))
+            .unwrap();
+        log.append(entry("other.near", "1", ProvenanceKind::Mint, 5))
+            .unwrap();
+        log.append(entry("nft.near", "1", ProvenanceKind::Transfer, 3))
+            .unwrap();
+
+        let page = log.history("nft.near", "1", 0, 10);
+        assert_eq!(page.total, 2);
+        assert!(page.entries.iter().all(|e| e.contract_id == "nft.near"));
+        assert_eq!(log.last_block_height("nft.near", "1"), Some(3));
+        assert_eq!(log.last_block_height("other.near", "1"), Some(5));
+        assert_eq!(log.last_block_height("market.near", "1"), None);
+        assert_eq!(log.tokens("other.near").count(), 1);
+    }
+
+    #[test]
+    fn skips_logged_entries_and_reloads_the_history() {
+        let mut log = log("reload");
+        assert!(log
+            .append(entry("nft.near", "1", ProvenanceKind::Mint, 1))
+            .unwrap());
+        assert!(!log
+            .append(entry("nft.near", "1", ProvenanceKind::Mint, 1))
+            .unwrap());
+        log.append(entry("nft.near", "1", ProvenanceKind::Burn, 2))
+            .unwrap();
+
+        let reloaded = ProvenanceLog::load(log.store.clone()).unwrap();
+        let page = reloaded.history("nft.near", "1", 1, 1);
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries[0].kind, ProvenanceKind::Burn);
+        assert_eq!(page.next_offset, None);
+    }
+}
@@ -0,0 +1,684 @@
+use crate::analytics;
+use crate::config::{get_config, RestConfig};
+use crate::consts::{LAST_RECONCILED, RECONCILING};
+use crate::events;
+use crate::events::nft::{NftBurnData, NftChange};
+use crate::listings::{self, ListingKey, ListingKind, ListingState};
+use crate::metrics;
+use crate::models::BlockContext;
+use crate::provenance::{self, ProvenanceEntry};
+use crate::routing;
+use crate::rpc::{RpcClient, Views};
+use crate::search;
+use crate::snapshot::{self, OwnerMismatch, OwnerSnapshot, RPC_PAGE_SIZE};
+use crate::traits;
+use actix_web::web;
+use anyhow::{anyhow, Context};
+use battlemon_models::market::{ask::AskForRest, bid::BidForRest};
+use battlemon_models::nft::{NftTokenForRest, TokenExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+const STATE: &str = "reconciliation";
+
+#[derive(Deserialize, Clone)]
+pub struct ReconciliationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_interval_blocks")]
+    pub interval_blocks: u64,
+    /// Sends the on-chain state of drifted tokens and listings to the rest service and the
+    /// local state instead of only reporting them.
+    #[serde(default)]
+    pub correct: bool,
+    /// Market view methods returning the open asks and bids.
+    #[serde(default = "default_asks_method")]
+    pub asks_method: String,
+    #[serde(default = "default_bids_method")]
+    pub bids_method: String,
+}
+
+impl Default for ReconciliationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_blocks: default_interval_blocks(),
+            correct: false,
+            asks_method: default_asks_method(),
+            bids_method: default_bids_method(),
+        }
+    }
+}
+
+fn default_interval_blocks() -> u64 {
+    10_000
+}
+
+fn default_asks_method() -> String {
+    "get_asks".to_owned()
+}
+
+fn default_bids_method() -> String {
+    "get_bids".to_owned()
+}
+
+/// Listing open on one side only.
+#[derive(Serialize, Debug, Clone)]
+pub struct ListingMismatch {
+    pub kind: ListingKind,
+    pub token_id: String,
+    pub account_id: String,
+    pub indexed: bool,
+    pub on_chain: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReconciliationReport {
+    /// Block the indexed state is taken at.
+    pub block_height: u64,
+    /// Final block when the contracts were read, their state may include later changes.
+    pub on_chain_block_height: u64,
+    pub nft_contract_id: String,
+    pub market_contract_id: String,
+    pub tokens_on_chain: usize,
+    pub token_mismatches: Vec<OwnerMismatch>,
+    pub listing_mismatches: Vec<ListingMismatch>,
+    /// Tokens whose on-chain state was sent to the rest service.
+    pub corrected: Vec<String>,
+    /// Listings opened or closed to match the market.
+    pub corrected_listings: Vec<ListingMismatch>,
+}
+
+/// Open listings of the market keyed by `(kind, token_id, account_id)`, with what they are
+/// stored in the rest service as when that's known.
+type Listings = BTreeMap<(ListingKind, String, String), Option<Value>>;
+
+#[tracing::instrument(name = "Getting last reconciled block")]
+async fn get_last_reconciled() -> &'static Mutex<Option<u64>> {
+    LAST_RECONCILED
+        .get_or_init(|| async {
+            let last = get_config()
+                .await
+                .store()
+                .load(STATE)
+                .expect("Couldn't load last reconciled block");
+            Mutex::new(last)
+        })
+        .await
+}
+
+/// Starts a reconciliation once `interval_blocks` blocks passed since the last one, unless one
+/// is still running. It runs in the background, comparing the state indexed up to
+/// `block_height` with the final state of the contracts.
+pub async fn schedule(block_height: u64, client: web::Data<reqwest::Client>) {
+    let config = get_config().await;
+    let reconciliation = &config.reconciliation;
+    if !reconciliation.enabled || reconciliation.interval_blocks == 0 {
+        return;
+    }
+    {
+        let mut last = get_last_reconciled()
+            .await
+            .lock()
+            .expect("Last reconciled lock poisoned");
+        let due = last.map_or(true, |last| {
+            block_height >= last + reconciliation.interval_blocks
+        });
+        if !due {
+            return;
+        }
+        if RECONCILING.swap(true, Ordering::SeqCst) {
+            tracing::warn!(
+                "Previous reconciliation is still running, skipping block {block_height}"
+            );
+            return;
+        }
+        *last = Some(block_height);
+        if let Err(e) = config.store().save(STATE, &*last) {
+            tracing::error!("Failed to save last reconciled block: {:?}", e);
+        }
+    }
+
+    let (_, nft, market) = config.contracts.ids();
+    let (nft, market): (&str, &str) = (nft.as_ref(), market.as_ref());
+    let snapshot = {
+        let log = provenance::get_provenance()
+            .await
+            .lock()
+            .expect("Provenance lock poisoned");
+        OwnerSnapshot::from_provenance(&log, nft, block_height)
+    };
+    let indexed_listings = {
+        let book = listings::get_listings()
+            .await
+            .lock()
+            .expect("Listings lock poisoned");
+        book.open_listings()
+            .filter(|key| key.market_contract_id == market)
+            .map(|key| {
+                let record = book.get(key).and_then(|listing| listing.record.clone());
+                (
+                    (key.kind, key.token_id.clone(), key.account_id.clone()),
+                    record,
+                )
+            })
+            .collect::<Listings>()
+    };
+    let market = market.to_owned();
+
+    tokio::spawn(async move {
+        let result = reconcile(snapshot, &market, indexed_listings, client).await;
+        if let Err(e) = result {
+            tracing::error!("Reconciliation at block {block_height} failed: {:?}", e);
+        }
+        RECONCILING.store(false, Ordering::SeqCst);
+    });
+}
+
+#[tracing::instrument(
+    name = "Reconciling indexed state with contracts",
+    skip(snapshot, indexed_listings, client),
+    fields(block_height = snapshot.block_height)
+)]
+async fn reconcile(
+    snapshot: OwnerSnapshot,
+    market_contract_id: &str,
+    indexed_listings: Listings,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<ReconciliationReport> {
+    let config = get_config().await;
+    let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+    let json_rpc_client = config.near_lake.json_rpc_client();
+    let views = Views::Final(&json_rpc_client);
+    let block_height = snapshot.block_height;
+    let nft_contract_id = snapshot.contract_id.clone();
+    let final_block = rpc_client.final_block().await?;
+    let block = BlockContext {
+        height: final_block.header.height,
+        timestamp_nanosec: final_block.header.timestamp_nanosec,
+    };
+
+    let rpc_owners = snapshot::rpc_token_owners(views, &nft_contract_id).await?;
+    let token_mismatches = snapshot::compare(&snapshot, &rpc_owners);
+
+    let mut on_chain_listings = Listings::new();
+    for kind in [ListingKind::Ask, ListingKind::Bid] {
+        let listings = market_listings(views, market_contract_id, kind).await?;
+        for listing in listings {
+            let (token_id, account_id) = listing_parties(&listing)?;
+            on_chain_listings.insert((kind, token_id, account_id), Some(listing));
+        }
+    }
+    let listing_mismatches = listing_mismatches(&indexed_listings, &on_chain_listings);
+
+    for mismatch in &listing_mismatches {
+        tracing::warn!("Listing drifted from {market_contract_id}: {:?}", mismatch);
+    }
+    let mut corrected = Vec::new();
+    let mut corrected_listings = Vec::new();
+    if config.reconciliation.correct {
+        let sink = routing::route_sink(&nft_contract_id).await;
+        for mismatch in &token_mismatches {
+            let corrected_token = correct_token(
+                views,
+                &nft_contract_id,
+                mismatch,
+                block,
+                client.clone(),
+                &sink,
+            )
+            .await?;
+            if corrected_token {
+                corrected.push(mismatch.token_id.clone());
+            }
+        }
+
+        let sink = routing::route_sink(market_contract_id).await;
+        for mismatch in &listing_mismatches {
+            let parties = (
+                mismatch.kind,
+                mismatch.token_id.clone(),
+                mismatch.account_id.clone(),
+            );
+            let corrected_listing = match on_chain_listings.remove(&parties).flatten() {
+                Some(listing) => {
+                    store_listing(
+                        mismatch.kind,
+                        market_contract_id,
+                        listing,
+                        block.height,
+                        &client,
+                        &sink,
+                    )
+                    .await?
+                }
+                None => {
+                    let key = ListingKey {
+                        kind: mismatch.kind,
+                        market_contract_id: market_contract_id.to_owned(),
+                        token_id: mismatch.token_id.clone(),
+                        account_id: mismatch.account_id.clone(),
+                    };
+                    remove_listing(&key, block.height, &client, &sink).await?
+                }
+            };
+            if corrected_listing {
+                corrected_listings.push(mismatch.clone());
+            }
+        }
+    }
+
+    metrics::RECONCILIATION_MISMATCHES
+        .with_label_values(&["token"])
+        .inc_by(token_mismatches.len() as u64);
+    metrics::RECONCILIATION_MISMATCHES
+        .with_label_values(&["listing"])
+        .inc_by(listing_mismatches.len() as u64);
+
+    let report = ReconciliationReport {
+        block_height,
+        on_chain_block_height: block.height,
+        nft_contract_id,
+        market_contract_id: market_contract_id.to_owned(),
+        tokens_on_chain: rpc_owners.len(),
+        token_mismatches,
+        listing_mismatches,
+        corrected,
+        corrected_listings,
+    };
+    tracing::info!(
+        "Reconciled block {}: {} token and {} listing mismatches, {} tokens and {} listings corrected",
+        report.block_height,
+        report.token_mismatches.len(),
+        report.listing_mismatches.len(),
+        report.corrected.len(),
+        report.corrected_listings.len()
+    );
+    store_report(&report, client, &config.rest).await?;
+
+    Ok(report)
+}
+
+/// Listings open on one side only.
+fn listing_mismatches(indexed: &Listings, on_chain: &Listings) -> Vec<ListingMismatch> {
+    let indexed_only = indexed.keys().filter(|key| !on_chain.contains_key(*key));
+    let on_chain_only = on_chain.keys().filter(|key| !indexed.contains_key(*key));
+
+    indexed_only
+        .chain(on_chain_only)
+        .map(|key| {
+            let (kind, token_id, account_id) = key;
+            ListingMismatch {
+                kind: *kind,
+                token_id: token_id.clone(),
+                account_id: account_id.clone(),
+                indexed: indexed.contains_key(key),
+                on_chain: on_chain.contains_key(key),
+            }
+        })
+        .collect()
+}
+
+/// Open asks or bids of the market, as its configured view method returns them page by page.
+pub async fn market_listings(
+    views: Views<'_>,
+    market_contract_id: &str,
+    kind: ListingKind,
+) -> anyhow::Result<Vec<Value>> {
+    let reconciliation = &get_config().await.reconciliation;
+    let method = match kind {
+        ListingKind::Ask => &reconciliation.asks_method,
+        ListingKind::Bid => &reconciliation.bids_method,
+    };
+
+    let mut listings = Vec::new();
+    let mut from_index = 0;
+    loop {
+        let page: Vec<Value> = views
+            .call(
+                market_contract_id,
+                method,
+                &json!({ "from_index": from_index.to_string(), "limit": RPC_PAGE_SIZE }),
+            )
+            .await?;
+        let count = page.len() as u64;
+        listings.extend(page);
+        if count < RPC_PAGE_SIZE {
+            break;
+        }
+        from_index += count;
+    }
+
+    Ok(listings)
+}
+
+fn listing_path(kind: ListingKind) -> &'static str {
+    match kind {
+        ListingKind::Ask => "asks",
+        ListingKind::Bid => "bids",
+    }
+}
+
+/// Stores a listing the market returned in the listing book, the analytics and the rest
+/// service. Returns false without storing it when the book saw the listing change after
+/// `block_height`.
+pub async fn store_listing(
+    kind: ListingKind,
+    market_contract_id: &str,
+    listing: Value,
+    block_height: u64,
+    client: &reqwest::Client,
+    sink: &RestConfig,
+) -> anyhow::Result<bool> {
+    let (token_id, account_id) = listing_parties(&listing)?;
+    let (price, record) = match kind {
+        ListingKind::Ask => {
+            let ask: AskForRest = serde_json::from_value(listing)
+                .context("Failed to deserialize ask returned by the market")?;
+            (ask.price, serde_json::to_value(&ask)?)
+        }
+        ListingKind::Bid => {
+            let bid: BidForRest = serde_json::from_value(listing)
+                .context("Failed to deserialize bid returned by the market")?;
+            (bid.price, serde_json::to_value(&bid)?)
+        }
+    };
+    let key = ListingKey {
+        kind,
+        market_contract_id: market_contract_id.to_owned(),
+        token_id,
+        account_id,
+    };
+    {
+        let mut book = listings::get_listings()
+            .await
+            .lock()
+            .expect("Listings lock poisoned");
+        if book
+            .get(&key)
+            .map_or(false, |l| l.block_height > block_height)
+        {
+            return Ok(false);
+        }
+        book.add(key.clone(), record.clone(), block_height);
+    }
+    analytics::observe_listing(&key, Some(price)).await;
+
+    let response = client
+        .post(format!("{}/{}", sink.base_url(), listing_path(kind)))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&record)
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await?;
+
+    Ok(true)
+}
+
+/// Closes a listing the market no longer has in the listing book, the analytics and the rest
+/// service. Returns false without closing it when the book saw the listing change after
+/// `block_height`, or doesn't know what the rest service stores it as.
+async fn remove_listing(
+    key: &ListingKey,
+    block_height: u64,
+    client: &reqwest::Client,
+    sink: &RestConfig,
+) -> anyhow::Result<bool> {
+    let record = {
+        let mut book = listings::get_listings()
+            .await
+            .lock()
+            .expect("Listings lock poisoned");
+        let record = match book.get(key) {
+            Some(listing)
+                if listing.state == ListingState::Open && listing.block_height <= block_height =>
+            {
+                listing.record.clone()
+            }
+            _ => return Ok(false),
+        };
+        let record = match record {
+            Some(record) => record,
+            None => {
+                tracing::warn!(
+                    "Listing {:?} was opened without a record to remove it with",
+                    key
+                );
+                return Ok(false);
+            }
+        };
+        book.remove(key.clone(), block_height);
+        record
+    };
+    analytics::observe_listing(key, None).await;
+
+    let response = client
+        .delete(format!("{}/{}", sink.base_url(), listing_path(key.kind)))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&record)
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await?;
+
+    Ok(true)
+}
+
+/// Token and lister of a listing returned by the market.
+pub fn listing_parties(listing: &Value) -> anyhow::Result<(String, String)> {
+    let field = |name: &str| listing.get(name).and_then(Value::as_str).map(str::to_owned);
+    let token_id = field("token_id").context("Listing has no `token_id`")?;
+    let account_id = field("account_id")
+        .or_else(|| field("owner_id"))
+        .context("Listing has no `account_id`")?;
+
+    Ok((token_id, account_id))
+}
+
+/// Stores the token as `nft_token` returns it, creating it when it was never indexed, or
+/// removes it when the contract no longer has it. The provenance, traits and search index
+/// follow. Returns false without changing anything when the token has provenance after
+/// `block`, the contract's answer is outdated then.
+async fn correct_token(
+    views: Views<'_>,
+    nft_contract_id: &str,
+    mismatch: &OwnerMismatch,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<bool> {
+    let changed_since = provenance::get_provenance()
+        .await
+        .lock()
+        .expect("Provenance lock poisoned")
+        .last_block_height(nft_contract_id, &mismatch.token_id)
+        .map_or(false, |height| height > block.height);
+    if changed_since {
+        return Ok(false);
+    }
+
+    let token: Option<TokenExt> = views
+        .call(
+            nft_contract_id,
+            "nft_token",
+            &json!({ "token_id": mismatch.token_id }),
+        )
+        .await?;
+    let token = match token {
+        Some(token) => token,
+        None => return remove_token(nft_contract_id, mismatch, block, client, sink).await,
+    };
+    let token: NftTokenForRest = token
+        .try_into()
+        .map_err(|_| anyhow!("Failed to convert TokenExt to NftTokenForRest"))?;
+    provenance::record([ProvenanceEntry::reconcile(
+        nft_contract_id,
+        token.token_id.clone(),
+        Some(token.owner_id.to_string()),
+        block,
+    )])
+    .await?;
+    let change = NftChange::Minted(vec![token.clone()]);
+    traits::observe_nft_change(nft_contract_id, &change).await?;
+    search::observe_nft_change(nft_contract_id, &change).await?;
+
+    let base_url = sink.base_url();
+    let request = match mismatch.indexed_owner_id {
+        None => client
+            .post(format!("{base_url}/nft_tokens"))
+            .json(&vec![token]),
+        Some(_) => client.patch(format!("{base_url}/nft_tokens")).json(&token),
+    };
+    let response = request
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await?;
+
+    Ok(true)
+}
+
+/// Removes a token the contract no longer has, as its burn would.
+async fn remove_token(
+    nft_contract_id: &str,
+    mismatch: &OwnerMismatch,
+    block: BlockContext,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<bool> {
+    let owner_id = match &mismatch.indexed_owner_id {
+        Some(owner_id) => owner_id.clone(),
+        None => return Ok(false),
+    };
+    let token_ids = vec![mismatch.token_id.clone()];
+    provenance::record([ProvenanceEntry::reconcile(
+        nft_contract_id,
+        mismatch.token_id.clone(),
+        None,
+        block,
+    )])
+    .await?;
+    traits::observe_burn(nft_contract_id, &token_ids).await;
+    search::observe_burn(nft_contract_id, &token_ids).await;
+
+    let burn = NftBurnData {
+        owner_id,
+        token_ids,
+        authorized_id: None,
+        memo: None,
+    };
+    let response = client
+        .delete(format!("{}/nft_tokens", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(&burn)
+        .send()
+        .await?;
+    events::handle_response_for_error(response).await?;
+
+    Ok(true)
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store reconciliation report",
+    skip(report, client, sink)
+)]
+async fn store_report(
+    report: &ReconciliationReport,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/reconciliation_reports", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(report)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn listing(
+        kind: ListingKind,
+        token_id: &str,
+        account_id: &str,
+    ) -> (ListingKind, String, String) {
+        (kind, token_id.to_owned(), account_id.to_owned())
+    }
+
+    #[test]
+    fn reports_listings_open_on_one_side_only() {
+        let indexed = Listings::from([
+            (listing(ListingKind::Ask, "1", "alice.near"), None),
+            (listing(ListingKind::Bid, "2", "bob.near"), None),
+        ]);
+        let on_chain = Listings::from([
+            (listing(ListingKind::Ask, "1", "alice.near"), None),
+            (listing(ListingKind::Ask, "2", "bob.near"), None),
+        ]);
+
+        let mismatches = listing_mismatches(&indexed, &on_chain);
+
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].kind, ListingKind::Bid);
+        assert!(mismatches[0].indexed && !mismatches[0].on_chain);
+        assert_eq!(mismatches[1].kind, ListingKind::Ask);
+        assert_eq!(mismatches[1].token_id, "2");
+        assert!(!mismatches[1].indexed && mismatches[1].on_chain);
+    }
+
+    #[test]
+    fn reads_listing_parties_with_either_account_field() {
+        let ask = json!({ "token_id": "1", "account_id": "alice.near", "price": "10" });
+        let bid = json!({ "token_id": "2", "owner_id": "bob.near" });
+
+        assert_eq!(
+            listing_parties(&ask).unwrap(),
+            ("1".to_owned(), "alice.near".to_owned())
+        );
+        assert_eq!(
+            listing_parties(&bid).unwrap(),
+            ("2".to_owned(), "bob.near".to_owned())
+        );
+        assert!(listing_parties(&json!({ "token_id": "3" })).is_err());
+    }
+
+    #[test]
+    fn tokens_gone_from_the_contract_leave_the_snapshot() {
+        let dir = testing::state_dir("reconcile");
+        let mut log = provenance::ProvenanceLog::load(crate::store::JsonStore::new(dir)).unwrap();
+        let block = |height| BlockContext {
+            height,
+            timestamp_nanosec: 0,
+        };
+        for (token_id, owner_id, height) in [("1", Some("alice.near"), 1), ("1", None, 5)] {
+            let owner_id = owner_id.map(str::to_owned);
+            let entry = ProvenanceEntry::reconcile(
+                "nft.near",
+                token_id.to_owned(),
+                owner_id,
+                block(height),
+            );
+            log.append(entry).unwrap();
+        }
+
+        assert_eq!(
+            OwnerSnapshot::from_provenance(&log, "nft.near", 4).token_owners(),
+            BTreeMap::from([("1", "alice.near")])
+        );
+        assert!(OwnerSnapshot::from_provenance(&log, "nft.near", 5)
+            .owners
+            .is_empty());
+    }
+}
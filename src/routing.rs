@@ -0,0 +1,213 @@
+use crate::config::{AppConfig, RestConfig};
+use crate::consts::ROUTER;
+use crate::{discovery, get_config};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+/// Contract account id, or `*.parent` to match every sub-account of `parent`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct AccountPattern(String);
+
+impl TryFrom<String> for AccountPattern {
+    type Error = anyhow::Error;
+
+    /// Wildcards are only allowed as a whole leading label, as in `*.parent`.
+    fn try_from(pattern: String) -> anyhow::Result<Self> {
+        let account_id = pattern.strip_prefix("*.").unwrap_or(&pattern);
+        if account_id.is_empty() || account_id.contains('*') {
+            return Err(anyhow!(
+                "Account pattern `{pattern}` is neither an account id nor `*.parent`"
+            ));
+        }
+
+        Ok(Self(pattern))
+    }
+}
+
+impl AccountPattern {
+    pub fn exact(account_id: impl Into<String>) -> Self {
+        Self(account_id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn parent(&self) -> Option<&str> {
+        self.0.strip_prefix("*.")
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.parent().is_none()
+    }
+
+    pub fn matches(&self, account_id: &str) -> bool {
+        match self.parent() {
+            Some(parent) => account_id
+                .strip_suffix(parent)
+                .and_then(|sub_account| sub_account.strip_suffix('.'))
+                .map_or(false, |sub_account| !sub_account.is_empty()),
+            None => self.0 == account_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HandlerKind {
+    /// Stores NEP-297 envelopes without decoding them.
+    Generic,
+    Nft,
+    Market,
+    /// NEP-141 fungible tokens.
+    Ft,
+    /// The top-level Battlemon contract, `top_contract_id`.
+    Top,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RouteConfig {
+    pub account: AccountPattern,
+    pub handler: HandlerKind,
+    /// Rest service receiving the events, `rest` when absent.
+    #[serde(default)]
+    pub sink: Option<RestConfig>,
+}
+
+#[derive(Clone)]
+pub struct Route {
+    pub pattern: AccountPattern,
+    pub handler: HandlerKind,
+    pub sink: RestConfig,
+}
+
+/// Maps receipt receivers to the handler and sink their events go to.
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Builds the table from `routes` and previously discovered contracts, then registers
+    /// the built-in handlers for the contracts in `contracts` that aren't routed explicitly.
+    pub fn from_config(config: &AppConfig) -> anyhow::Result<Self> {
+        let mut router = Self { routes: Vec::new() };
+        for route in &config.routes {
+            router.register(Route {
+                pattern: route.account.clone(),
+                handler: route.handler,
+                sink: route.sink.clone().unwrap_or_else(|| config.rest.clone()),
+            });
+        }
+
+        for contract in discovery::load_registry(config)? {
+            router.register(contract.route(config));
+        }
+
+        let (top, nft, market) = config.contracts.ids();
+        let (top, nft, market): (&str, &str, &str) = (top.as_ref(), nft.as_ref(), market.as_ref());
+        let builtins = [
+            (top, HandlerKind::Top),
+            (nft, HandlerKind::Nft),
+            (market, HandlerKind::Market),
+        ];
+        for (account_id, handler) in builtins {
+            if !router.has_exact(account_id) {
+                router.register(Route {
+                    pattern: AccountPattern::exact(account_id),
+                    handler,
+                    sink: config.rest.clone(),
+                });
+            }
+        }
+
+        Ok(router)
+    }
+
+    pub fn register(&mut self, route: Route) {
+        tracing::info!(
+            "Routing `{}` to the {:?} handler",
+            route.pattern.as_str(),
+            route.handler
+        );
+        self.routes.push(route);
+    }
+
+    pub fn has_exact(&self, account_id: &str) -> bool {
+        self.routes
+            .iter()
+            .any(|r| r.pattern.is_exact() && r.pattern.as_str() == account_id)
+    }
+
+    /// Exact ids win over patterns, and longer patterns over shorter ones.
+    pub fn route(&self, account_id: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|r| r.pattern.matches(account_id))
+            .max_by_key(|r| (r.pattern.is_exact(), r.pattern.as_str().len()))
+    }
+}
+
+#[tracing::instrument(name = "Getting contracts router")]
+pub async fn get_router() -> &'static RwLock<Router> {
+    ROUTER
+        .get_or_init(|| async {
+            let router =
+                Router::from_config(get_config().await).expect("Couldn't build contracts router");
+            RwLock::new(router)
+        })
+        .await
+}
+
+/// Sink of the contract's route, the default one when it has none.
+pub async fn route_sink(account_id: &str) -> RestConfig {
+    let config = get_config().await;
+    get_router()
+        .await
+        .read()
+        .await
+        .route(account_id)
+        .map_or(&config.rest, |route| &route.sink)
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> AccountPattern {
+        AccountPattern::try_from(pattern.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn wildcard_matches_sub_accounts_only() {
+        let pattern = pattern("*.factory.near");
+
+        assert!(!pattern.is_exact());
+        assert!(pattern.matches("nft.factory.near"));
+        assert!(pattern.matches("a.b.factory.near"));
+        assert!(!pattern.matches("factory.near"));
+        assert!(!pattern.matches(".factory.near"));
+        assert!(!pattern.matches("myfactory.near"));
+    }
+
+    #[test]
+    fn exact_pattern_matches_itself() {
+        let pattern = pattern("nft.near");
+
+        assert!(pattern.is_exact());
+        assert!(pattern.matches("nft.near"));
+        assert!(!pattern.matches("a.nft.near"));
+    }
+
+    #[test]
+    fn rejects_other_wildcards() {
+        for pattern in ["*factory.near", "nft.*.near", "*.", "*", ""] {
+            assert!(
+                AccountPattern::try_from(pattern.to_owned()).is_err(),
+                "`{pattern}` was accepted"
+            );
+        }
+    }
+}
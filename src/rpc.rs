@@ -0,0 +1,504 @@
+use anyhow::{anyhow, Context};
+use battlemon_near_json_rpc_client_wrapper::JsonRpcWrapper;
+use near_lake_framework::near_indexer_primitives::{
+    types::{AccountId, BlockHeight},
+    views::{BlockView, ChunkView, FinalExecutionOutcomeWithReceiptView},
+    CryptoHash,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+pub mod streamer;
+
+/// Where contract view calls are answered.
+#[derive(Clone, Copy)]
+pub enum Views<'a> {
+    /// A node keeping the state of past blocks, at the given height.
+    AtBlock(&'a RpcClient, BlockHeight),
+    /// The final state, through the JSON-RPC wrapper.
+    Final(&'a JsonRpcWrapper),
+}
+
+impl Views<'_> {
+    /// Calls view method `method_name` of `account_id` with JSON `args` and deserializes its
+    /// JSON result.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        account_id: &str,
+        method_name: &str,
+        args: &Value,
+    ) -> anyhow::Result<T> {
+        match self {
+            Views::AtBlock(rpc_client, block_height) => {
+                rpc_client
+                    .view_function(
+                        account_id,
+                        method_name,
+                        args,
+                        BlockRef::Height(*block_height),
+                    )
+                    .await
+            }
+            Views::Final(rpc_client) => {
+                let contract_id = account_id
+                    .parse()
+                    .with_context(|| format!("`{account_id}` isn't a valid account id"))?;
+                let result = rpc_client
+                    .view(contract_id, method_name, serde_json::to_vec(args)?)
+                    .await
+                    .with_context(|| format!("Failed to call {account_id}.{method_name}"))?;
+                serde_json::from_slice(&result).with_context(|| {
+                    format!("Failed to deserialize {account_id}.{method_name} result")
+                })
+            }
+        }
+    }
+}
+
+/// Minimal client for the NEAR JSON-RPC protocol.
+///
+/// Requests are plain JSON over `reqwest`, so any node or a local stand-in serving the
+/// same methods can be used behind it.
+#[derive(Clone)]
+pub struct RpcClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RpcError {
+    pub name: Option<String>,
+    pub cause: Option<RpcErrorCause>,
+    pub message: Option<String>,
+    pub data: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RpcErrorCause {
+    pub name: String,
+}
+
+impl RpcError {
+    fn cause_name(&self) -> Option<&str> {
+        self.cause.as_ref().map(|c| c.name.as_str())
+    }
+
+    /// Skipped heights and garbage-collected blocks are reported as `UNKNOWN_BLOCK`.
+    pub fn is_unknown_block(&self) -> bool {
+        self.cause_name() == Some("UNKNOWN_BLOCK")
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.name.as_deref().unwrap_or("RPC_ERROR"),
+            self.cause_name().unwrap_or("unknown cause"),
+            self.data
+                .as_ref()
+                .map(ToString::to_string)
+                .or_else(|| self.message.clone())
+                .unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum BlockRef {
+    Final,
+    Height(BlockHeight),
+    Hash(CryptoHash),
+}
+
+impl BlockRef {
+    fn params(&self) -> Value {
+        match self {
+            BlockRef::Final => json!({ "finality": "final" }),
+            BlockRef::Height(height) => json!({ "block_id": height }),
+            BlockRef::Hash(hash) => json!({ "block_id": hash }),
+        }
+    }
+}
+
+/// Result of a `call_function` query. Failed calls are reported in `error` rather than as an
+/// RPC error by some node versions.
+#[derive(Deserialize)]
+struct CallResult {
+    result: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GenesisConfig {
+    genesis_height: BlockHeight,
+}
+
+impl RpcClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    #[tracing::instrument(name = "Calling JSON-RPC method", skip(self, params))]
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> anyhow::Result<Result<T, RpcError>> {
+        let response: RpcResponse<T> = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": "battlemon_indexer",
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await
+            .with_context(|| format!("Failed to send `{method}` request to {}", self.url))?
+            .json()
+            .await
+            .with_context(|| format!("Failed to deserialize `{method}` response"))?;
+
+        match (response.result, response.error) {
+            (Some(result), _) => Ok(Ok(result)),
+            (None, Some(error)) => Ok(Err(error)),
+            (None, None) => Err(anyhow!("`{method}` response has neither result nor error")),
+        }
+    }
+
+    /// Returns `None` when there is no block at the requested height.
+    pub async fn block(&self, block: BlockRef) -> anyhow::Result<Option<BlockView>> {
+        match self.call("block", block.params()).await? {
+            Ok(block) => Ok(Some(block)),
+            Err(e) if e.is_unknown_block() => Ok(None),
+            Err(e) => Err(anyhow!("Failed to get block {block:?}: {e}")),
+        }
+    }
+
+    pub async fn final_block(&self) -> anyhow::Result<BlockView> {
+        self.block(BlockRef::Final)
+            .await?
+            .context("RPC node doesn't have a final block")
+    }
+
+    pub async fn chunk(&self, chunk_hash: &CryptoHash) -> anyhow::Result<ChunkView> {
+        self.call("chunk", json!({ "chunk_id": chunk_hash }))
+            .await?
+            .map_err(|e| anyhow!("Failed to get chunk {chunk_hash}: {e}"))
+    }
+
+    /// Returns the transaction together with every receipt it produced and their outcomes.
+    pub async fn tx_status(
+        &self,
+        tx_hash: &CryptoHash,
+        signer_id: &AccountId,
+    ) -> anyhow::Result<FinalExecutionOutcomeWithReceiptView> {
+        self.call("EXPERIMENTAL_tx_status", json!([tx_hash, signer_id]))
+            .await?
+            .map_err(|e| anyhow!("Failed to get status of transaction {tx_hash}: {e}"))
+    }
+
+    /// Calls view method `method_name` of `account_id` with JSON `args` at `block` and
+    /// deserializes its JSON result.
+    #[tracing::instrument(name = "Calling view function", skip(self, args))]
+    pub async fn view_function<T: DeserializeOwned>(
+        &self,
+        account_id: &str,
+        method_name: &str,
+        args: &Value,
+        block: BlockRef,
+    ) -> anyhow::Result<T> {
+        let mut params = block.params();
+        params["request_type"] = json!("call_function");
+        params["account_id"] = json!(account_id);
+        params["method_name"] = json!(method_name);
+        params["args_base64"] = json!(base64::encode(serde_json::to_vec(args)?));
+
+        let call: CallResult = self
+            .call("query", params)
+            .await?
+            .map_err(|e| anyhow!("Failed to call {account_id}.{method_name}: {e}"))?;
+        match (call.result, call.error) {
+            (Some(result), _) => serde_json::from_slice(&result).with_context(|| {
+                format!("Failed to deserialize {account_id}.{method_name} result")
+            }),
+            (None, error) => Err(anyhow!(
+                "Failed to call {account_id}.{method_name}: {}",
+                error.unwrap_or_default()
+            )),
+        }
+    }
+
+    pub async fn genesis_height(&self) -> anyhow::Result<BlockHeight> {
+        let config: GenesisConfig = self
+            .call("EXPERIMENTAL_genesis_config", json!(null))
+            .await?
+            .map_err(|e| anyhow!("Failed to get genesis config: {e}"))?;
+
+        Ok(config.genesis_height)
+    }
+
+    /// Returns the first existing block in `from..=to`, skipping heights without blocks.
+    async fn first_block_from(
+        &self,
+        from: BlockHeight,
+        to: BlockHeight,
+    ) -> anyhow::Result<Option<BlockView>> {
+        for height in from..=to {
+            if let Some(block) = self.block(BlockRef::Height(height)).await? {
+                return Ok(Some(block));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Binary searches for the height of the first block produced at or after
+    /// `timestamp_nanosec`.
+    #[tracing::instrument(name = "Resolving block height by timestamp", skip(self))]
+    pub async fn first_block_at_or_after(
+        &self,
+        timestamp_nanosec: u64,
+    ) -> anyhow::Result<BlockHeight> {
+        let final_block = self.final_block().await?;
+        if final_block.header.timestamp_nanosec < timestamp_nanosec {
+            return Err(anyhow!(
+                "Timestamp {timestamp_nanosec} is later than the final block {}",
+                final_block.header.height
+            ));
+        }
+
+        let mut low = self.genesis_height().await?;
+        let mut high = final_block.header.height;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let block = self
+                .first_block_from(middle, high)
+                .await?
+                .context("Final block disappeared during the search")?;
+            if block.header.timestamp_nanosec >= timestamp_nanosec {
+                high = middle;
+            } else {
+                low = block.header.height + 1;
+            }
+        }
+
+        let block = self
+            .first_block_from(low, final_block.header.height)
+            .await?
+            .context("Failed to find a block after the resolved height")?;
+
+        Ok(block.header.height)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::{self, Reply};
+    use near_crypto::{KeyType, Signature};
+    use std::collections::BTreeMap;
+
+    /// Starts a local stand-in for a node answering JSON-RPC calls with `respond`, given the
+    /// method and params. Other requests, e.g. to the rest service, get an empty success.
+    pub(crate) fn serve<F>(respond: F) -> String
+    where
+        F: Fn(&str, &Value) -> Result<Value, Value> + Send + Sync + 'static,
+    {
+        testing::serve(move |request| {
+            let request = request.json();
+            let method = match request.get("method").and_then(Value::as_str) {
+                Some(method) => method,
+                None => return Reply::json(200, &json!({})),
+            };
+            let response = match respond(method, &request["params"]) {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+                Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+            };
+            Reply::json(200, &response)
+        })
+    }
+
+    pub(crate) fn unknown_block() -> Value {
+        json!({
+            "name": "HANDLER_ERROR",
+            "cause": { "name": "UNKNOWN_BLOCK", "info": {} },
+            "code": -32000,
+            "message": "Server error",
+            "data": "DB Not Found Error",
+        })
+    }
+
+    pub(crate) fn block_hash(height: BlockHeight) -> CryptoHash {
+        CryptoHash::hash_bytes(&height.to_le_bytes())
+    }
+
+    pub(crate) fn chunk_header_json(
+        chunk_hash: CryptoHash,
+        height_included: BlockHeight,
+        shard_id: u64,
+    ) -> Value {
+        json!({
+            "chunk_hash": chunk_hash,
+            "prev_block_hash": block_hash(height_included - 1),
+            "outcome_root": CryptoHash::default(),
+            "prev_state_root": CryptoHash::default(),
+            "encoded_merkle_root": CryptoHash::default(),
+            "encoded_length": 0,
+            "height_created": height_included,
+            "height_included": height_included,
+            "shard_id": shard_id,
+            "gas_used": 0,
+            "gas_limit": 1_000_000_000_000_000u64,
+            "rent_paid": "0",
+            "validator_reward": "0",
+            "balance_burnt": "0",
+            "outgoing_receipts_root": CryptoHash::default(),
+            "tx_root": CryptoHash::default(),
+            "validator_proposals": [],
+            "signature": Signature::empty(KeyType::ED25519),
+        })
+    }
+
+    pub(crate) fn block_json(
+        height: BlockHeight,
+        timestamp_nanosec: u64,
+        chunks: Vec<Value>,
+    ) -> Value {
+        json!({
+            "author": "validator.test",
+            "header": {
+                "height": height,
+                "prev_height": height - 1,
+                "epoch_id": CryptoHash::default(),
+                "next_epoch_id": CryptoHash::default(),
+                "hash": block_hash(height),
+                "prev_hash": block_hash(height - 1),
+                "prev_state_root": CryptoHash::default(),
+                "chunk_receipts_root": CryptoHash::default(),
+                "chunk_headers_root": CryptoHash::default(),
+                "chunk_tx_root": CryptoHash::default(),
+                "outcome_root": CryptoHash::default(),
+                "chunks_included": chunks.len(),
+                "challenges_root": CryptoHash::default(),
+                "timestamp": timestamp_nanosec,
+                "timestamp_nanosec": timestamp_nanosec.to_string(),
+                "random_value": CryptoHash::default(),
+                "validator_proposals": [],
+                "chunk_mask": vec![true; chunks.len()],
+                "gas_price": "100000000",
+                "block_ordinal": height,
+                "rent_paid": "0",
+                "validator_reward": "0",
+                "total_supply": "1000000000000000000000000000000000",
+                "challenges_result": [],
+                "last_final_block": block_hash(height - 1),
+                "last_ds_final_block": block_hash(height - 1),
+                "next_bp_hash": CryptoHash::default(),
+                "block_merkle_root": CryptoHash::default(),
+                "epoch_sync_data_hash": null,
+                "approvals": [],
+                "signature": Signature::empty(KeyType::ED25519),
+                "latest_protocol_version": 56,
+            },
+            "chunks": chunks,
+        })
+    }
+
+    /// Node with blocks at `heights` from genesis at the first one, each produced at
+    /// `height * 1000` nanoseconds.
+    fn chain(heights: impl IntoIterator<Item = BlockHeight>) -> RpcClient {
+        let blocks = heights
+            .into_iter()
+            .map(|height| (height, block_json(height, height * 1000, Vec::new())))
+            .collect::<BTreeMap<_, _>>();
+        let genesis_height = *blocks.keys().next().expect("Chain has no blocks");
+        let url = serve(move |method, params| match method {
+            "EXPERIMENTAL_genesis_config" => Ok(json!({ "genesis_height": genesis_height })),
+            "block" if params.get("finality").is_some() => {
+                Ok(blocks.values().next_back().cloned().unwrap_or_default())
+            }
+            "block" => params["block_id"]
+                .as_u64()
+                .and_then(|height| blocks.get(&height).cloned())
+                .ok_or_else(unknown_block),
+            _ => Err(json!({ "name": "REQUEST_VALIDATION_ERROR" })),
+        });
+
+        RpcClient::new(url)
+    }
+
+    #[tokio::test]
+    async fn resolves_timestamp_to_first_block_at_or_after() {
+        let rpc_client = chain(100..=130);
+
+        assert_eq!(
+            rpc_client.first_block_at_or_after(100_000).await.unwrap(),
+            100
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(117_000).await.unwrap(),
+            117
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(116_500).await.unwrap(),
+            117
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(130_000).await.unwrap(),
+            130
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_heights_without_blocks() {
+        let rpc_client = chain((100..=110).chain(119..=130));
+
+        assert_eq!(
+            rpc_client.first_block_at_or_after(110_000).await.unwrap(),
+            110
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(110_500).await.unwrap(),
+            119
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(115_000).await.unwrap(),
+            119
+        );
+        assert_eq!(
+            rpc_client.first_block_at_or_after(119_000).await.unwrap(),
+            119
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_timestamp_before_genesis_to_genesis() {
+        let rpc_client = chain((100..=103).chain(120..=130));
+
+        assert_eq!(rpc_client.first_block_at_or_after(0).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn rejects_timestamp_after_final_block() {
+        let rpc_client = chain(100..=130);
+
+        assert!(rpc_client.first_block_at_or_after(130_001).await.is_err());
+    }
+}
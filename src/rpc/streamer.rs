@@ -0,0 +1,700 @@
+use super::{BlockRef, RpcClient};
+use crate::config::RpcSourceConfig;
+use futures::{StreamExt, TryStreamExt};
+use near_lake_framework::near_indexer_primitives::{
+    types::{AccountId, BlockHeight, ShardId},
+    views::{BlockView, FinalExecutionOutcomeWithReceiptView, ReceiptView},
+    CryptoHash, IndexerChunkView, IndexerExecutionOutcomeWithOptionalReceipt,
+    IndexerExecutionOutcomeWithReceipt, IndexerShard, IndexerTransactionWithOutcome,
+    StreamerMessage,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long the status of a transaction is fetched again after the block including it, while
+/// some of its receipts aren't executed in a final block yet.
+const PENDING_TRANSACTION_TTL_BLOCKS: BlockHeight = 500;
+
+/// Receipt outcomes fetched while processing earlier blocks, keyed by the hash of the block
+/// they were executed in.
+type PendingOutcomes = HashMap<CryptoHash, PendingBlock>;
+
+struct PendingBlock {
+    /// Height of the block the outcomes were executed in. They are dropped once the streamer
+    /// passes it, e.g. when it started after it.
+    height: BlockHeight,
+    outcomes: Vec<IndexerExecutionOutcomeWithReceipt>,
+}
+
+/// Transaction whose outcome tree wasn't executed in final blocks yet when its status was
+/// last fetched.
+struct PendingTransaction {
+    signer_id: AccountId,
+    /// Height of the block that included the transaction.
+    included_at: BlockHeight,
+    /// Height of the block being assembled when the status was last fetched.
+    polled_at: BlockHeight,
+    /// Receipts whose outcome was queued or given up on.
+    done: HashSet<CryptoHash>,
+}
+
+/// What the streamer carries over from one block to the next.
+#[derive(Default)]
+struct Pending {
+    outcomes: PendingOutcomes,
+    transactions: HashMap<CryptoHash, PendingTransaction>,
+    /// Heights of the blocks outcomes were executed in, as resolved from their hash.
+    heights: HashMap<CryptoHash, BlockHeight>,
+}
+
+/// Polls a NEAR RPC node and assembles a `StreamerMessage` for every block starting from
+/// `start_block_height`, mirroring `near_lake_framework::streamer`.
+///
+/// Receipt outcomes are discovered through the status of the transactions that produced
+/// them, so receipts of transactions submitted before the start block are not indexed.
+pub fn streamer(
+    rpc_client: RpcClient,
+    config: RpcSourceConfig,
+    start_block_height: BlockHeight,
+) -> (
+    JoinHandle<anyhow::Result<()>>,
+    mpsc::Receiver<StreamerMessage>,
+) {
+    let (sender, receiver) = mpsc::channel(config.buffer_size);
+    let handle = tokio::spawn(start(sender, rpc_client, config, start_block_height));
+
+    (handle, receiver)
+}
+
+#[tracing::instrument(
+    name = "Polling blocks from JSON-RPC",
+    skip(sender, rpc_client, config)
+)]
+async fn start(
+    sender: mpsc::Sender<StreamerMessage>,
+    rpc_client: RpcClient,
+    config: RpcSourceConfig,
+    start_block_height: BlockHeight,
+) -> anyhow::Result<()> {
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let mut pending = Pending::default();
+    let mut next_height = start_block_height;
+
+    loop {
+        match poll_block(&rpc_client, &config, next_height, &mut pending).await {
+            Ok(Poll::Block(message)) => {
+                next_height = message.block.header.height + 1;
+                if sender.send(message).await.is_err() {
+                    tracing::info!("Streamer message receiver dropped, stopping RPC polling");
+                    return Ok(());
+                }
+            }
+            Ok(Poll::Skipped) => next_height += 1,
+            Ok(Poll::NotReady) => tokio::time::sleep(poll_interval).await,
+            Err(e) => {
+                tracing::warn!("Failed to fetch block {next_height}, retrying: {e:?}");
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+enum Poll {
+    Block(StreamerMessage),
+    Skipped,
+    NotReady,
+}
+
+async fn poll_block(
+    rpc_client: &RpcClient,
+    config: &RpcSourceConfig,
+    height: BlockHeight,
+    pending: &mut Pending,
+) -> anyhow::Result<Poll> {
+    let final_height = rpc_client.final_block().await?.header.height;
+    if height + config.finality_lag > final_height {
+        return Ok(Poll::NotReady);
+    }
+
+    match rpc_client.block(BlockRef::Height(height)).await? {
+        Some(block) => Ok(Poll::Block(
+            build_streamer_message(rpc_client, config, block, final_height, pending).await?,
+        )),
+        None => Ok(Poll::Skipped),
+    }
+}
+
+#[tracing::instrument(
+    name = "Assembling streamer message from JSON-RPC",
+    fields(block = %block.header.height),
+    skip(rpc_client, config, block, pending)
+)]
+async fn build_streamer_message(
+    rpc_client: &RpcClient,
+    config: &RpcSourceConfig,
+    block: BlockView,
+    final_height: BlockHeight,
+    pending: &mut Pending,
+) -> anyhow::Result<StreamerMessage> {
+    let height = block.header.height;
+    pending.heights.insert(block.header.hash, height);
+    let mut shards = Vec::with_capacity(block.chunks.len());
+    let mut receipt_shards = HashMap::<CryptoHash, ShardId>::new();
+
+    for chunk_header in &block.chunks {
+        // Chunks missing in this block are repeated from the previous one.
+        if chunk_header.height_included != height {
+            shards.push(empty_shard(chunk_header.shard_id));
+            continue;
+        }
+
+        let chunk = rpc_client.chunk(&chunk_header.chunk_hash).await?;
+        let statuses = futures::stream::iter(&chunk.transactions)
+            .map(|transaction| rpc_client.tx_status(&transaction.hash, &transaction.signer_id))
+            .buffered(config.tx_status_concurrency.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut transactions = Vec::with_capacity(chunk.transactions.len());
+        for (transaction, status) in chunk.transactions.into_iter().zip(statuses) {
+            pending.transactions.insert(
+                transaction.hash,
+                PendingTransaction {
+                    signer_id: transaction.signer_id.clone(),
+                    included_at: height,
+                    polled_at: height,
+                    done: HashSet::new(),
+                },
+            );
+            queue_outcomes(
+                rpc_client,
+                pending,
+                transaction.hash,
+                &status,
+                height,
+                final_height,
+            )
+            .await?;
+
+            transactions.push(IndexerTransactionWithOutcome {
+                transaction,
+                outcome: IndexerExecutionOutcomeWithOptionalReceipt {
+                    execution_outcome: status.final_outcome.transaction_outcome,
+                    receipt: None,
+                },
+            });
+        }
+
+        receipt_shards.extend(
+            chunk
+                .receipts
+                .iter()
+                .map(|receipt| (receipt.receipt_id, chunk_header.shard_id)),
+        );
+
+        let mut shard = empty_shard(chunk_header.shard_id);
+        shard.chunk = Some(IndexerChunkView {
+            author: chunk.author,
+            header: chunk.header,
+            transactions,
+            receipts: chunk.receipts,
+        });
+        shards.push(shard);
+    }
+
+    repoll_transactions(rpc_client, config, pending, height, final_height).await?;
+
+    // The shard layout isn't available over RPC, so outcomes go to the shard whose chunk
+    // included the receipt, or to the first shard when it was included earlier.
+    let outcomes = pending
+        .outcomes
+        .remove(&block.header.hash)
+        .map(|pending_block| pending_block.outcomes)
+        .unwrap_or_default();
+    for outcome in outcomes {
+        let shard_id = receipt_shards
+            .get(&outcome.receipt.receipt_id)
+            .copied()
+            .unwrap_or_default();
+        if let Some(shard) = shards.iter_mut().find(|s| s.shard_id == shard_id) {
+            shard.receipt_execution_outcomes.push(outcome);
+        } else if let Some(shard) = shards.first_mut() {
+            shard.receipt_execution_outcomes.push(outcome);
+        }
+    }
+
+    prune_pending(pending, height);
+
+    Ok(StreamerMessage { block, shards })
+}
+
+/// Queues the outcomes in the transaction's status that were executed in final blocks, to be
+/// added to the message of their block. The transaction stays pending until every receipt of
+/// its outcome tree was queued.
+async fn queue_outcomes(
+    rpc_client: &RpcClient,
+    pending: &mut Pending,
+    tx_hash: CryptoHash,
+    status: &FinalExecutionOutcomeWithReceiptView,
+    height: BlockHeight,
+    final_height: BlockHeight,
+) -> anyhow::Result<()> {
+    let mut transaction = match pending.transactions.remove(&tx_hash) {
+        Some(transaction) => transaction,
+        None => return Ok(()),
+    };
+    let receipts: HashMap<CryptoHash, &ReceiptView> = status
+        .receipts
+        .iter()
+        .map(|receipt| (receipt.receipt_id, receipt))
+        .collect();
+    let mut expected = status
+        .final_outcome
+        .transaction_outcome
+        .outcome
+        .receipt_ids
+        .clone();
+    for execution_outcome in &status.final_outcome.receipts_outcome {
+        expected.extend(execution_outcome.outcome.receipt_ids.iter().copied());
+        if transaction.done.contains(&execution_outcome.id) {
+            continue;
+        }
+        let receipt = match receipts.get(&execution_outcome.id) {
+            Some(receipt) => (*receipt).clone(),
+            None => {
+                tracing::debug!("Receipt {} is not in the status", execution_outcome.id);
+                transaction.done.insert(execution_outcome.id);
+                continue;
+            }
+        };
+        let executed_at =
+            match block_height(rpc_client, pending, execution_outcome.block_hash).await? {
+                Some(executed_at) if executed_at <= final_height => executed_at,
+                // Fetched again with the next block.
+                _ => continue,
+            };
+        transaction.done.insert(execution_outcome.id);
+        if executed_at < height {
+            tracing::warn!(
+                "Receipt {} was executed in block {executed_at}, which was already streamed",
+                execution_outcome.id
+            );
+            continue;
+        }
+
+        pending
+            .outcomes
+            .entry(execution_outcome.block_hash)
+            .or_insert_with(|| PendingBlock {
+                height: executed_at,
+                outcomes: Vec::new(),
+            })
+            .outcomes
+            .push(IndexerExecutionOutcomeWithReceipt {
+                execution_outcome: execution_outcome.clone(),
+                receipt,
+            });
+    }
+
+    if !expected.iter().all(|id| transaction.done.contains(id)) {
+        transaction.polled_at = height;
+        pending.transactions.insert(tx_hash, transaction);
+    }
+
+    Ok(())
+}
+
+/// Fetches again the status of the transactions whose outcome tree wasn't complete, giving up
+/// on the ones included more than `PENDING_TRANSACTION_TTL_BLOCKS` ago.
+async fn repoll_transactions(
+    rpc_client: &RpcClient,
+    config: &RpcSourceConfig,
+    pending: &mut Pending,
+    height: BlockHeight,
+    final_height: BlockHeight,
+) -> anyhow::Result<()> {
+    pending.transactions.retain(|tx_hash, transaction| {
+        let alive = transaction.included_at + PENDING_TRANSACTION_TTL_BLOCKS >= height;
+        if !alive {
+            tracing::warn!("Giving up on the receipts of transaction {tx_hash} not executed yet");
+        }
+        alive
+    });
+    let due = pending
+        .transactions
+        .iter()
+        .filter(|(_, transaction)| transaction.polled_at < height)
+        .map(|(tx_hash, transaction)| (*tx_hash, transaction.signer_id.clone()))
+        .collect::<Vec<_>>();
+    let statuses = futures::stream::iter(&due)
+        .map(|(tx_hash, signer_id)| rpc_client.tx_status(tx_hash, signer_id))
+        .buffered(config.tx_status_concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    for ((tx_hash, _), status) in due.into_iter().zip(statuses) {
+        queue_outcomes(rpc_client, pending, tx_hash, &status, height, final_height).await?;
+    }
+
+    Ok(())
+}
+
+/// Height of the block with `block_hash`, `None` while the node doesn't know it.
+async fn block_height(
+    rpc_client: &RpcClient,
+    pending: &mut Pending,
+    block_hash: CryptoHash,
+) -> anyhow::Result<Option<BlockHeight>> {
+    if let Some(height) = pending.heights.get(&block_hash) {
+        return Ok(Some(*height));
+    }
+
+    let height = rpc_client
+        .block(BlockRef::Hash(block_hash))
+        .await?
+        .map(|block| block.header.height);
+    if let Some(height) = height {
+        pending.heights.insert(block_hash, height);
+    }
+
+    Ok(height)
+}
+
+/// Drops outcomes of blocks the streamer already passed, e.g. blocks it started after, or
+/// blocks that didn't make it into the canonical chain.
+fn prune_pending(pending: &mut Pending, height: BlockHeight) {
+    pending.outcomes.retain(|block_hash, pending_block| {
+        let reachable = pending_block.height > height;
+        if !reachable {
+            tracing::debug!(
+                "Dropping {} outcomes of block {block_hash} before height {height}",
+                pending_block.outcomes.len()
+            );
+        }
+        reachable
+    });
+    pending
+        .heights
+        .retain(|_, block_height| *block_height > height);
+}
+
+fn empty_shard(shard_id: ShardId) -> IndexerShard {
+    IndexerShard {
+        shard_id,
+        chunk: None,
+        receipt_execution_outcomes: Vec::new(),
+        state_changes: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::CONFIG;
+    use crate::rpc::tests::{block_hash, block_json, chunk_header_json, serve, unknown_block};
+    use crate::testing;
+    use actix_web::web;
+    use near_crypto::{KeyType, PublicKey, Signature};
+    use serde_json::{json, Value};
+
+    const HEIGHT: BlockHeight = 101;
+    const FINAL_HEIGHT: BlockHeight = 110;
+
+    fn transfer() -> Value {
+        json!([{ "Transfer": { "deposit": "1" } }])
+    }
+
+    fn transaction_json(name: &str) -> Value {
+        json!({
+            "signer_id": "alice.test",
+            "public_key": PublicKey::empty(KeyType::ED25519),
+            "nonce": 1,
+            "receiver_id": "bob.test",
+            "actions": transfer(),
+            "signature": Signature::empty(KeyType::ED25519),
+            "hash": CryptoHash::hash_bytes(name.as_bytes()),
+        })
+    }
+
+    fn outcome_json(id: CryptoHash, block_hash: CryptoHash, receipt_ids: Vec<CryptoHash>) -> Value {
+        json!({
+            "proof": [],
+            "block_hash": block_hash,
+            "id": id,
+            "outcome": {
+                "logs": [],
+                "receipt_ids": receipt_ids,
+                "gas_burnt": 0,
+                "tokens_burnt": "0",
+                "executor_id": "bob.test",
+                "status": { "SuccessValue": "" },
+                "metadata": { "version": 1, "gas_profile": null },
+            },
+        })
+    }
+
+    fn receipt_json(receipt_id: CryptoHash) -> Value {
+        json!({
+            "predecessor_id": "alice.test",
+            "receiver_id": "bob.test",
+            "receipt_id": receipt_id,
+            "receipt": {
+                "Action": {
+                    "signer_id": "alice.test",
+                    "signer_public_key": PublicKey::empty(KeyType::ED25519),
+                    "gas_price": "100000000",
+                    "output_data_receivers": [],
+                    "input_data_ids": [],
+                    "actions": transfer(),
+                },
+            },
+        })
+    }
+
+    /// Transaction `name` whose receipt is executed in the block at `executed_at`.
+    fn tx_status_json(name: &str, executed_at: BlockHeight) -> Value {
+        let transaction = transaction_json(name);
+        let receipt_id = CryptoHash::hash_bytes(format!("{name}:receipt").as_bytes());
+        let tx_hash = CryptoHash::hash_bytes(name.as_bytes());
+        json!({
+            "status": { "SuccessValue": "" },
+            "transaction": transaction,
+            "transaction_outcome": outcome_json(tx_hash, block_hash(HEIGHT), vec![receipt_id]),
+            "receipts_outcome": [outcome_json(receipt_id, block_hash(executed_at), Vec::new())],
+            "receipts": [receipt_json(receipt_id)],
+        })
+    }
+
+    /// Node with a single chunk at `HEIGHT` holding a transaction whose receipt runs in the same
+    /// block and one whose receipt runs in the next.
+    fn node() -> String {
+        let chunk_hash = CryptoHash::hash_bytes(b"chunk");
+        serve(move |method, params| match method {
+            "block" if params.get("finality").is_some() => {
+                Ok(block_json(FINAL_HEIGHT, FINAL_HEIGHT, Vec::new()))
+            }
+            "block" if params["block_id"] == json!(HEIGHT) => Ok(block_json(
+                HEIGHT,
+                HEIGHT,
+                vec![chunk_header_json(chunk_hash, HEIGHT, 0)],
+            )),
+            "block" => block_by_hash(params),
+            "chunk" => Ok(json!({
+                "author": "validator.test",
+                "header": chunk_header_json(chunk_hash, HEIGHT, 0),
+                "transactions": [transaction_json("now"), transaction_json("later")],
+                "receipts": [],
+            })),
+            "EXPERIMENTAL_tx_status" => {
+                let tx_hash = params[0].as_str().unwrap_or_default();
+                if tx_hash == CryptoHash::hash_bytes(b"now").to_string() {
+                    Ok(tx_status_json("now", HEIGHT))
+                } else {
+                    Ok(tx_status_json("later", HEIGHT + 1))
+                }
+            }
+            _ => Err(json!({ "name": "REQUEST_VALIDATION_ERROR" })),
+        })
+    }
+
+    /// Blocks up to the final one looked up by hash, without chunks.
+    fn block_by_hash(params: &Value) -> Result<Value, Value> {
+        (HEIGHT..=FINAL_HEIGHT + 5)
+            .find(|height| params["block_id"] == json!(block_hash(*height)))
+            .map(|height| block_json(height, height, Vec::new()))
+            .ok_or_else(unknown_block)
+    }
+
+    fn source_config() -> RpcSourceConfig {
+        serde_json::from_value(json!({ "tx_status_concurrency": 2 })).unwrap()
+    }
+
+    /// Points the indexer at `url` for both the node and the rest service, keeping its state in
+    /// a fresh directory.
+    fn set_config(url: &str) {
+        let mut config = testing::app_config(
+            "streamer",
+            json!({
+                "near_lake": {
+                    "start_block_height": HEIGHT,
+                    "start_from_last_block": false,
+                    "rpc_url": url,
+                },
+            }),
+        );
+        config.rest = testing::sink(url);
+        assert!(CONFIG.set(config).is_ok(), "Config was already set");
+    }
+
+    #[tokio::test]
+    async fn builds_message_accepted_by_indexer() {
+        let url = node();
+        set_config(&url);
+        let rpc_client = RpcClient::new(&url);
+        let block = rpc_client
+            .block(BlockRef::Height(HEIGHT))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut pending = Pending::default();
+
+        let message = build_streamer_message(
+            &rpc_client,
+            &source_config(),
+            block,
+            FINAL_HEIGHT,
+            &mut pending,
+        )
+        .await
+        .unwrap();
+
+        let shard = &message.shards[0];
+        let transactions = &shard.chunk.as_ref().unwrap().transactions;
+        let tx_hashes = transactions
+            .iter()
+            .map(|t| t.transaction.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tx_hashes,
+            [
+                CryptoHash::hash_bytes(b"now"),
+                CryptoHash::hash_bytes(b"later")
+            ]
+        );
+        assert_eq!(shard.receipt_execution_outcomes.len(), 1);
+        assert_eq!(
+            shard.receipt_execution_outcomes[0].receipt.receipt_id,
+            CryptoHash::hash_bytes(b"now:receipt")
+        );
+        assert_eq!(pending.outcomes[&block_hash(HEIGHT + 1)].outcomes.len(), 1);
+        assert!(pending.transactions.is_empty());
+
+        let client = web::Data::new(reqwest::Client::new());
+        crate::handle_message(message, client).await.unwrap();
+    }
+
+    #[test]
+    fn prunes_outcomes_of_passed_blocks() {
+        let mut pending = Pending::default();
+        for height in [HEIGHT, HEIGHT + 1] {
+            pending.outcomes.insert(
+                block_hash(height),
+                PendingBlock {
+                    height,
+                    outcomes: Vec::new(),
+                },
+            );
+        }
+
+        prune_pending(&mut pending, HEIGHT - 1);
+        assert_eq!(pending.outcomes.len(), 2);
+        prune_pending(&mut pending, HEIGHT);
+        assert!(!pending.outcomes.contains_key(&block_hash(HEIGHT)));
+        assert!(pending.outcomes.contains_key(&block_hash(HEIGHT + 1)));
+    }
+
+    /// Transaction `buy` whose receipt calls back into a receipt that the node executes only
+    /// after its status was fetched `executed_after` times, in the block at `callback_height`.
+    fn callback_node(executed_after: usize, callback_height: BlockHeight) -> String {
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        serve(move |method, params| match method {
+            "block" => block_by_hash(params),
+            "EXPERIMENTAL_tx_status" => {
+                let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let tx_hash = CryptoHash::hash_bytes(b"buy");
+                let receipt_id = CryptoHash::hash_bytes(b"buy:receipt");
+                let callback_id = CryptoHash::hash_bytes(b"buy:callback");
+                let mut outcomes = vec![outcome_json(
+                    receipt_id,
+                    block_hash(HEIGHT),
+                    vec![callback_id],
+                )];
+                if call >= executed_after {
+                    outcomes.push(outcome_json(
+                        callback_id,
+                        block_hash(callback_height),
+                        Vec::new(),
+                    ));
+                }
+                Ok(json!({
+                    "status": { "SuccessValue": "" },
+                    "transaction": transaction_json("buy"),
+                    "transaction_outcome": outcome_json(tx_hash, block_hash(HEIGHT), vec![receipt_id]),
+                    "receipts_outcome": outcomes,
+                    "receipts": [receipt_json(receipt_id), receipt_json(callback_id)],
+                }))
+            }
+            _ => Err(json!({ "name": "REQUEST_VALIDATION_ERROR" })),
+        })
+    }
+
+    async fn poll_buy(rpc_client: &RpcClient, pending: &mut Pending, final_height: BlockHeight) {
+        let tx_hash = CryptoHash::hash_bytes(b"buy");
+        let status = rpc_client
+            .tx_status(&tx_hash, &"alice.test".parse().unwrap())
+            .await
+            .unwrap();
+        pending.transactions.insert(
+            tx_hash,
+            PendingTransaction {
+                signer_id: "alice.test".parse().unwrap(),
+                included_at: HEIGHT,
+                polled_at: HEIGHT,
+                done: HashSet::new(),
+            },
+        );
+        queue_outcomes(rpc_client, pending, tx_hash, &status, HEIGHT, final_height)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_again_until_the_callback_is_executed() {
+        let rpc_client = RpcClient::new(callback_node(1, HEIGHT + 2));
+        let mut pending = Pending::default();
+
+        poll_buy(&rpc_client, &mut pending, FINAL_HEIGHT).await;
+        assert_eq!(pending.transactions.len(), 1);
+        assert!(!pending.outcomes.contains_key(&block_hash(HEIGHT + 2)));
+
+        repoll_transactions(
+            &rpc_client,
+            &source_config(),
+            &mut pending,
+            HEIGHT + 1,
+            FINAL_HEIGHT,
+        )
+        .await
+        .unwrap();
+        assert!(pending.transactions.is_empty());
+        assert_eq!(pending.outcomes[&block_hash(HEIGHT + 2)].outcomes.len(), 1);
+        assert_eq!(pending.outcomes[&block_hash(HEIGHT)].outcomes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_outcomes_above_the_final_block_until_they_are_final() {
+        let rpc_client = RpcClient::new(callback_node(0, FINAL_HEIGHT + 2));
+        let mut pending = Pending::default();
+
+        poll_buy(&rpc_client, &mut pending, FINAL_HEIGHT).await;
+        assert_eq!(pending.transactions.len(), 1);
+        prune_pending(&mut pending, HEIGHT);
+
+        repoll_transactions(
+            &rpc_client,
+            &source_config(),
+            &mut pending,
+            HEIGHT + 1,
+            FINAL_HEIGHT + 2,
+        )
+        .await
+        .unwrap();
+        assert!(pending.transactions.is_empty());
+        assert_eq!(
+            pending.outcomes[&block_hash(FINAL_HEIGHT + 2)]
+                .outcomes
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use crate::consts::SEARCH;
+use crate::events::nft::NftChange;
+use crate::get_config;
+use crate::store::JsonStore;
+use crate::traits::{self, Traits};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+const STATE: &str = "search";
+const LOG: &str = "search_updates";
+
+const NAME_WEIGHT: u32 = 3;
+const TRAIT_WEIGHT: u32 = 2;
+const TEXT_WEIGHT: u32 = 1;
+
+/// What is searchable about a token.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SearchDocument {
+    pub collection: String,
+    pub token_id: String,
+    pub owner_id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub traits: Traits,
+    /// Metadata resolved from IPFS, searchable by name, description and attribute values.
+    #[serde(default)]
+    pub metadata: Option<Value>,
+}
+
+impl SearchDocument {
+    fn key(&self) -> String {
+        document_key(&self.collection, &self.token_id)
+    }
+
+    /// Every term of the document with its weight, the highest one when a term occurs in
+    /// several fields.
+    fn terms(&self) -> HashMap<String, u32> {
+        let mut terms = HashMap::new();
+        let mut add = |text: &str, weight: u32| {
+            for term in tokenize(text) {
+                let entry = terms.entry(term).or_insert(weight);
+                *entry = (*entry).max(weight);
+            }
+        };
+
+        add(&self.token_id, NAME_WEIGHT);
+        if let Some(name) = &self.name {
+            add(name, NAME_WEIGHT);
+        }
+        if let Some(description) = &self.description {
+            add(description, TEXT_WEIGHT);
+        }
+        for value in self.traits.values() {
+            add(value, TRAIT_WEIGHT);
+        }
+        if let Some(metadata) = &self.metadata {
+            for text in metadata_texts(metadata) {
+                add(text, TEXT_WEIGHT);
+            }
+        }
+
+        terms
+    }
+}
+
+fn document_key(collection: &str, token_id: &str) -> String {
+    format!("{collection}/{token_id}")
+}
+
+/// Lowercased alphanumeric words of `text`.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Searchable strings of resolved metadata: its name, description and attribute values.
+fn metadata_texts(metadata: &Value) -> Vec<&str> {
+    let mut texts = ["name", "title", "description"]
+        .iter()
+        .filter_map(|field| metadata.get(field)?.as_str())
+        .collect::<Vec<_>>();
+    if let Some(attributes) = metadata.get("attributes").and_then(Value::as_array) {
+        texts.extend(
+            attributes
+                .iter()
+                .filter_map(|attribute| attribute.get("value")?.as_str()),
+        );
+    }
+
+    texts
+}
+
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+    pub owner: Option<String>,
+    pub collection: Option<String>,
+    /// `type:value` the token's traits must contain.
+    pub trait_filter: Option<String>,
+}
+
+impl SearchFilters {
+    fn matches(&self, document: &SearchDocument) -> bool {
+        let trait_matches = match self.trait_filter.as_deref().map(|t| t.split_once(':')) {
+            Some(Some((trait_type, value))) => {
+                document.traits.get(trait_type).map(String::as_str) == Some(value)
+            }
+            Some(None) => false,
+            None => true,
+        };
+
+        trait_matches
+            && self
+                .owner
+                .as_ref()
+                .map_or(true, |o| *o == document.owner_id)
+            && self
+                .collection
+                .as_ref()
+                .map_or(true, |c| *c == document.collection)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub score: u32,
+    #[serde(flatten)]
+    pub document: SearchDocument,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    pub total: usize,
+    pub hits: Vec<SearchHit>,
+}
+
+/// Change to the index, logged to `search_updates.jsonl` in the state dir until the next
+/// snapshot. Replaying one the snapshot already contains changes nothing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Update {
+    Upsert {
+        document: SearchDocument,
+    },
+    Remove {
+        collection: String,
+        token_id: String,
+    },
+}
+
+/// Inverted index over token documents. Only documents are persisted, the postings are
+/// rebuilt on load.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<SearchDocument>", into = "Vec<SearchDocument>")]
+pub struct SearchIndex {
+    documents: HashMap<String, SearchDocument>,
+    /// Term to the documents containing it, with the term's weight in each. Sorted, so the
+    /// terms starting with a prefix are a range.
+    postings: BTreeMap<String, HashMap<String, u32>>,
+    /// Updates made since the last persist, to be logged.
+    pending: Vec<Update>,
+    /// Block of the last snapshot saved by this process.
+    snapshot_height: Option<u64>,
+    /// Whether there were updates since the last snapshot.
+    dirty: bool,
+}
+
+impl From<Vec<SearchDocument>> for SearchIndex {
+    fn from(documents: Vec<SearchDocument>) -> Self {
+        let mut index = Self::default();
+        for document in documents {
+            index.apply(Update::Upsert { document });
+        }
+
+        index
+    }
+}
+
+impl From<SearchIndex> for Vec<SearchDocument> {
+    fn from(index: SearchIndex) -> Self {
+        index.documents.into_values().collect()
+    }
+}
+
+impl SearchIndex {
+    /// Loads the last snapshot and replays the updates logged after it.
+    fn load(store: &JsonStore) -> anyhow::Result<Self> {
+        let mut index: Self = store.load(STATE)?;
+        for update in store.load_lines::<Update>(LOG)? {
+            index.apply(update);
+            index.dirty = true;
+        }
+
+        Ok(index)
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Upsert { document } => {
+                let key = document.key();
+                self.remove_key(&key);
+                for (term, weight) in document.terms() {
+                    self.postings
+                        .entry(term)
+                        .or_default()
+                        .insert(key.clone(), weight);
+                }
+                self.documents.insert(key, document);
+            }
+            Update::Remove {
+                collection,
+                token_id,
+            } => self.remove_key(&document_key(&collection, &token_id)),
+        }
+    }
+
+    fn record(&mut self, update: Update) {
+        self.dirty = true;
+        self.pending.push(update.clone());
+        self.apply(update);
+    }
+
+    pub fn get(&self, collection: &str, token_id: &str) -> Option<&SearchDocument> {
+        self.documents.get(&document_key(collection, token_id))
+    }
+
+    pub fn upsert(&mut self, document: SearchDocument) {
+        self.record(Update::Upsert { document });
+    }
+
+    /// Applies `f` to an indexed document and reindexes it.
+    pub fn update(
+        &mut self,
+        collection: &str,
+        token_id: &str,
+        f: impl FnOnce(&mut SearchDocument),
+    ) {
+        if let Some(mut document) = self.get(collection, token_id).cloned() {
+            f(&mut document);
+            self.upsert(document);
+        }
+    }
+
+    pub fn remove(&mut self, collection: &str, token_id: &str) {
+        if self.get(collection, token_id).is_some() {
+            self.record(Update::Remove {
+                collection: collection.to_owned(),
+                token_id: token_id.to_owned(),
+            });
+        }
+    }
+
+    fn remove_key(&mut self, key: &str) {
+        let document = match self.documents.remove(key) {
+            Some(document) => document,
+            None => return,
+        };
+        for term in document.terms().into_keys() {
+            if let Some(keys) = self.postings.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Documents containing every term of `query`, the last one also as a prefix, best scored
+    /// first. An empty query matches every document passing the filters.
+    pub fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        offset: usize,
+        limit: usize,
+    ) -> SearchResults {
+        let terms = tokenize(query).collect::<Vec<_>>();
+        let mut scores: Option<BTreeMap<&str, u32>> = None;
+        for (position, term) in terms.iter().enumerate() {
+            let mut matches = BTreeMap::<&str, u32>::new();
+            let is_last = position + 1 == terms.len();
+            let postings = self
+                .postings
+                .range::<str, _>((Bound::Included(term.as_str()), Bound::Unbounded))
+                .take_while(|(indexed, _)| {
+                    *indexed == term || (is_last && indexed.starts_with(term.as_str()))
+                });
+            for (_, keys) in postings {
+                for (key, weight) in keys {
+                    let score = matches.entry(key.as_str()).or_default();
+                    *score = (*score).max(*weight);
+                }
+            }
+
+            scores = Some(match scores {
+                None => matches,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(key, score)| Some((key, score + matches.get(key)?)))
+                    .collect(),
+            });
+        }
+
+        let scores =
+            scores.unwrap_or_else(|| self.documents.keys().map(|key| (key.as_str(), 0)).collect());
+        let mut hits = scores
+            .into_iter()
+            .filter_map(|(key, score)| Some((self.documents.get(key)?, score)))
+            .filter(|(document, _)| filters.matches(document))
+            .collect::<Vec<_>>();
+        hits.sort_by(|(a, a_score), (b, b_score)| {
+            b_score.cmp(a_score).then_with(|| a.key().cmp(&b.key()))
+        });
+
+        SearchResults {
+            total: hits.len(),
+            hits: hits
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|(document, score)| SearchHit {
+                    score,
+                    document: document.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Builds the document of a token from its `NftTokenForRest` form.
+pub fn token_document(collection: &str, token: &Value) -> SearchDocument {
+    let metadata = token.get("metadata").unwrap_or(token);
+    let text = |fields: &[&str]| {
+        fields
+            .iter()
+            .find_map(|field| metadata.get(field)?.as_str())
+            .map(str::to_owned)
+    };
+
+    SearchDocument {
+        collection: collection.to_owned(),
+        token_id: token
+            .get("token_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        owner_id: token
+            .get("owner_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        name: text(&["title", "name"]),
+        description: text(&["description"]),
+        traits: traits::token_traits(token.get("model").unwrap_or(&Value::Null)),
+        metadata: None,
+    }
+}
+
+#[tracing::instrument(name = "Getting search index")]
+pub async fn get_search() -> &'static Mutex<SearchIndex> {
+    SEARCH
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let index = SearchIndex::load(&store).expect("Couldn't load search index");
+            Mutex::new(index)
+        })
+        .await
+}
+
+/// Indexes minted tokens and reindexes updated ones, keeping metadata resolved earlier.
+pub async fn observe_nft_change(collection: &str, change: &NftChange) -> anyhow::Result<()> {
+    let mut index = get_search().await.lock().expect("Search lock poisoned");
+    for token in change.tokens() {
+        let mut document = token_document(collection, &serde_json::to_value(token)?);
+        document.metadata = index
+            .get(collection, &document.token_id)
+            .and_then(|previous| previous.metadata.clone());
+        index.upsert(document);
+    }
+
+    Ok(())
+}
+
+pub async fn observe_transfer(collection: &str, token_id: &str, new_owner_id: &str) {
+    let mut index = get_search().await.lock().expect("Search lock poisoned");
+    index.update(collection, token_id, |document| {
+        document.owner_id = new_owner_id.to_owned()
+    });
+}
+
+pub async fn observe_burn(collection: &str, token_ids: &[String]) {
+    let mut index = get_search().await.lock().expect("Search lock poisoned");
+    for token_id in token_ids {
+        index.remove(collection, token_id);
+    }
+}
+
+pub async fn observe_metadata(collection: &str, token_id: &str, metadata: &Value) {
+    let mut index = get_search().await.lock().expect("Search lock poisoned");
+    index.update(collection, token_id, |document| {
+        document.metadata = Some(metadata.clone())
+    });
+}
+
+/// Logs the updates made since the last block and saves a snapshot every
+/// `snapshot_interval_blocks`.
+#[tracing::instrument(name = "Persisting search index")]
+pub async fn persist(block_height: u64) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let (updates, snapshot) = {
+        let mut index = get_search().await.lock().expect("Search lock poisoned");
+        let updates = std::mem::take(&mut index.pending);
+        let due = index.snapshot_height.map_or(true, |height| {
+            block_height >= height + config.snapshot_interval_blocks
+        });
+        let snapshot = if due && index.dirty {
+            index.snapshot_height = Some(block_height);
+            index.dirty = false;
+            Some(index.clone())
+        } else {
+            None
+        };
+        (updates, snapshot)
+    };
+
+    let store = config.store();
+    for update in &updates {
+        store.append(LOG, update)?;
+    }
+    if let Some(snapshot) = snapshot {
+        store.save(STATE, &snapshot)?;
+        store.remove_lines(LOG)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(token_id: &str, name: &str) -> SearchDocument {
+        SearchDocument {
+            collection: "nft".to_owned(),
+            token_id: token_id.to_owned(),
+            owner_id: "alice.near".to_owned(),
+            name: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn hits(index: &SearchIndex, query: &str) -> Vec<String> {
+        index
+            .search(query, &SearchFilters::default(), 0, 10)
+            .hits
+            .into_iter()
+            .map(|hit| hit.document.token_id)
+            .collect()
+    }
+
+    #[test]
+    fn matches_last_term_as_prefix() {
+        let mut index = SearchIndex::default();
+        index.upsert(document("token-1", "Red Dragon"));
+        index.upsert(document("token-2", "Red Drake"));
+        index.upsert(document("token-3", "Blue Dragon"));
+
+        assert_eq!(hits(&index, "red dra"), ["token-1", "token-2"]);
+        assert_eq!(hits(&index, "dragon"), ["token-1", "token-3"]);
+        assert!(hits(&index, "dra red").is_empty());
+    }
+
+    #[test]
+    fn removed_documents_leave_the_postings() {
+        let mut index = SearchIndex::default();
+        index.upsert(document("token-1", "Red Dragon"));
+        index.remove("nft", "token-1");
+
+        assert!(hits(&index, "red").is_empty());
+        assert!(index.postings.is_empty());
+    }
+}
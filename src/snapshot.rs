@@ -0,0 +1,399 @@
+use crate::config::get_config;
+use crate::provenance::{ProvenanceKind, ProvenanceLog};
+use crate::rpc::{RpcClient, Views};
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Tokens requested per `nft_tokens` call.
+pub const RPC_PAGE_SIZE: u64 = 100;
+
+const USAGE: &str = "Usage: battlemon_indexer snapshot --height <block height> \
+[--format csv|json] [--output <path>] [--contract <nft contract id>] [--verify]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct SnapshotArgs {
+    pub block_height: u64,
+    pub format: SnapshotFormat,
+    /// Standard output when absent.
+    pub output: Option<PathBuf>,
+    /// The configured NFT contract when absent.
+    pub contract_id: Option<String>,
+    /// Compares the snapshot with `nft_tokens` at the same height.
+    pub verify: bool,
+}
+
+impl SnapshotArgs {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut block_height = None;
+        let mut format = SnapshotFormat::Csv;
+        let mut output = None;
+        let mut contract_id = None;
+        let mut verify = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("`{arg}` needs a value"));
+            match arg.as_str() {
+                "--height" => {
+                    block_height = Some(value()?.parse().context("Invalid block height")?);
+                }
+                "--format" => {
+                    format = match value()?.as_str() {
+                        "csv" => SnapshotFormat::Csv,
+                        "json" => SnapshotFormat::Json,
+                        other => bail!("Unknown format `{other}`, expected csv or json"),
+                    }
+                }
+                "--output" => output = Some(PathBuf::from(value()?)),
+                "--contract" => contract_id = Some(value()?),
+                "--verify" => verify = true,
+                other => bail!("Unknown argument `{other}`\n{USAGE}"),
+            }
+        }
+
+        Ok(Self {
+            block_height: block_height.ok_or_else(|| anyhow!("`--height` is required\n{USAGE}"))?,
+            format,
+            output,
+            contract_id,
+            verify,
+        })
+    }
+}
+
+/// Who owned which token of a contract after a block.
+#[derive(Serialize, Debug)]
+pub struct OwnerSnapshot {
+    pub contract_id: String,
+    pub block_height: u64,
+    /// Owner to the ids of their tokens.
+    pub owners: BTreeMap<String, Vec<String>>,
+}
+
+impl OwnerSnapshot {
+    /// Replays the mints, transfers and burns of `contract_id` logged up to `block_height`.
+    pub fn from_provenance(log: &ProvenanceLog, contract_id: &str, block_height: u64) -> Self {
+        let mut owners = BTreeMap::<String, Vec<String>>::new();
+        for (token_id, entries) in log.tokens(contract_id) {
+            let mut owner = None;
+            let entries = entries
+                .iter()
+                .take_while(|e| e.block_height <= block_height);
+            for entry in entries {
+                match entry.kind {
+                    ProvenanceKind::Mint
+                    | ProvenanceKind::Assemble
+                    | ProvenanceKind::Disassemble
+                    | ProvenanceKind::Bootstrap
+                    | ProvenanceKind::Reconcile => owner = entry.account_id.clone(),
+                    ProvenanceKind::Transfer => owner = entry.counterparty_id.clone(),
+                    ProvenanceKind::Burn => owner = None,
+                    _ => {}
+                }
+            }
+            if let Some(owner) = owner {
+                owners.entry(owner).or_default().push(token_id.to_owned());
+            }
+        }
+        for tokens in owners.values_mut() {
+            tokens.sort();
+        }
+
+        Self {
+            contract_id: contract_id.to_owned(),
+            block_height,
+            owners,
+        }
+    }
+
+    pub fn token_owners(&self) -> BTreeMap<&str, &str> {
+        self.owners
+            .iter()
+            .flat_map(|(owner, tokens)| tokens.iter().map(move |t| (t.as_str(), owner.as_str())))
+            .collect()
+    }
+
+    pub fn write_csv(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        writeln!(writer, "owner_id,token_id")?;
+        for (owner, tokens) in &self.owners {
+            for token_id in tokens {
+                writeln!(writer, "{},{}", csv_field(owner), csv_field(token_id))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcToken {
+    token_id: String,
+    owner_id: String,
+}
+
+/// Token whose owner differs between the snapshot and the contract.
+#[derive(Serialize, Debug)]
+pub struct OwnerMismatch {
+    pub token_id: String,
+    pub indexed_owner_id: Option<String>,
+    pub rpc_owner_id: Option<String>,
+}
+
+/// Owners of every token according to `nft_tokens`, called page by page.
+#[tracing::instrument(name = "Fetching token owners over RPC", skip(views))]
+pub async fn rpc_token_owners(
+    views: Views<'_>,
+    contract_id: &str,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut owners = BTreeMap::new();
+    let mut from_index = 0;
+    loop {
+        let tokens: Vec<RpcToken> = views
+            .call(
+                contract_id,
+                "nft_tokens",
+                &json!({ "from_index": from_index.to_string(), "limit": RPC_PAGE_SIZE }),
+            )
+            .await?;
+        let count = tokens.len() as u64;
+        owners.extend(tokens.into_iter().map(|t| (t.token_id, t.owner_id)));
+        if count < RPC_PAGE_SIZE {
+            return Ok(owners);
+        }
+        from_index += count;
+    }
+}
+
+pub fn compare(
+    snapshot: &OwnerSnapshot,
+    rpc_owners: &BTreeMap<String, String>,
+) -> Vec<OwnerMismatch> {
+    let indexed = snapshot.token_owners();
+    let mut token_ids = indexed.keys().copied().collect::<Vec<_>>();
+    token_ids.extend(rpc_owners.keys().map(String::as_str));
+    token_ids.sort_unstable();
+    token_ids.dedup();
+
+    token_ids
+        .into_iter()
+        .filter_map(|token_id| {
+            let indexed_owner_id = indexed.get(token_id).map(|o| o.to_string());
+            let rpc_owner_id = rpc_owners.get(token_id).cloned();
+            (indexed_owner_id != rpc_owner_id).then(|| OwnerMismatch {
+                token_id: token_id.to_owned(),
+                indexed_owner_id,
+                rpc_owner_id,
+            })
+        })
+        .collect()
+}
+
+/// Runs the `snapshot` command. Fails when `--verify` finds mismatches, after reporting them.
+#[tracing::instrument(name = "Exporting owner snapshot", skip(args))]
+pub async fn run(args: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
+    let args = SnapshotArgs::parse(args)?;
+    let config = get_config().await;
+    let contract_id = match &args.contract_id {
+        Some(contract_id) => contract_id.clone(),
+        None => {
+            let (_, nft, _) = config.contracts.ids();
+            let nft: &str = nft.as_ref();
+            nft.to_owned()
+        }
+    };
+
+    let log = ProvenanceLog::load(config.store())?;
+    let snapshot = OwnerSnapshot::from_provenance(&log, &contract_id, args.block_height);
+
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+    match args.format {
+        SnapshotFormat::Csv => snapshot.write_csv(&mut output)?,
+        SnapshotFormat::Json => {
+            serde_json::to_writer_pretty(&mut output, &snapshot)?;
+            writeln!(output)?;
+        }
+    }
+    output.flush()?;
+
+    if !args.verify {
+        return Ok(());
+    }
+
+    let rpc_client = RpcClient::new(config.near_lake.rpc_url());
+    let views = Views::AtBlock(&rpc_client, args.block_height);
+    let rpc_owners = rpc_token_owners(views, &contract_id).await?;
+    let mismatches = compare(&snapshot, &rpc_owners);
+    for mismatch in &mismatches {
+        tracing::warn!(
+            "Owner mismatch for token {}: indexed {}, nft_tokens {}",
+            mismatch.token_id,
+            mismatch.indexed_owner_id.as_deref().unwrap_or("none"),
+            mismatch.rpc_owner_id.as_deref().unwrap_or("none"),
+        );
+    }
+    if !mismatches.is_empty() {
+        bail!(
+            "{} of {} tokens differ from nft_tokens at block {}",
+            mismatches.len(),
+            rpc_owners.len(),
+            args.block_height
+        );
+    }
+    tracing::info!("Snapshot matches nft_tokens at block {}", args.block_height);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provenance::ProvenanceEntry;
+    use crate::store::JsonStore;
+    use crate::testing;
+
+    fn entry(
+        contract_id: &str,
+        token_id: &str,
+        kind: ProvenanceKind,
+        account_id: &str,
+        counterparty_id: Option<&str>,
+        height: u64,
+    ) -> ProvenanceEntry {
+        ProvenanceEntry {
+            token_id: token_id.to_owned(),
+            kind,
+            contract_id: contract_id.to_owned(),
+            account_id: Some(account_id.to_owned()),
+            counterparty_id: counterparty_id.map(str::to_owned),
+            price: None,
+            receipt_id: format!("receipt-{height}"),
+            transaction_hash: None,
+            block_height: height,
+            timestamp_ms: height,
+        }
+    }
+
+    fn log(name: &str, entries: Vec<ProvenanceEntry>) -> ProvenanceLog {
+        let dir = testing::state_dir(&format!("snapshot_{name}"));
+        let mut log = ProvenanceLog::load(JsonStore::new(dir)).unwrap();
+        for entry in entries {
+            log.append(entry).unwrap();
+        }
+
+        log
+    }
+
+    fn history() -> Vec<ProvenanceEntry> {
+        use ProvenanceKind::*;
+
+        vec![
+            entry("nft.near", "1", Mint, "alice.near", None, 1),
+            entry("nft.near", "2", Mint, "alice.near", None, 2),
+            entry("nft.near", "1", AskAdded, "alice.near", None, 3),
+            entry("nft.near", "1", Transfer, "alice.near", Some("bob.near"), 4),
+            entry("nft.near", "2", Burn, "alice.near", None, 5),
+            entry("other.near", "3", Mint, "carol.near", None, 1),
+        ]
+    }
+
+    #[test]
+    fn replays_mints_transfers_and_burns_up_to_the_height() {
+        let log = log("replay", history());
+
+        let before = OwnerSnapshot::from_provenance(&log, "nft.near", 3);
+        assert_eq!(
+            before.owners,
+            BTreeMap::from([(
+                "alice.near".to_owned(),
+                vec!["1".to_owned(), "2".to_owned()]
+            )])
+        );
+
+        let after = OwnerSnapshot::from_provenance(&log, "nft.near", 5);
+        assert_eq!(
+            after.owners,
+            BTreeMap::from([("bob.near".to_owned(), vec!["1".to_owned()])])
+        );
+    }
+
+    #[test]
+    fn keeps_tokens_of_other_contracts_out() {
+        let log = log("contracts", history());
+
+        let snapshot = OwnerSnapshot::from_provenance(&log, "other.near", 5);
+
+        assert_eq!(
+            snapshot.token_owners(),
+            BTreeMap::from([("3", "carol.near")])
+        );
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        let snapshot = OwnerSnapshot {
+            contract_id: "nft.near".to_owned(),
+            block_height: 1,
+            owners: BTreeMap::from([(
+                "alice.near".to_owned(),
+                vec![
+                    "plain".to_owned(),
+                    "a,b".to_owned(),
+                    "say \"hi\"".to_owned(),
+                ],
+            )]),
+        };
+
+        let mut csv = Vec::new();
+        snapshot.write_csv(&mut csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "owner_id,token_id\nalice.near,plain\nalice.near,\"a,b\"\nalice.near,\"say \"\"hi\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn compares_owners_both_ways() {
+        let log = log("compare", history());
+        let snapshot = OwnerSnapshot::from_provenance(&log, "nft.near", 5);
+        let rpc_owners = BTreeMap::from([
+            ("1".to_owned(), "carol.near".to_owned()),
+            ("4".to_owned(), "dave.near".to_owned()),
+        ]);
+
+        let mismatches = compare(&snapshot, &rpc_owners);
+
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].token_id, "1");
+        assert_eq!(mismatches[0].indexed_owner_id.as_deref(), Some("bob.near"));
+        assert_eq!(mismatches[0].rpc_owner_id.as_deref(), Some("carol.near"));
+        assert_eq!(mismatches[1].token_id, "4");
+        assert_eq!(mismatches[1].indexed_owner_id, None);
+        let matching = BTreeMap::from([("1".to_owned(), "bob.near".to_owned())]);
+        assert!(compare(&snapshot, &matching).is_empty());
+    }
+}
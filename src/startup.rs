@@ -1,7 +1,7 @@
 use actix_web::web;
 use tokio::sync::mpsc;
 
-use crate::{handle_message, StreamerMessage};
+use crate::{deposits, events, get_config, handle_message, trades, transactions, StreamerMessage};
 
 #[tracing::instrument(name = "Run indexer", skip(stream, client))]
 pub async fn run_indexer(
@@ -9,6 +9,11 @@ pub async fn run_indexer(
     client: reqwest::Client,
 ) -> anyhow::Result<()> {
     let client = web::Data::new(client);
+    let store = get_config().await.store();
+    trades::restore(&store)?;
+    transactions::restore(&store)?;
+    deposits::restore(&store)?;
+    events::restore_unknown_counts(&store)?;
     while let Some(stream_message) = stream.recv().await {
         handle_message(stream_message, client.clone()).await?
     }
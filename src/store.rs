@@ -0,0 +1,153 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// JSON files in `state_dir` holding indexer state that has to survive restarts.
+#[derive(Clone, Debug)]
+pub struct JsonStore {
+    dir: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+
+    /// Returns `T::default()` when nothing was saved under `name` yet.
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &str) -> anyhow::Result<T> {
+        let path = self.path(name);
+        if !path.exists() {
+            return Ok(T::default());
+        }
+
+        let bytes = std::fs::read(&path)
+            .with_context(|| format!("Failed to read state from {}", path.display()))?;
+        serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to deserialize state from {}", path.display()))
+    }
+
+    /// Writes to a temporary file first, so a crash never leaves half-written state.
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create state dir {}", self.dir.display()))?;
+        let path = self.path(name);
+        let tmp_path = path.with_extension("json.tmp");
+        let bytes = serde_json::to_vec(value).context("Failed to serialize state")?;
+        std::fs::write(&tmp_path, bytes)
+            .with_context(|| format!("Failed to write state to {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to replace state in {}", path.display()))
+    }
+
+    /// Appends `value` as a line of `{name}.jsonl`, for logs that are only ever added to.
+    pub fn append<T: Serialize>(&self, name: &str, value: &T) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create state dir {}", self.dir.display()))?;
+        let path = self.log_path(name);
+        let mut line = serde_json::to_vec(value).context("Failed to serialize state")?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("Failed to append state to {}", path.display()))
+    }
+
+    /// Reads back every line written with `append`, skipping lines a crash left half-written.
+    pub fn load_lines<T: DeserializeOwned>(&self, name: &str) -> anyhow::Result<Vec<T>> {
+        let path = self.log_path(name);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read state from {}", path.display()))?;
+        let values = content
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    tracing::warn!("Skipping corrupted line of {}: {e}", path.display());
+                    None
+                }
+            })
+            .collect();
+
+        Ok(values)
+    }
+
+    /// Deletes the log written with `append`, once what it holds was saved elsewhere.
+    pub fn remove_lines(&self, name: &str) -> anyhow::Result<()> {
+        let path = self.log_path(name);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes everything saved or appended, except under the names in `keep`.
+    pub fn clear(&self, keep: &[&str]) -> anyhow::Result<()> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read state dir {}", self.dir.display()))
+            }
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let kept = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .map_or(false, |name| keep.contains(&name));
+            if kept || !path.is_file() {
+                continue;
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn log_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.jsonl"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn clear_keeps_only_listed_names() {
+        let dir = testing::state_dir("store");
+        let store = JsonStore::new(&dir);
+        store.save("listings", &vec![1]).unwrap();
+        store.append("provenance", &1).unwrap();
+        store.append("ipfs_cache", &1).unwrap();
+
+        store.clear(&["ipfs_cache"]).unwrap();
+
+        assert_eq!(
+            store.load::<Vec<u32>>("listings").unwrap(),
+            Vec::<u32>::new()
+        );
+        assert!(store.load_lines::<u32>("provenance").unwrap().is_empty());
+        assert_eq!(store.load_lines::<u32>("ipfs_cache").unwrap(), [1]);
+    }
+}
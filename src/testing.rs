@@ -0,0 +1,155 @@
+//! Fixtures shared by the unit tests: a local HTTP stand-in for the node, the rest service
+//! and IPFS gateways, and per-test state dirs.
+use crate::config::{AppConfig, RestConfig};
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Request received by the stand-in.
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+}
+
+/// Response the stand-in sends back.
+pub(crate) struct Reply {
+    pub status: u16,
+    pub body: Vec<u8>,
+    pub delay_ms: u64,
+}
+
+impl Reply {
+    pub fn json(status: u16, body: &Value) -> Self {
+        Self {
+            status,
+            body: serde_json::to_vec(body).expect("Couldn't serialize reply"),
+            delay_ms: 0,
+        }
+    }
+
+    pub fn bytes(status: u16, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            body,
+            delay_ms: 0,
+        }
+    }
+
+    pub fn delayed(self, delay_ms: u64) -> Self {
+        Self { delay_ms, ..self }
+    }
+}
+
+/// Starts a local HTTP server answering every request with `respond` and returns its url.
+pub(crate) fn serve<F>(respond: F) -> String
+where
+    F: Fn(&Request) -> Reply + Send + Sync + 'static,
+{
+    let respond = Arc::new(respond);
+    let server = HttpServer::new(move || {
+        let respond = respond.clone();
+        App::new().default_service(web::to(move |request: HttpRequest, body: web::Bytes| {
+            let reply = respond(&Request {
+                method: request.method().to_string(),
+                path: request.path().to_owned(),
+                body: body.to_vec(),
+            });
+            async move {
+                tokio::time::sleep(Duration::from_millis(reply.delay_ms)).await;
+                let status = StatusCode::from_u16(reply.status).expect("Invalid status");
+                HttpResponse::build(status)
+                    .content_type("application/json")
+                    .body(reply.body)
+            }
+        }))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .expect("Couldn't bind HTTP stand-in");
+    let url = format!("http://{}", server.addrs()[0]);
+    tokio::spawn(server.run());
+
+    url
+}
+
+/// Starts a stand-in that accepts every request with an empty JSON object and keeps them.
+pub(crate) fn record() -> (String, Arc<Mutex<Vec<Request>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    let url = serve(move |request| {
+        received.lock().unwrap().push(request.clone());
+        Reply::json(200, &serde_json::json!({}))
+    });
+
+    (url, requests)
+}
+
+/// Rest service config pointing at a stand-in started by [`serve`] or [`record`].
+pub(crate) fn sink(url: &str) -> RestConfig {
+    let (host, port) = url.rsplit_once(':').expect("Stand-in url has no port");
+    serde_json::from_value(serde_json::json!({
+        "host": host,
+        "port": port.parse::<u16>().expect("Invalid stand-in port"),
+        "username": "indexer",
+        "password": "secret",
+    }))
+    .expect("Invalid rest service config")
+}
+
+/// Config for the test contracts `top.test`, `nft.test` and `market.test` with an empty
+/// state dir, with the fields in `overrides` replacing the defaults.
+pub(crate) fn app_config(name: &str, overrides: Value) -> AppConfig {
+    let mut config = serde_json::json!({
+        "contracts": {
+            "top_contract_id": "top.test",
+            "nft_contract_id": "nft.test",
+            "market_contract_id": "market.test",
+        },
+        "rest": { "host": "localhost", "port": 3000, "username": "indexer", "password": "secret" },
+        "near_lake": {
+            "network": "testnet",
+            "aws_access_key_id": "key",
+            "aws_secret_access_key": "secret",
+            "near_credentials": {
+                "account_id": "indexer.test",
+                "private_key": "ed25519:unused",
+            },
+        },
+        "state_dir": state_dir(name),
+    });
+    merge(&mut config, overrides);
+
+    serde_json::from_value(config).expect("Test config is invalid")
+}
+
+/// Replaces the fields of `value` with the ones in `overrides`, recursing into objects.
+fn merge(value: &mut Value, overrides: Value) {
+    match (value, overrides) {
+        (Value::Object(fields), Value::Object(overrides)) => {
+            for (field, value) in overrides {
+                merge(fields.entry(field).or_insert(Value::Null), value);
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+/// Empty state dir for a test, unique to the process.
+pub(crate) fn state_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("battlemon_indexer_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    dir
+}
@@ -0,0 +1,510 @@
+use crate::config::RestConfig;
+use crate::consts::TRADES;
+use crate::events;
+use crate::models::U128;
+use crate::pricing::{Payout, SalePricing};
+use crate::routing;
+use crate::store::JsonStore;
+use crate::transactions::TransactionInfo;
+use actix_web::web;
+use battlemon_models::market::sale::SaleForRest;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const STATE: &str = "trades";
+
+/// How long a sale or a transfer waits for its counterpart before it's given up on.
+const PENDING_TTL_BLOCKS: u64 = 200;
+
+/// What a sale and its transfer have in common: the transaction and the token, or the token
+/// and both parties when the transaction started before the indexer did.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TradeKey {
+    Transaction {
+        transaction_hash: String,
+        token_id: String,
+    },
+    Parties {
+        token_id: String,
+        seller_id: String,
+        buyer_id: String,
+    },
+}
+
+impl TradeKey {
+    fn new(
+        transaction: Option<&TransactionInfo>,
+        token_id: &str,
+        seller_id: &str,
+        buyer_id: &str,
+    ) -> Self {
+        match transaction {
+            Some(transaction) => Self::Transaction {
+                transaction_hash: transaction.transaction_hash.clone(),
+                token_id: token_id.to_owned(),
+            },
+            None => Self::Parties {
+                token_id: token_id.to_owned(),
+                seller_id: seller_id.to_owned(),
+                buyer_id: buyer_id.to_owned(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingSale {
+    pub market_contract_id: String,
+    pub sale: SaleForRest,
+    pub receipt_id: String,
+    pub transaction: Option<TransactionInfo>,
+    pub block_height: u64,
+    /// Attached to the call that made the sale, in yoctoNEAR.
+    #[serde(default)]
+    pub deposit: Option<U128>,
+}
+
+impl PendingSale {
+    fn key(&self) -> TradeKey {
+        TradeKey::new(
+            self.transaction.as_ref(),
+            &self.sale.token_id,
+            &self.sale.prev_owner,
+            &self.sale.curr_owner,
+        )
+    }
+}
+
+/// Transfer of a single token made by a market contract.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenTransfer {
+    pub nft_contract_id: String,
+    pub market_contract_id: String,
+    pub token_id: String,
+    pub old_owner_id: String,
+    pub new_owner_id: String,
+    pub receipt_id: String,
+    pub transaction: Option<TransactionInfo>,
+    pub block_height: u64,
+    /// Returned by `nft_transfer_payout`.
+    pub payout: Option<Payout>,
+}
+
+impl TokenTransfer {
+    fn key(&self) -> TradeKey {
+        TradeKey::new(
+            self.transaction.as_ref(),
+            &self.token_id,
+            &self.old_owner_id,
+            &self.new_owner_id,
+        )
+    }
+}
+
+/// Sale joined with the transfer it caused. The rest service applies the new owner, the last
+/// sale price and the history entry in one transaction.
+#[derive(Serialize, Debug, Clone)]
+pub struct TradeForRest {
+    pub nft_contract_id: String,
+    pub market_contract_id: String,
+    pub token_id: String,
+    pub seller_id: String,
+    pub buyer_id: String,
+    pub price: Decimal,
+    pub pricing: Option<SalePricing>,
+    pub sale_receipt_id: String,
+    pub transfer_receipt_id: String,
+    pub transaction_hash: Option<String>,
+    pub signer_id: Option<String>,
+    pub block_height: u64,
+    pub deposit: Option<U128>,
+    pub sale: SaleForRest,
+}
+
+/// Sale whose transfer never showed up. Only the price is known without the transfer's payout.
+#[derive(Serialize, Debug, Clone)]
+pub struct UnmatchedSaleForRest {
+    #[serde(flatten)]
+    pub sale: SaleForRest,
+    pub pricing: Option<SalePricing>,
+    pub deposit: Option<U128>,
+}
+
+impl UnmatchedSaleForRest {
+    fn new(sale: PendingSale) -> Self {
+        let pricing = SalePricing::new(
+            sale.sale.price,
+            &sale.sale.prev_owner,
+            &sale.market_contract_id,
+            None,
+        )
+        .map_err(|e| tracing::error!("Failed to compute sale pricing: {e:?}"))
+        .ok();
+        Self {
+            deposit: sale.deposit,
+            sale: sale.sale,
+            pricing,
+        }
+    }
+}
+
+impl TradeForRest {
+    fn new(sale: PendingSale, transfer: TokenTransfer) -> Self {
+        let transaction = sale.transaction.or(transfer.transaction);
+        let pricing = SalePricing::new(
+            sale.sale.price,
+            &transfer.old_owner_id,
+            &sale.market_contract_id,
+            transfer.payout.as_ref(),
+        )
+        .map_err(|e| tracing::error!("Failed to compute sale pricing: {e:?}"))
+        .ok();
+        Self {
+            nft_contract_id: transfer.nft_contract_id,
+            market_contract_id: sale.market_contract_id,
+            token_id: transfer.token_id,
+            seller_id: transfer.old_owner_id,
+            buyer_id: transfer.new_owner_id,
+            price: sale.sale.price,
+            pricing,
+            sale_receipt_id: sale.receipt_id,
+            transfer_receipt_id: transfer.receipt_id,
+            transaction_hash: transaction.as_ref().map(|t| t.transaction_hash.clone()),
+            signer_id: transaction.map(|t| t.signer_id),
+            block_height: sale.block_height.max(transfer.block_height),
+            deposit: sale.deposit,
+            sale: sale.sale,
+        }
+    }
+}
+
+/// Sales and transfers waiting for their counterpart, as saved to `trades.json` in the state
+/// dir. The keys are derived from them again on load.
+#[derive(Serialize, Deserialize, Default)]
+struct PendingTrades {
+    sales: Vec<PendingSale>,
+    transfers: Vec<TokenTransfer>,
+}
+
+/// Pairs market sales with the NFT transfers they caused, whichever is seen first.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(from = "PendingTrades", into = "PendingTrades")]
+pub struct TradeCorrelator {
+    sales: HashMap<TradeKey, PendingSale>,
+    transfers: HashMap<TradeKey, TokenTransfer>,
+    dirty: bool,
+}
+
+impl From<PendingTrades> for TradeCorrelator {
+    fn from(pending: PendingTrades) -> Self {
+        Self {
+            sales: pending
+                .sales
+                .into_iter()
+                .map(|sale| (sale.key(), sale))
+                .collect(),
+            transfers: pending
+                .transfers
+                .into_iter()
+                .map(|transfer| (transfer.key(), transfer))
+                .collect(),
+            dirty: false,
+        }
+    }
+}
+
+impl From<TradeCorrelator> for PendingTrades {
+    fn from(correlator: TradeCorrelator) -> Self {
+        Self {
+            sales: correlator.sales.into_values().collect(),
+            transfers: correlator.transfers.into_values().collect(),
+        }
+    }
+}
+
+impl TradeCorrelator {
+    pub fn add_sale(&mut self, sale: PendingSale) -> Option<TradeForRest> {
+        self.dirty = true;
+        let key = sale.key();
+        match self.transfers.remove(&key) {
+            Some(transfer) => Some(TradeForRest::new(sale, transfer)),
+            None => {
+                self.sales.insert(key, sale);
+                None
+            }
+        }
+    }
+
+    pub fn add_transfer(&mut self, transfer: TokenTransfer) -> Option<TradeForRest> {
+        self.dirty = true;
+        let key = transfer.key();
+        match self.sales.remove(&key) {
+            Some(sale) => Some(TradeForRest::new(sale, transfer)),
+            None => {
+                self.transfers.insert(key, transfer);
+                None
+            }
+        }
+    }
+
+    /// Drops everything pending for longer than `PENDING_TTL_BLOCKS` and returns the sales
+    /// and transfers among them.
+    pub fn expire(&mut self, block_height: u64) -> (Vec<PendingSale>, Vec<TokenTransfer>) {
+        let is_expired = |height: u64| height + PENDING_TTL_BLOCKS < block_height;
+        let expired_sales = self
+            .sales
+            .iter()
+            .filter(|(_, sale)| is_expired(sale.block_height))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let expired_transfers = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| is_expired(transfer.block_height))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        self.dirty |= !expired_sales.is_empty() || !expired_transfers.is_empty();
+
+        let sales = expired_sales
+            .into_iter()
+            .filter_map(|key| self.sales.remove(&key))
+            .collect();
+        let transfers = expired_transfers
+            .into_iter()
+            .filter_map(|key| self.transfers.remove(&key))
+            .collect();
+
+        (sales, transfers)
+    }
+}
+
+/// Replaces the pending trades with the ones saved before the last shutdown.
+#[tracing::instrument(name = "Restoring pending trades", skip(store))]
+pub fn restore(store: &JsonStore) -> anyhow::Result<()> {
+    let correlator: TradeCorrelator = store.load(STATE)?;
+    *TRADES.lock().expect("Trades lock poisoned") = correlator;
+
+    Ok(())
+}
+
+/// Saves the pending trades if they changed during the block.
+#[tracing::instrument(name = "Persisting pending trades", skip(store))]
+pub fn persist(store: &JsonStore) -> anyhow::Result<()> {
+    let correlator = {
+        let mut correlator = TRADES.lock().expect("Trades lock poisoned");
+        if !correlator.dirty {
+            return Ok(());
+        }
+        correlator.dirty = false;
+        correlator.clone()
+    };
+
+    store.save(STATE, &correlator)
+}
+
+/// Stores the trade in the rest service of the market's route, where sales and transfers that
+/// never matched go too.
+#[tracing::instrument(
+    name = "Sending request to the rest service to store a trade",
+    skip(trade, client),
+    fields(token_id = %trade.token_id)
+)]
+pub async fn store_trade(
+    trade: &TradeForRest,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let sink = routing::route_sink(&trade.market_contract_id).await;
+    post("trades", trade, client, &sink).await
+}
+
+/// Stores sales whose transfer never showed up as plain sales, and transfers whose sale never
+/// showed up as unmatched transfers, so they aren't lost. Like trades, they go to the rest
+/// service of the market's route.
+#[tracing::instrument(name = "Storing trades without a counterpart", skip(client))]
+pub async fn expire_pending_trades(
+    block_height: u64,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let (sales, transfers) = TRADES
+        .lock()
+        .expect("Trades lock poisoned")
+        .expire(block_height);
+    if sales.is_empty() && transfers.is_empty() {
+        return Ok(());
+    }
+
+    for sale in sales {
+        tracing::warn!(
+            "No transfer matched the sale of token {} in receipt {}",
+            sale.sale.token_id,
+            sale.receipt_id
+        );
+        let sink = routing::route_sink(&sale.market_contract_id).await;
+        post(
+            "sales",
+            &UnmatchedSaleForRest::new(sale),
+            client.clone(),
+            &sink,
+        )
+        .await?;
+    }
+    for transfer in transfers {
+        tracing::warn!(
+            "No sale matched the transfer of token {} in receipt {}",
+            transfer.token_id,
+            transfer.receipt_id
+        );
+        let sink = routing::route_sink(&transfer.market_contract_id).await;
+        post("unmatched_transfers", &transfer, client.clone(), &sink).await?;
+    }
+
+    Ok(())
+}
+
+async fn post<T: Serialize>(
+    path: &str,
+    body: &T,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/{path}", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(body)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transaction(hash: &str) -> TransactionInfo {
+        TransactionInfo {
+            transaction_hash: hash.to_owned(),
+            signer_id: "bob.near".to_owned(),
+            block_height: 1,
+        }
+    }
+
+    fn sale(transaction: Option<TransactionInfo>, block_height: u64) -> PendingSale {
+        let sale = json!({
+            "token_id": "1",
+            "prev_owner": "alice.near",
+            "curr_owner": "bob.near",
+            "price": "1000",
+        });
+        PendingSale {
+            market_contract_id: "market.near".to_owned(),
+            sale: serde_json::from_value(sale).expect("Test sale is invalid"),
+            receipt_id: "sale-receipt".to_owned(),
+            transaction,
+            block_height,
+            deposit: Some(U128(1000)),
+        }
+    }
+
+    fn transfer(transaction: Option<TransactionInfo>, block_height: u64) -> TokenTransfer {
+        TokenTransfer {
+            nft_contract_id: "nft.near".to_owned(),
+            market_contract_id: "market.near".to_owned(),
+            token_id: "1".to_owned(),
+            old_owner_id: "alice.near".to_owned(),
+            new_owner_id: "bob.near".to_owned(),
+            receipt_id: "transfer-receipt".to_owned(),
+            transaction,
+            block_height,
+            payout: None,
+        }
+    }
+
+    #[test]
+    fn matches_a_sale_seen_before_its_transfer() {
+        let mut correlator = TradeCorrelator::default();
+        assert!(correlator
+            .add_sale(sale(Some(transaction("tx-1")), 10))
+            .is_none());
+
+        let trade = correlator
+            .add_transfer(transfer(Some(transaction("tx-1")), 11))
+            .unwrap();
+
+        assert_eq!(trade.transaction_hash.as_deref(), Some("tx-1"));
+        assert_eq!(trade.sale_receipt_id, "sale-receipt");
+        assert_eq!(trade.transfer_receipt_id, "transfer-receipt");
+        assert_eq!(trade.block_height, 11);
+        assert_eq!(trade.deposit, Some(U128(1000)));
+    }
+
+    #[test]
+    fn matches_a_transfer_seen_before_its_sale() {
+        let mut correlator = TradeCorrelator::default();
+        assert!(correlator
+            .add_transfer(transfer(Some(transaction("tx-1")), 10))
+            .is_none());
+
+        let trade = correlator.add_sale(sale(Some(transaction("tx-1")), 10));
+
+        assert!(trade.is_some());
+        let (sales, transfers) = correlator.expire(1000);
+        assert!(sales.is_empty() && transfers.is_empty());
+    }
+
+    #[test]
+    fn keeps_trades_of_other_transactions_apart() {
+        let mut correlator = TradeCorrelator::default();
+        correlator.add_sale(sale(Some(transaction("tx-1")), 10));
+
+        assert!(correlator
+            .add_transfer(transfer(Some(transaction("tx-2")), 10))
+            .is_none());
+    }
+
+    #[test]
+    fn matches_by_parties_without_a_transaction() {
+        let mut correlator = TradeCorrelator::default();
+        correlator.add_sale(sale(None, 10));
+
+        let trade = correlator.add_transfer(transfer(None, 12)).unwrap();
+
+        assert_eq!(trade.transaction_hash, None);
+        assert_eq!(trade.seller_id, "alice.near");
+        assert_eq!(trade.buyer_id, "bob.near");
+    }
+
+    #[test]
+    fn expires_counterparts_that_never_showed_up() {
+        let mut correlator = TradeCorrelator::default();
+        correlator.add_sale(sale(Some(transaction("tx-1")), 10));
+        correlator.add_transfer(transfer(Some(transaction("tx-2")), 20));
+
+        let (sales, transfers) = correlator.expire(10 + PENDING_TTL_BLOCKS);
+        assert!(sales.is_empty() && transfers.is_empty());
+
+        let (sales, transfers) = correlator.expire(11 + PENDING_TTL_BLOCKS);
+        assert_eq!(sales.len(), 1);
+        assert!(transfers.is_empty());
+
+        let (sales, transfers) = correlator.expire(21 + PENDING_TTL_BLOCKS);
+        assert!(sales.is_empty());
+        assert_eq!(transfers[0].receipt_id, "transfer-receipt");
+    }
+
+    #[test]
+    fn pending_trades_survive_a_restart() {
+        let mut correlator = TradeCorrelator::default();
+        correlator.add_sale(sale(Some(transaction("tx-1")), 10));
+
+        let json = serde_json::to_string(&correlator).unwrap();
+        let mut restored: TradeCorrelator = serde_json::from_str(&json).unwrap();
+
+        assert!(restored
+            .add_transfer(transfer(Some(transaction("tx-1")), 11))
+            .is_some());
+    }
+}
@@ -0,0 +1,465 @@
+use crate::analytics;
+use crate::config::RestConfig;
+use crate::consts::TRAITS;
+use crate::events::{self, nft::NftChange};
+use crate::get_config;
+use crate::routing::get_router;
+use crate::store::JsonStore;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+const STATE: &str = "traits";
+const LOG: &str = "traits_updates";
+
+/// Trait type to value.
+pub type Traits = BTreeMap<String, String>;
+
+/// Trait type to value to the number of tokens having it.
+pub type TraitCounts = BTreeMap<String, BTreeMap<String, u64>>;
+
+#[derive(Default, Clone)]
+struct CollectionTraits {
+    tokens: HashMap<String, Traits>,
+    counts: TraitCounts,
+}
+
+impl CollectionTraits {
+    fn count(&mut self, traits: &Traits, added: bool) {
+        for (trait_type, value) in traits {
+            let values = self.counts.entry(trait_type.clone()).or_default();
+            let count = values.entry(value.clone()).or_default();
+            if added {
+                *count += 1;
+            } else {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    values.remove(value);
+                }
+            }
+            if values.is_empty() {
+                self.counts.remove(trait_type);
+            }
+        }
+    }
+
+    fn set(&mut self, token_id: String, traits: Traits) {
+        if let Some(previous) = self.tokens.remove(&token_id) {
+            self.count(&previous, false);
+        }
+        self.count(&traits, true);
+        self.tokens.insert(token_id, traits);
+    }
+
+    fn remove(&mut self, token_id: &str) -> bool {
+        match self.tokens.remove(token_id) {
+            Some(traits) => {
+                self.count(&traits, false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sum of `supply / count` over the token's traits, so rarer traits weigh more.
+    fn score(&self, traits: &Traits) -> f64 {
+        let supply = self.tokens.len() as f64;
+        traits
+            .iter()
+            .filter_map(|(trait_type, value)| self.counts.get(trait_type)?.get(value))
+            .map(|&count| supply / count as f64)
+            .sum()
+    }
+
+    /// Every token with its score and rank, rarest first.
+    fn ranking(&self) -> Vec<TokenRarity> {
+        let mut ranking = self
+            .tokens
+            .iter()
+            .map(|(token_id, traits)| TokenRarity {
+                token_id: token_id.clone(),
+                score: self.score(traits),
+                rank: 0,
+                traits: traits.clone(),
+            })
+            .collect::<Vec<_>>();
+        ranking.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.token_id.cmp(&b.token_id))
+        });
+        for (index, rarity) in ranking.iter_mut().enumerate() {
+            rarity.rank = index + 1;
+        }
+
+        ranking
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TokenRarity {
+    pub token_id: String,
+    pub score: f64,
+    /// 1 for the rarest token of the collection.
+    pub rank: usize,
+    pub traits: Traits,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CollectionTraitsForRest {
+    pub collection: String,
+    pub supply: usize,
+    pub traits: TraitCounts,
+    /// Tokens whose rank or score changed since the collection was last published.
+    pub rarity: Vec<TokenRarity>,
+}
+
+/// Change to the index, logged to `traits_updates.jsonl` in the state dir until the next
+/// snapshot. Replaying one the snapshot already contains changes nothing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Update {
+    Set {
+        collection: String,
+        token_id: String,
+        traits: Traits,
+    },
+    Remove {
+        collection: String,
+        token_id: String,
+    },
+}
+
+/// Traits of every token per collection, from which frequencies and rarity follow. Only the
+/// tokens' traits are persisted, counts are rebuilt on load.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(
+    from = "HashMap<String, HashMap<String, Traits>>",
+    into = "HashMap<String, HashMap<String, Traits>>"
+)]
+pub struct TraitIndex {
+    collections: HashMap<String, CollectionTraits>,
+    changed: HashSet<String>,
+    /// Collection to token to the rank and score last sent to the rest service.
+    published: HashMap<String, HashMap<String, (usize, f64)>>,
+    /// Updates made during the block, to be logged.
+    pending: Vec<Update>,
+    /// Block of the last snapshot saved by this process.
+    snapshot_height: Option<u64>,
+    /// Whether there were updates since the last snapshot.
+    dirty: bool,
+}
+
+impl From<HashMap<String, HashMap<String, Traits>>> for TraitIndex {
+    fn from(collections: HashMap<String, HashMap<String, Traits>>) -> Self {
+        let mut index = Self::default();
+        for (collection, tokens) in collections {
+            for (token_id, traits) in tokens {
+                index.apply(Update::Set {
+                    collection: collection.clone(),
+                    token_id,
+                    traits,
+                });
+            }
+        }
+        index.changed.clear();
+
+        index
+    }
+}
+
+impl From<TraitIndex> for HashMap<String, HashMap<String, Traits>> {
+    fn from(index: TraitIndex) -> Self {
+        index
+            .collections
+            .into_iter()
+            .map(|(collection, traits)| (collection, traits.tokens))
+            .collect()
+    }
+}
+
+impl TraitIndex {
+    /// Loads the last snapshot and replays the updates logged after it.
+    fn load(store: &JsonStore) -> anyhow::Result<Self> {
+        let mut index: Self = store.load(STATE)?;
+        for update in store.load_lines::<Update>(LOG)? {
+            index.apply(update);
+            index.dirty = true;
+        }
+        index.changed.clear();
+
+        Ok(index)
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Set {
+                collection,
+                token_id,
+                traits,
+            } => {
+                self.collections
+                    .entry(collection.clone())
+                    .or_default()
+                    .set(token_id, traits);
+                self.changed.insert(collection);
+            }
+            Update::Remove {
+                collection,
+                token_id,
+            } => {
+                let removed = self
+                    .collections
+                    .get_mut(&collection)
+                    .map_or(false, |traits| traits.remove(&token_id));
+                if removed {
+                    self.changed.insert(collection);
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, update: Update) {
+        self.dirty = true;
+        self.pending.push(update.clone());
+        self.apply(update);
+    }
+
+    pub fn set(&mut self, collection: &str, token_id: String, traits: Traits) {
+        self.record(Update::Set {
+            collection: collection.to_owned(),
+            token_id,
+            traits,
+        });
+    }
+
+    pub fn remove(&mut self, collection: &str, token_id: &str) {
+        self.record(Update::Remove {
+            collection: collection.to_owned(),
+            token_id: token_id.to_owned(),
+        });
+    }
+
+    pub fn counts(&self, collection: &str) -> Option<(usize, &TraitCounts)> {
+        self.collections
+            .get(collection)
+            .map(|traits| (traits.tokens.len(), &traits.counts))
+    }
+
+    pub fn ranking(&self, collection: &str) -> Option<Vec<TokenRarity>> {
+        self.collections.get(collection).map(|t| t.ranking())
+    }
+
+    pub fn rarity(&self, collection: &str, token_id: &str) -> Option<TokenRarity> {
+        self.ranking(collection)?
+            .into_iter()
+            .find(|rarity| rarity.token_id == token_id)
+    }
+
+    /// Changed collections with the tokens whose rank or score moved since they were last
+    /// taken.
+    fn take_changed(&mut self) -> Vec<CollectionTraitsForRest> {
+        let mut collections = Vec::new();
+        for collection in std::mem::take(&mut self.changed) {
+            let traits = match self.collections.get(&collection) {
+                Some(traits) => traits,
+                None => continue,
+            };
+            let published = self.published.entry(collection.clone()).or_default();
+            published.retain(|token_id, _| traits.tokens.contains_key(token_id));
+            let rarity = traits
+                .ranking()
+                .into_iter()
+                .filter(|rarity| {
+                    let ranked = (rarity.rank, rarity.score);
+                    published.insert(rarity.token_id.clone(), ranked) != Some(ranked)
+                })
+                .collect();
+            collections.push(CollectionTraitsForRest {
+                supply: traits.tokens.len(),
+                traits: traits.counts.clone(),
+                rarity,
+                collection,
+            });
+        }
+
+        collections
+    }
+}
+
+/// Traits of a token according to its `model`: its type and every scalar field, nested ones
+/// named by their path. Equipped parts are tokens of their own and don't count.
+pub fn token_traits(model: &Value) -> Traits {
+    let mut traits = Traits::new();
+    traits.insert("type".to_owned(), analytics::token_type(model));
+
+    // Externally tagged models keep their fields under the variant name.
+    let fields = match model {
+        Value::Object(fields) if fields.len() == 1 => match fields.values().next() {
+            Some(Value::Object(inner)) => inner,
+            _ => fields,
+        },
+        Value::Object(fields) => fields,
+        _ => return traits,
+    };
+
+    let mut pending = fields
+        .iter()
+        .map(|(name, value)| (name.clone(), value))
+        .collect::<Vec<_>>();
+    while let Some((name, value)) = pending.pop() {
+        match value {
+            Value::String(value) => {
+                traits.insert(name, value.clone());
+            }
+            Value::Bool(_) | Value::Number(_) => {
+                traits.insert(name, value.to_string());
+            }
+            Value::Object(fields) if !fields.contains_key("token_id") => pending.extend(
+                fields
+                    .iter()
+                    .map(|(field, value)| (format!("{name}.{field}"), value)),
+            ),
+            _ => {}
+        }
+    }
+
+    traits
+}
+
+#[tracing::instrument(name = "Getting trait index")]
+pub async fn get_traits() -> &'static Mutex<TraitIndex> {
+    TRAITS
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let index = TraitIndex::load(&store).expect("Couldn't load trait index");
+            Mutex::new(index)
+        })
+        .await
+}
+
+/// Indexes the traits of minted, assembled and disassembled tokens.
+pub async fn observe_nft_change(collection: &str, change: &NftChange) -> anyhow::Result<()> {
+    let mut index = get_traits().await.lock().expect("Traits lock poisoned");
+    for token in change.tokens() {
+        let model = serde_json::to_value(&token.model)?;
+        index.set(collection, token.token_id.clone(), token_traits(&model));
+    }
+
+    Ok(())
+}
+
+pub async fn observe_burn(collection: &str, token_ids: &[String]) {
+    let mut index = get_traits().await.lock().expect("Traits lock poisoned");
+    for token_id in token_ids {
+        index.remove(collection, token_id);
+    }
+}
+
+/// Logs the updates made during the block, saves a snapshot every `snapshot_interval_blocks`
+/// and sends the collections whose supply or traits changed to their rest service.
+#[tracing::instrument(name = "Publishing trait index", skip(client))]
+pub async fn publish(block_height: u64, client: web::Data<reqwest::Client>) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let (collections, updates, snapshot) = {
+        let mut index = get_traits().await.lock().expect("Traits lock poisoned");
+        let collections = index.take_changed();
+        let updates = std::mem::take(&mut index.pending);
+        let due = index.snapshot_height.map_or(true, |height| {
+            block_height >= height + config.snapshot_interval_blocks
+        });
+        let snapshot = if due && index.dirty {
+            index.snapshot_height = Some(block_height);
+            index.dirty = false;
+            Some(index.clone())
+        } else {
+            None
+        };
+        (collections, updates, snapshot)
+    };
+
+    let store = config.store();
+    for update in &updates {
+        store.append(LOG, update)?;
+    }
+    if let Some(snapshot) = snapshot {
+        store.save(STATE, &snapshot)?;
+        store.remove_lines(LOG)?;
+    }
+
+    let router = get_router().await.read().await;
+    for collection in collections {
+        let sink = router
+            .route(&collection.collection)
+            .map_or(&config.rest, |route| &route.sink);
+        store_traits(std::slice::from_ref(&collection), client.clone(), sink).await?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store collection traits",
+    skip(collections, client, sink)
+)]
+async fn store_traits(
+    collections: &[CollectionTraitsForRest],
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/collection_traits", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(collections)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traits(kind: &str) -> Traits {
+        Traits::from([("type".to_owned(), kind.to_owned())])
+    }
+
+    #[test]
+    fn takes_only_moved_ranks() {
+        let mut index = TraitIndex::default();
+        index.set("nft", "token-1".to_owned(), traits("a"));
+        index.set("nft", "token-2".to_owned(), traits("b"));
+        let collections = index.take_changed();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].rarity.len(), 2);
+
+        index.set("nft", "token-1".to_owned(), traits("a"));
+        let collections = index.take_changed();
+        assert_eq!(collections.len(), 1);
+        assert!(collections[0].rarity.is_empty());
+
+        index.set("nft", "token-3".to_owned(), traits("a"));
+        let rarity = index.take_changed().remove(0).rarity;
+        let ranks = rarity
+            .iter()
+            .map(|rarity| (rarity.token_id.as_str(), rarity.rank))
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, [("token-2", 1), ("token-1", 2), ("token-3", 3)]);
+    }
+
+    #[test]
+    fn burned_tokens_leave_the_counts() {
+        let mut index = TraitIndex::default();
+        index.set("nft", "token-1".to_owned(), traits("a"));
+        index.set("nft", "token-2".to_owned(), traits("a"));
+        index.remove("nft", "token-1");
+
+        let (supply, counts) = index.counts("nft").unwrap();
+        assert_eq!(supply, 1);
+        assert_eq!(counts["type"]["a"], 1);
+    }
+}
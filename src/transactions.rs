@@ -0,0 +1,326 @@
+use crate::consts::TRANSACTIONS;
+use crate::get_config;
+use crate::store::JsonStore;
+use near_lake_framework::near_indexer_primitives::{CryptoHash, StreamerMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const STATE: &str = "transactions";
+const LOG: &str = "transactions_updates";
+
+/// How long a receipt is remembered after it or its parent was last seen. Callbacks
+/// resolving later than this lose their transaction.
+const RECEIPT_TTL_BLOCKS: u64 = 1000;
+
+/// How long a receipt of a chain that hasn't reached a routed contract yet is kept in memory,
+/// in case one of its children does.
+const UNTRACKED_TTL_BLOCKS: u64 = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub transaction_hash: String,
+    pub signer_id: String,
+    /// Block the transaction was included in.
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct IndexedReceipt {
+    transaction: TransactionInfo,
+    seen_at: u64,
+}
+
+/// Receipt added to the index or seen again, logged to `transactions_updates.jsonl` in the state
+/// dir until the next snapshot.
+#[derive(Serialize, Deserialize, Clone)]
+struct Update {
+    receipt_id: CryptoHash,
+    receipt: IndexedReceipt,
+}
+
+/// Links receipts to the transaction they originate from, across blocks. Only chains starting
+/// from or reaching a routed contract are tracked and saved to `transactions.json` in the state
+/// dir, so callbacks resolving after a restart keep theirs. The other receipts are only kept
+/// for a few blocks in memory.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(
+    from = "Vec<(CryptoHash, IndexedReceipt)>",
+    into = "Vec<(CryptoHash, IndexedReceipt)>"
+)]
+pub struct TransactionIndex {
+    receipts: HashMap<CryptoHash, IndexedReceipt>,
+    untracked: HashMap<CryptoHash, IndexedReceipt>,
+    /// Updates made during the block, to be logged.
+    pending: Vec<Update>,
+    /// Block of the last snapshot saved by this process.
+    snapshot_height: Option<u64>,
+    /// Whether there were changes since the last snapshot.
+    dirty: bool,
+}
+
+impl From<Vec<(CryptoHash, IndexedReceipt)>> for TransactionIndex {
+    fn from(receipts: Vec<(CryptoHash, IndexedReceipt)>) -> Self {
+        Self {
+            receipts: receipts.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<TransactionIndex> for Vec<(CryptoHash, IndexedReceipt)> {
+    fn from(index: TransactionIndex) -> Self {
+        index.receipts.into_iter().collect()
+    }
+}
+
+impl TransactionIndex {
+    /// Loads the last snapshot and replays the updates logged after it.
+    fn load(store: &JsonStore) -> anyhow::Result<Self> {
+        let mut index: Self = store.load(STATE)?;
+        for update in store.load_lines::<Update>(LOG)? {
+            index.receipts.insert(update.receipt_id, update.receipt);
+            index.dirty = true;
+        }
+
+        Ok(index)
+    }
+
+    /// Indexes the receipts created by the block's transactions, then the receipts spawned by
+    /// the block's receipt outcomes, which inherit the transaction of their parent.
+    pub fn index_block(&mut self, message: &StreamerMessage, is_routed: impl Fn(&str) -> bool) {
+        let block_height = message.block.header.height;
+        for shard in &message.shards {
+            let transactions = shard.chunk.iter().flat_map(|chunk| &chunk.transactions);
+            for transaction in transactions {
+                let info = TransactionInfo {
+                    transaction_hash: transaction.transaction.hash.to_string(),
+                    signer_id: transaction.transaction.signer_id.to_string(),
+                    block_height,
+                };
+                let routed = is_routed(transaction.transaction.receiver_id.as_ref());
+                let receipt_ids = &transaction.outcome.execution_outcome.outcome.receipt_ids;
+                self.index_transaction(info, receipt_ids, routed, block_height);
+            }
+        }
+
+        for shard in &message.shards {
+            for outcome in &shard.receipt_execution_outcomes {
+                let routed = is_routed(outcome.receipt.receiver_id.as_ref());
+                self.index_outcome(
+                    outcome.receipt.receipt_id,
+                    &outcome.execution_outcome.outcome.receipt_ids,
+                    routed,
+                    block_height,
+                );
+            }
+        }
+    }
+
+    fn index_transaction(
+        &mut self,
+        info: TransactionInfo,
+        receipt_ids: &[CryptoHash],
+        routed: bool,
+        block_height: u64,
+    ) {
+        for receipt_id in receipt_ids {
+            self.insert(*receipt_id, info.clone(), routed, block_height);
+        }
+    }
+
+    /// Indexes the children of an executed receipt. Executing on a routed contract starts
+    /// tracking a chain that wasn't yet.
+    fn index_outcome(
+        &mut self,
+        receipt_id: CryptoHash,
+        receipt_ids: &[CryptoHash],
+        routed: bool,
+        block_height: u64,
+    ) {
+        let (info, tracked) = match self.receipts.get(&receipt_id) {
+            Some(receipt) => (receipt.transaction.clone(), true),
+            None => match self.untracked.get(&receipt_id) {
+                Some(receipt) => (receipt.transaction.clone(), routed),
+                None => return,
+            },
+        };
+        self.insert(receipt_id, info.clone(), tracked, block_height);
+        for receipt_id in receipt_ids {
+            self.insert(*receipt_id, info.clone(), tracked, block_height);
+        }
+    }
+
+    fn insert(
+        &mut self,
+        receipt_id: CryptoHash,
+        transaction: TransactionInfo,
+        tracked: bool,
+        seen_at: u64,
+    ) {
+        let receipt = IndexedReceipt {
+            transaction,
+            seen_at,
+        };
+        if !tracked {
+            self.untracked.insert(receipt_id, receipt);
+            return;
+        }
+
+        self.untracked.remove(&receipt_id);
+        self.dirty = true;
+        self.pending.push(Update {
+            receipt_id,
+            receipt: receipt.clone(),
+        });
+        self.receipts.insert(receipt_id, receipt);
+    }
+
+    pub fn transaction(&self, receipt_id: &CryptoHash) -> Option<&TransactionInfo> {
+        self.receipts
+            .get(receipt_id)
+            .or_else(|| self.untracked.get(receipt_id))
+            .map(|r| &r.transaction)
+    }
+
+    pub fn prune(&mut self, block_height: u64) {
+        let len = self.receipts.len();
+        self.receipts
+            .retain(|_, receipt| receipt.seen_at + RECEIPT_TTL_BLOCKS >= block_height);
+        self.untracked
+            .retain(|_, receipt| receipt.seen_at + UNTRACKED_TTL_BLOCKS >= block_height);
+        self.dirty |= len != self.receipts.len();
+    }
+}
+
+/// Replaces the index with the one saved before the last shutdown.
+#[tracing::instrument(name = "Restoring transaction index", skip(store))]
+pub fn restore(store: &JsonStore) -> anyhow::Result<()> {
+    let index = TransactionIndex::load(store)?;
+    *TRANSACTIONS.lock().expect("Transactions lock poisoned") = index;
+
+    Ok(())
+}
+
+/// Prunes receipts not seen for `RECEIPT_TTL_BLOCKS`, logs the receipts indexed during the
+/// block and saves a snapshot every `snapshot_interval_blocks`.
+#[tracing::instrument(name = "Persisting transaction index", skip(store))]
+pub async fn persist(store: &JsonStore, block_height: u64) -> anyhow::Result<()> {
+    let config = get_config().await;
+    let (updates, snapshot) = {
+        let mut index = TRANSACTIONS.lock().expect("Transactions lock poisoned");
+        index.prune(block_height);
+        let updates = std::mem::take(&mut index.pending);
+        let due = index.snapshot_height.map_or(true, |height| {
+            block_height >= height + config.snapshot_interval_blocks
+        });
+        let snapshot = if due && index.dirty {
+            index.snapshot_height = Some(block_height);
+            index.dirty = false;
+            Some(
+                index
+                    .receipts
+                    .iter()
+                    .map(|(receipt_id, receipt)| (*receipt_id, receipt.clone()))
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            None
+        };
+        (updates, snapshot)
+    };
+
+    for update in &updates {
+        store.append(LOG, update)?;
+    }
+    if let Some(snapshot) = snapshot {
+        store.save(STATE, &snapshot)?;
+        store.remove_lines(LOG)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the transaction `receipt_id` originates from, if it was seen by the indexer.
+pub fn transaction_of(receipt_id: &CryptoHash) -> Option<TransactionInfo> {
+    TRANSACTIONS
+        .lock()
+        .expect("Transactions lock poisoned")
+        .transaction(receipt_id)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn hash(name: &str) -> CryptoHash {
+        CryptoHash::hash_bytes(name.as_bytes())
+    }
+
+    fn info() -> TransactionInfo {
+        TransactionInfo {
+            transaction_hash: hash("buy").to_string(),
+            signer_id: "alice.near".to_owned(),
+            block_height: 10,
+        }
+    }
+
+    #[test]
+    fn follows_a_chain_reaching_a_routed_contract() {
+        let mut index = TransactionIndex::default();
+        // alice -> router.near -> market.near -> nft.near -> market.near callback
+        index.index_transaction(info(), &[hash("router")], false, 10);
+        index.index_outcome(hash("router"), &[hash("buy")], false, 11);
+        index.index_outcome(hash("buy"), &[hash("payout"), hash("resolve")], true, 12);
+        index.index_outcome(hash("payout"), &[hash("refund")], true, 13);
+        index.index_outcome(hash("resolve"), &[], true, 14);
+
+        for receipt in ["router", "buy", "payout", "resolve", "refund"] {
+            assert_eq!(
+                index.transaction(&hash(receipt)),
+                Some(&info()),
+                "{receipt}"
+            );
+        }
+        assert_eq!(index.transaction(&hash("unknown")), None);
+    }
+
+    #[test]
+    fn persists_only_routed_chains() {
+        let mut index = TransactionIndex::default();
+        index.index_transaction(info(), &[hash("other")], false, 10);
+        index.index_outcome(hash("other"), &[hash("child")], false, 11);
+        index.index_transaction(info(), &[hash("market")], true, 10);
+
+        let saved: Vec<(CryptoHash, IndexedReceipt)> = index.clone().into();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].0, hash("market"));
+
+        index.prune(11 + UNTRACKED_TTL_BLOCKS + 1);
+        assert_eq!(index.transaction(&hash("child")), None);
+        assert_eq!(index.transaction(&hash("market")), Some(&info()));
+    }
+
+    #[test]
+    fn replays_logged_receipts_after_the_snapshot() {
+        let store = JsonStore::new(testing::state_dir("transactions"));
+        let mut index = TransactionIndex::default();
+        index.index_transaction(info(), &[hash("market")], true, 10);
+        store
+            .save(
+                STATE,
+                &Vec::<(CryptoHash, IndexedReceipt)>::from(index.clone()),
+            )
+            .unwrap();
+        index.index_outcome(hash("market"), &[hash("callback")], true, 11);
+        for update in std::mem::take(&mut index.pending) {
+            store.append(LOG, &update).unwrap();
+        }
+
+        let loaded = TransactionIndex::load(&store).unwrap();
+
+        assert_eq!(loaded.transaction(&hash("callback")), Some(&info()));
+        assert!(loaded.dirty);
+    }
+}
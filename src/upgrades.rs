@@ -0,0 +1,324 @@
+use crate::config::RestConfig;
+use crate::consts::DEPLOYMENTS;
+use crate::events::{self, legacy::LegacyFormat, rules::EventRules};
+use crate::get_config;
+use crate::routing::{get_router, HandlerKind};
+use crate::store::JsonStore;
+use crate::{ExecutionStatusView, IndexerExecutionOutcomeWithReceipt};
+use actix_web::web;
+use near_lake_framework::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use near_lake_framework::near_indexer_primitives::CryptoHash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const LOG: &str = "deployments";
+
+/// Decoders for the events a contract emitted while running a given version of its code.
+///
+/// A version starts either at the deployment of `code_hash` or, for versions deployed before
+/// the indexer watched the contract, at `from_block_height`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ContractVersionConfig {
+    pub account_id: String,
+    #[serde(default)]
+    pub code_hash: Option<String>,
+    #[serde(default)]
+    pub from_block_height: Option<u64>,
+    /// Handler decoding the version's events, e.g. `generic` for formats the current models
+    /// can't read.
+    pub handler: HandlerKind,
+    /// Replaces the contract's `event_rules` while the version is deployed.
+    #[serde(default)]
+    pub event_rules: Option<EventRules>,
+    /// Names the version's events and tokens used, when they differ from the current ones.
+    #[serde(default)]
+    pub legacy: Option<LegacyFormat>,
+}
+
+/// New code deployed to a watched contract.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContractDeployment {
+    pub account_id: String,
+    pub code_hash: String,
+    pub receipt_id: String,
+    pub block_height: u64,
+    /// Index of the deploying receipt among the execution outcomes of its shard in the block.
+    /// Receipts of the contract executed before it in the same block still ran the old code.
+    #[serde(default)]
+    pub position: usize,
+}
+
+/// Code hash history of watched contracts, mirrored from `deployments.jsonl` in the state dir.
+pub struct DeploymentHistory {
+    store: JsonStore,
+    accounts: HashMap<String, Vec<ContractDeployment>>,
+}
+
+impl DeploymentHistory {
+    pub fn load(store: JsonStore) -> anyhow::Result<Self> {
+        let mut history = Self {
+            store,
+            accounts: HashMap::new(),
+        };
+        for deployment in history.store.load_lines::<ContractDeployment>(LOG)? {
+            history.insert(deployment);
+        }
+
+        Ok(history)
+    }
+
+    fn insert(&mut self, deployment: ContractDeployment) -> bool {
+        let deployments = self
+            .accounts
+            .entry(deployment.account_id.clone())
+            .or_default();
+        if deployments
+            .iter()
+            .any(|d| d.receipt_id == deployment.receipt_id)
+        {
+            return false;
+        }
+        let index = deployments.partition_point(|d| {
+            (d.block_height, d.position) <= (deployment.block_height, deployment.position)
+        });
+        deployments.insert(index, deployment);
+
+        true
+    }
+
+    /// Returns whether the deployment wasn't recorded before.
+    pub fn record(&mut self, deployment: ContractDeployment) -> anyhow::Result<bool> {
+        let known = self
+            .accounts
+            .get(&deployment.account_id)
+            .map_or(false, |d| {
+                d.iter().any(|d| d.receipt_id == deployment.receipt_id)
+            });
+        if known {
+            return Ok(false);
+        }
+        self.store.append(LOG, &deployment)?;
+
+        Ok(self.insert(deployment))
+    }
+
+    pub fn deployments(&self, account_id: &str) -> &[ContractDeployment] {
+        self.accounts.get(account_id).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Deployment whose code ran the receipt at `position` in a shard's execution outcomes of
+    /// `block_height`. A receipt deploying code runs its following actions with the new code.
+    pub fn deployed_at(
+        &self,
+        account_id: &str,
+        block_height: u64,
+        position: usize,
+    ) -> Option<&ContractDeployment> {
+        self.deployments(account_id)
+            .iter()
+            .take_while(|d| (d.block_height, d.position) <= (block_height, position))
+            .last()
+    }
+}
+
+#[tracing::instrument(name = "Getting deployment history")]
+pub async fn get_deployments() -> &'static Mutex<DeploymentHistory> {
+    DEPLOYMENTS
+        .get_or_init(|| async {
+            let store = get_config().await.store();
+            let history = DeploymentHistory::load(store).expect("Couldn't load deployment history");
+            Mutex::new(history)
+        })
+        .await
+}
+
+/// Version of the contract's code running the receipt at `position` of `block_height`, if one
+/// is configured for it. Code deployed since the latest `from_block_height` version started
+/// only has a version when one is configured for its code hash, the route's handler decodes
+/// its events otherwise.
+pub async fn version_at(
+    account_id: &str,
+    block_height: u64,
+    position: usize,
+) -> Option<&'static ContractVersionConfig> {
+    let config = get_config().await;
+    let versions = config
+        .contract_versions
+        .iter()
+        .filter(|v| v.account_id == account_id)
+        .collect::<Vec<_>>();
+    if versions.is_empty() {
+        return None;
+    }
+
+    let deployment = get_deployments()
+        .await
+        .lock()
+        .expect("Deployments lock poisoned")
+        .deployed_at(account_id, block_height, position)
+        .cloned();
+    let by_height = versions
+        .iter()
+        .filter_map(|v| Some((v.from_block_height?, *v)))
+        .filter(|(from, _)| *from <= block_height)
+        .max_by_key(|(from, _)| *from);
+
+    match deployment {
+        Some(deployment) if by_height.map_or(true, |(from, _)| deployment.block_height >= from) => {
+            versions
+                .into_iter()
+                .find(|v| v.code_hash.as_ref() == Some(&deployment.code_hash))
+        }
+        _ => by_height.map(|(_, version)| version),
+    }
+}
+
+/// Code hash in a `DeployContract` action view, which carries the base64 of the hash of the
+/// code rather than the code itself.
+fn deployed_code_hash(action: &ActionView) -> Option<String> {
+    let code = match action {
+        ActionView::DeployContract { code } => code,
+        _ => return None,
+    };
+    let bytes = base64::decode(code).ok()?;
+    let hash = CryptoHash::try_from(bytes.as_slice()).ok()?;
+
+    Some(hash.to_string())
+}
+
+/// Records code deployed to routed contracts, so events after it get decoded by the version
+/// configured for the new code.
+#[tracing::instrument(
+    name = "Detecting contract upgrades",
+    skip(outcome, client),
+    fields(receipt_id = %outcome.receipt.receipt_id)
+)]
+pub async fn observe_receipt(
+    outcome: &IndexerExecutionOutcomeWithReceipt,
+    block_height: u64,
+    position: usize,
+    client: web::Data<reqwest::Client>,
+) -> anyhow::Result<()> {
+    let account_id = outcome.receipt.receiver_id.as_ref();
+    let actions = match &outcome.receipt.receipt {
+        ReceiptEnumView::Action { actions, .. } => actions,
+        ReceiptEnumView::Data { .. } => return Ok(()),
+    };
+    let succeeded = matches!(
+        outcome.execution_outcome.outcome.status,
+        ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+    );
+    let code_hash = match actions.iter().find_map(deployed_code_hash) {
+        Some(code_hash) if succeeded => code_hash,
+        _ => return Ok(()),
+    };
+    let sink = match get_router().await.read().await.route(account_id) {
+        Some(route) => route.sink.clone(),
+        None => return Ok(()),
+    };
+
+    let deployment = ContractDeployment {
+        account_id: account_id.to_owned(),
+        code_hash,
+        receipt_id: outcome.receipt.receipt_id.to_string(),
+        block_height,
+        position,
+    };
+    let recorded = get_deployments()
+        .await
+        .lock()
+        .expect("Deployments lock poisoned")
+        .record(deployment.clone())?;
+    if !recorded {
+        return Ok(());
+    }
+
+    let config = get_config().await;
+    let configured = config
+        .contract_versions
+        .iter()
+        .any(|v| v.account_id == account_id && v.code_hash.as_ref() == Some(&deployment.code_hash));
+    if !configured {
+        tracing::warn!(
+            "`{account_id}` was upgraded to code {} at block {block_height}, which has no \
+            configured version, its events are decoded by the route's handler",
+            deployment.code_hash
+        );
+    }
+    store_deployment(&deployment, client, &sink).await
+}
+
+#[tracing::instrument(
+    name = "Sending request to the rest service to store contract deployment",
+    skip(deployment, client, sink)
+)]
+async fn store_deployment(
+    deployment: &ContractDeployment,
+    client: web::Data<reqwest::Client>,
+    sink: &RestConfig,
+) -> anyhow::Result<()> {
+    let response = client
+        .post(format!("{}/contract_deployments", sink.base_url()))
+        .header("Content-Type", "application/json")
+        .basic_auth(sink.username(), Some(sink.password()))
+        .json(deployment)
+        .send()
+        .await?;
+
+    events::handle_response_for_error(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn deployment(code_hash: &str, block_height: u64, position: usize) -> ContractDeployment {
+        ContractDeployment {
+            account_id: "nft.near".to_owned(),
+            code_hash: code_hash.to_owned(),
+            receipt_id: format!("receipt-{block_height}-{position}"),
+            block_height,
+            position,
+        }
+    }
+
+    #[test]
+    fn receipts_before_the_deploy_in_its_block_run_the_old_code() {
+        let dir = testing::state_dir("upgrades");
+        let mut history = DeploymentHistory::load(JsonStore::new(dir)).unwrap();
+        history.record(deployment("old", 10, 0)).unwrap();
+        history.record(deployment("new", 20, 3)).unwrap();
+
+        let code_hash = |block_height, position| {
+            history
+                .deployed_at("nft.near", block_height, position)
+                .map(|d| d.code_hash.as_str())
+        };
+        assert_eq!(code_hash(5, 0), None);
+        assert_eq!(code_hash(20, 2), Some("old"));
+        assert_eq!(code_hash(20, 3), Some("new"));
+        assert_eq!(code_hash(21, 0), Some("new"));
+    }
+
+    #[test]
+    fn reads_the_code_hash_of_deploy_actions_only() {
+        let hash = CryptoHash::hash_bytes(b"code");
+        let deploy = ActionView::DeployContract {
+            code: base64::encode(hash.as_ref()),
+        };
+
+        assert_eq!(deployed_code_hash(&deploy), Some(hash.to_string()));
+        assert_eq!(
+            deployed_code_hash(&ActionView::DeployContract {
+                code: base64::encode(b"not a hash"),
+            }),
+            None
+        );
+        assert_eq!(
+            deployed_code_hash(&ActionView::Transfer { deposit: 1 }),
+            None
+        );
+    }
+}